@@ -0,0 +1,275 @@
+use crate::disk::sstable::{write_sstable, SSTable};
+use crate::disk::wal;
+use crate::disk::wal::Wal;
+use crate::error::StepDbError;
+use crate::memory::entry::{new_entry, Entry, Value, BIT_DELETE};
+use crate::memory::keys::{key_with_ts, parse_ts};
+use crate::memory::memtable::Memtable;
+use std::path::{Path, PathBuf};
+
+// Db puts the memtable and the sstables together. Every write gets a new version, a read
+// goes from the newest data to the oldest and stops at the first version of the key:
+// the active memtable, the immutable memtables, then the sstables level by level.
+pub struct Db {
+    dir: PathBuf,
+    memtable: Memtable,
+    // logs what the memtable holds until it is flushed
+    wal: Wal,
+    // levels[0] holds the flushed memtables, oldest first, they may overlap
+    levels: Vec<Vec<SSTable>>,
+    next_file_id: u64,
+    ts: u64,
+}
+
+impl Db {
+    // open opens the db in dir, creating dir if needed. There is no manifest yet, the
+    // sstables found in dir all go to level 0, ordered by their file id. The entries in
+    // the wal are written to the memtable again, a wal holding an entry the memtable
+    // refuses is a Corruption error.
+    pub fn open(dir: &Path, arena_size: u32) -> anyhow::Result<Db> {
+        std::fs::create_dir_all(dir)?;
+        let mut ids = Vec::new();
+        for f in std::fs::read_dir(dir)? {
+            let path = f?.path();
+            if path.extension().is_some_and(|e| e == "sst") {
+                if let Some(id) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        let mut level0 = Vec::new();
+        let mut ts = 0;
+        for id in ids.iter() {
+            let t = SSTable::open(&sstable_path(dir, *id))?;
+            // New writes must get versions above all the flushed ones.
+            ts = t.entries().map(|(k, _)| parse_ts(k)).fold(ts, u64::max);
+            level0.push(t);
+        }
+        let mut memtable = Memtable::new(arena_size, arena_size)?;
+        let wal_path = dir.join(WAL_FILE);
+        let (wal, entries) = wal::open(&wal_path)?;
+        for e in entries {
+            ts = ts.max(parse_ts(&e.key));
+            memtable
+                .put(e)
+                .map_err(|_| StepDbError::Corruption(format!("wal {:?}", wal_path)))?;
+        }
+        Ok(Db {
+            dir: dir.to_path_buf(),
+            memtable,
+            wal,
+            levels: vec![level0],
+            next_file_id: ids.last().map_or(1, |id| id + 1),
+            ts,
+        })
+    }
+
+    // put writes value under key. It is logged to the wal but only durable after
+    // sync_all, or after the batch it belongs to with group commit on.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.ts += 1;
+        self.write(new_entry(&key_with_ts(key, self.ts), value))
+    }
+
+    // delete writes a tombstone, it hides every older version of key.
+    pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.ts += 1;
+        let mut e = new_entry(&key_with_ts(key, self.ts), &[]);
+        e.meta = BIT_DELETE;
+        self.write(e)
+    }
+
+    fn write(&mut self, e: Entry) -> anyhow::Result<()> {
+        // An entry goes to the wal only once the memtable is known to take it, or it
+        // would be replayed on every open.
+        self.memtable.check(&e)?;
+        self.wal.append(&e)?;
+        self.memtable.put(e)?;
+        Ok(())
+    }
+
+    // sync_all returns once every write so far is durable.
+    pub fn sync_all(&mut self) -> anyhow::Result<()> {
+        self.wal.sync_all()
+    }
+
+    // set_group_commit makes the writes durable in batches of batch writes, one fsync for
+    // each batch, 0 turns it off.
+    pub fn set_group_commit(&mut self, batch: usize) {
+        self.wal.set_group_commit(batch);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        // The largest ts seeks to the newest version.
+        let seek = key_with_ts(key, u64::MAX);
+        let v = self.memtable.get(&seek).or_else(|| {
+            self.levels
+                .iter()
+                .flat_map(|level| level.iter().rev())
+                .filter(|t| t.may_contain(&seek))
+                .find_map(|t| t.get(&seek))
+        })?;
+        live_value(v)
+    }
+
+    // flush writes the active and all immutable memtables to level 0 sstables.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.memtable.rotate()?;
+        while let Some(list) = self.memtable.pop_immutable() {
+            // The first item is the head, a list with nothing else is empty.
+            if list.iter().nth(1).is_none() {
+                self.memtable.recycle(list);
+                continue;
+            }
+            let path = sstable_path(&self.dir, self.next_file_id);
+            write_sstable(list.iter(), &path)?;
+            self.memtable.recycle(list);
+            self.levels[0].push(SSTable::open(&path)?);
+            self.next_file_id += 1;
+        }
+        sync_dir(&self.dir)?;
+        // everything the wal held is in the sstables now
+        self.wal.truncate()
+    }
+}
+
+fn live_value(v: Value) -> Option<Vec<u8>> {
+    if v.meta & BIT_DELETE != 0 {
+        return None;
+    }
+    Some(v.v)
+}
+
+const WAL_FILE: &str = "wal.log";
+
+// sync_dir makes the files created in dir durable, not only their contents.
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn sstable_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::disk::wal;
+    use crate::error::StepDbError;
+    use crate::memory::entry::new_entry;
+    use crate::memory::keys::key_with_ts;
+    use crate::memory::skiplist::MAX_KEY_SIZE;
+
+    #[test]
+    fn test_db() {
+        let dir = std::env::temp_dir().join(format!("step-db-db-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir, 1 << 16).unwrap();
+        db.put(b"k1", b"v1").unwrap();
+        db.put(b"k2", b"v2").unwrap();
+        db.put(b"k3", b"v3").unwrap();
+        db.flush().unwrap();
+        assert_eq!(Some(b"v1".to_vec()), db.get(b"k1"));
+
+        // the newer value in the memtable wins over the sstable
+        db.put(b"k1", b"new").unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"k1"));
+        // a tombstone hides the flushed version
+        db.delete(b"k2").unwrap();
+        assert_eq!(None, db.get(b"k2"));
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k3"));
+        assert_eq!(None, db.get(b"k4"));
+
+        // the same holds once everything is on disk
+        db.flush().unwrap();
+        db.flush().unwrap();
+        assert_eq!(2, db.levels[0].len());
+        assert_eq!(Some(b"new".to_vec()), db.get(b"k1"));
+        assert_eq!(None, db.get(b"k2"));
+
+        let mut db = Db::open(&dir, 1 << 16).unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"k1"));
+        assert_eq!(None, db.get(b"k2"));
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k3"));
+        // writes after a reopen are newer than the flushed ones
+        db.put(b"k1", b"newer").unwrap();
+        assert_eq!(Some(b"newer".to_vec()), db.get(b"k1"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_too_large() {
+        let dir = std::env::temp_dir().join(format!("step-db-large-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir, 1 << 12).unwrap();
+        db.put(b"k1", b"v1").unwrap();
+        // a value the arena can't hold is an error, not a panic
+        let err = db.put(b"k2", &[b'v'; 1 << 13]).unwrap_err();
+        assert_eq!(
+            Some(&StepDbError::ArenaFull),
+            err.downcast_ref::<StepDbError>()
+        );
+        let err = db.delete(&vec![b'k'; MAX_KEY_SIZE]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StepDbError>(),
+            Some(StepDbError::KeyTooLarge(_))
+        ));
+        assert_eq!(Some(b"v1".to_vec()), db.get(b"k1"));
+        assert_eq!(None, db.get(b"k2"));
+        db.put(b"k3", b"v3").unwrap();
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k3"));
+
+        // the refused writes never reached the wal, the db opens again
+        db.sync_all().unwrap();
+        drop(db);
+        let db = Db::open(&dir, 1 << 12).unwrap();
+        assert_eq!(Some(b"v1".to_vec()), db.get(b"k1"));
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k3"));
+        drop(db);
+
+        // a wal entry the memtable refuses is an error on open, not a panic
+        let (mut w, _) = wal::open(&dir.join("wal.log")).unwrap();
+        w.append(&new_entry(&key_with_ts(b"k4", 10), &[b'v'; 1 << 13]))
+            .unwrap();
+        w.sync_all().unwrap();
+        drop(w);
+        let err = Db::open(&dir, 1 << 12).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<StepDbError>(),
+            Some(StepDbError::Corruption(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_all() {
+        let dir = std::env::temp_dir().join(format!("step-db-sync-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut db = Db::open(&dir, 1 << 16).unwrap();
+        db.put(b"k1", b"v1").unwrap();
+        db.put(b"k2", b"v2").unwrap();
+        db.delete(b"k1").unwrap();
+        db.sync_all().unwrap();
+        // nothing is flushed, the writes come back from the wal
+        drop(db);
+        let mut db = Db::open(&dir, 1 << 16).unwrap();
+        assert_eq!(None, db.get(b"k1"));
+        assert_eq!(Some(b"v2".to_vec()), db.get(b"k2"));
+        // versions go on after the ones in the wal
+        db.put(b"k2", b"v3").unwrap();
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k2"));
+
+        // a flush empties the wal, the data is in the sstables
+        db.flush().unwrap();
+        assert_eq!(0, std::fs::metadata(dir.join("wal.log")).unwrap().len());
+        drop(db);
+        let db = Db::open(&dir, 1 << 16).unwrap();
+        assert_eq!(Some(b"v3".to_vec()), db.get(b"k2"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use crate::disk::sstable::{write_entries, SSTable};
+use crate::memory::entry::BIT_DELETE;
+use crate::memory::keys::same_key;
+use crate::memory::utils::compare_keys;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+// compact merges inputs, ordered oldest first, into a new sstable at path. Of every user
+// key only the newest version is kept. A newest version that is a tombstone is kept too,
+// it still has to hide the older versions in tables that are not part of the merge,
+// unless drop_tombstones says there are none of those, e.g. when compacting into the
+// last level.
+pub fn compact(inputs: &[SSTable], path: &Path, drop_tombstones: bool) -> anyhow::Result<SSTable> {
+    let mut iters: Vec<_> = inputs.iter().map(|t| t.entries()).collect();
+    let mut heap = BinaryHeap::new();
+    for (src, it) in iters.iter_mut().enumerate() {
+        if let Some((key, value)) = it.next() {
+            heap.push(Head { key, value, src });
+        }
+    }
+
+    let mut out: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut last: Option<&[u8]> = None;
+    while let Some(Head { key, value, src }) = heap.pop() {
+        if let Some((key, value)) = iters[src].next() {
+            heap.push(Head { key, value, src });
+        }
+        // Versions of a key sort newest first, the first one seen is the one to keep.
+        if last.is_some_and(|k| same_key(k, key)) {
+            continue;
+        }
+        last = Some(key);
+        // The first byte of an encoded value is the meta.
+        if drop_tombstones && value[0] & BIT_DELETE != 0 {
+            continue;
+        }
+        out.push((key.to_vec(), value.to_vec()));
+    }
+
+    write_entries(out.into_iter(), path)?;
+    SSTable::open(path)
+}
+
+// Head is the next entry of one input, the heap pops the smallest key first and, for
+// the same key, the entry of the newest input.
+struct Head<'a> {
+    key: &'a [u8],
+    value: &'a [u8],
+    src: usize,
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(other.key, self.key)
+            .cmp(&0)
+            .then(self.src.cmp(&other.src))
+    }
+}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::compact::compact;
+    use crate::disk::sstable::{write_sstable, SSTable};
+    use crate::memory::entry::{new_entry, BIT_DELETE};
+    use crate::memory::keys::{key_with_ts, parse_key};
+    use crate::memory::skiplist::new_versioned_skip_list;
+    use std::path::PathBuf;
+
+    fn table(name: &str, entries: &[(&[u8], u64, &[u8], u8)]) -> (SSTable, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("step-db-{}-{}.sst", name, std::process::id()));
+        let mut list = new_versioned_skip_list(1 << 16);
+        for (k, ts, v, meta) in entries {
+            let mut e = new_entry(&key_with_ts(k, *ts), v);
+            e.meta = *meta;
+            list.add(e).unwrap();
+        }
+        write_sstable(list.iter(), &path).unwrap();
+        (SSTable::open(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn test_compact() {
+        let (old, p1) = table(
+            "compact-old",
+            &[
+                (b"a", 1, b"a1", 0),
+                (b"b", 1, b"b1", 0),
+                (b"c", 1, b"c1", 0),
+            ],
+        );
+        let (new, p2) = table(
+            "compact-new",
+            &[
+                (b"a", 2, b"a2", 0),
+                (b"b", 2, b"", BIT_DELETE),
+                (b"d", 2, b"d2", 0),
+            ],
+        );
+        let inputs = [old, new];
+
+        let out = std::env::temp_dir().join(format!("step-db-compact-{}.sst", std::process::id()));
+        let t = compact(&inputs, &out, false).unwrap();
+        let keys: Vec<_> = t.entries().map(|(k, _)| parse_key(k).to_vec()).collect();
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()],
+            keys
+        );
+        assert_eq!(
+            b"a2".to_vec(),
+            t.get(&key_with_ts(b"a", u64::MAX)).unwrap().v
+        );
+        assert_ne!(
+            0,
+            t.get(&key_with_ts(b"b", u64::MAX)).unwrap().meta & BIT_DELETE
+        );
+        assert_eq!(
+            b"c1".to_vec(),
+            t.get(&key_with_ts(b"c", u64::MAX)).unwrap().v
+        );
+
+        let t = compact(&inputs, &out, true).unwrap();
+        let keys: Vec<_> = t.entries().map(|(k, _)| parse_key(k).to_vec()).collect();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()], keys);
+        assert!(t.get(&key_with_ts(b"b", u64::MAX)).is_none());
+
+        for p in [p1, p2, out] {
+            std::fs::remove_file(p).unwrap();
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// CRC-32 (IEEE 802.3), the checksum of zlib and gzip, bit-reversed with polynomial
+// 0xEDB88320.
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) fn crc32(buf: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for &b in buf {
+        c = TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::crc::crc32;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF43926, crc32(b"123456789"));
+        assert_eq!(
+            0x414FA339,
+            crc32(b"The quick brown fox jumps over the lazy dog")
+        );
+    }
+}
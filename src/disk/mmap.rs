@@ -1,10 +1,216 @@
 use memmap2::{Mmap, MmapMut, MmapOptions};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
 
-fn mmap(fd: &File, size: usize) -> anyhow::Result<Mmap> {
+pub(crate) fn mmap(fd: &File, size: usize) -> anyhow::Result<Mmap> {
     unsafe { Ok(MmapOptions::new().len(size).map(fd)?) }
 }
 
 fn mmap_mut(fd: &File, size: usize) -> anyhow::Result<MmapMut> {
     unsafe { Ok(MmapOptions::new().len(size).map_mut(fd)?) }
 }
+
+// MmapFile is a file mapped read-write into memory, writes to the map go to the file.
+// A zero-length file can't be mapped, its map is None until the file is given a length.
+pub struct MmapFile {
+    fd: File,
+    map: Option<MmapMut>,
+    // append writes at cursor, it starts at 0 when the file is opened
+    cursor: usize,
+}
+
+impl MmapFile {
+    // open opens the file at path, creating it if it doesn't exist, and maps it whole.
+    // The file is extended to size bytes if it is shorter.
+    pub fn open(path: &Path, size: usize) -> anyhow::Result<MmapFile> {
+        let fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = fd.metadata()?.len() as usize;
+        if len < size {
+            fd.set_len(size as u64)?;
+        }
+        let mut f = MmapFile {
+            fd,
+            map: None,
+            cursor: 0,
+        };
+        f.remap()?;
+        Ok(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |m| m.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or(&[])
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.map.as_deref_mut().unwrap_or(&mut [])
+    }
+
+    // flush writes the dirty pages of the map back to the file (msync).
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if let Some(m) = &self.map {
+            m.flush()?;
+        }
+        Ok(())
+    }
+
+    // flush_range is flush for the len bytes at offset only.
+    pub fn flush_range(&self, offset: usize, len: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(offset + len <= self.len(), "flush range out of the file");
+        if let Some(m) = &self.map {
+            m.flush_range(offset, len)?;
+        }
+        Ok(())
+    }
+
+    // append writes bytes at the cursor, growing the file if needed, and moves the cursor
+    // past them. It returns the offset bytes were written at.
+    pub fn append(&mut self, bytes: &[u8]) -> anyhow::Result<u64> {
+        let start = self.cursor;
+        self.ensure_capacity(start + bytes.len())?;
+        self.as_mut_slice()[start..start + bytes.len()].copy_from_slice(bytes);
+        self.cursor += bytes.len();
+        Ok(start as u64)
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    // truncate sets the length of the file to new_len and maps it again, the bytes below
+    // new_len are kept.
+    pub fn truncate(&mut self, new_len: usize) -> anyhow::Result<()> {
+        self.flush()?;
+        self.cursor = self.cursor.min(new_len);
+        // Unmap first, shrinking a file under a live mapping makes access to the cut
+        // pages fault.
+        self.map = None;
+        self.fd.set_len(new_len as u64)?;
+        self.remap()
+    }
+
+    // ensure_capacity grows the file, and the map with it, to at least needed bytes.
+    // The length at least doubles so a series of small appends remaps only a few times.
+    // An empty file, which has no map yet, grows to exactly needed bytes.
+    pub fn ensure_capacity(&mut self, needed: usize) -> anyhow::Result<()> {
+        let len = self.len();
+        if needed <= len {
+            return Ok(());
+        }
+        self.map = None;
+        self.fd.set_len(needed.max(len * 2) as u64)?;
+        self.remap()
+    }
+
+    fn remap(&mut self) -> anyhow::Result<()> {
+        let len = self.fd.metadata()?.len() as usize;
+        self.map = if len == 0 {
+            None
+        } else {
+            Some(mmap_mut(&self.fd, len)?)
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::mmap::{mmap, MmapFile};
+    use std::fs::File;
+
+    #[test]
+    fn test_mmap_file() {
+        let path = std::env::temp_dir().join(format!("step-db-mmap-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut f = MmapFile::open(&path, 64).unwrap();
+            assert_eq!(64, f.len());
+            f.as_mut_slice()[..5].copy_from_slice(b"hello");
+            f.flush().unwrap();
+
+            f.truncate(8).unwrap();
+            assert_eq!(b"hello\0\0\0", f.as_slice());
+            f.truncate(0).unwrap();
+            assert!(f.is_empty());
+            f.truncate(16).unwrap();
+            f.as_mut_slice()[..5].copy_from_slice(b"world");
+            f.flush().unwrap();
+        }
+
+        let fd = File::open(&path).unwrap();
+        assert_eq!(16, fd.metadata().unwrap().len());
+        let m = mmap(&fd, 16).unwrap();
+        assert_eq!(b"world", &m[..5]);
+
+        // opening an existing file keeps its content
+        let f = MmapFile::open(&path, 0).unwrap();
+        assert_eq!(&m[..], f.as_slice());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_capacity() {
+        let path = std::env::temp_dir().join(format!("step-db-grow-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut f = MmapFile::open(&path, 0).unwrap();
+        assert!(f.is_empty());
+        f.ensure_capacity(10).unwrap();
+        assert_eq!(10, f.len());
+        f.as_mut_slice().copy_from_slice(b"0123456789");
+
+        f.ensure_capacity(11).unwrap();
+        assert_eq!(20, f.len());
+        f.ensure_capacity(100).unwrap();
+        assert_eq!(100, f.len());
+        f.ensure_capacity(50).unwrap();
+        assert_eq!(100, f.len());
+        assert_eq!(b"0123456789", &f.as_slice()[..10]);
+        assert!(f.as_slice()[10..].iter().all(|&b| b == 0));
+        f.as_mut_slice()[99] = 1;
+        f.flush().unwrap();
+        drop(f);
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(100, data.len());
+        assert_eq!(b"0123456789", &data[..10]);
+        assert_eq!(1, data[99]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let path = std::env::temp_dir().join(format!("step-db-append-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut f = MmapFile::open(&path, 0).unwrap();
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; i % 13 + 1]).collect();
+        let mut offsets = Vec::new();
+        for r in records.iter() {
+            let offset = f.append(r).unwrap();
+            f.flush_range(offset as usize, r.len()).unwrap();
+            offsets.push(offset);
+        }
+        assert_eq!(0, offsets[0]);
+        assert_eq!(offsets[99] as usize + records[99].len(), f.cursor());
+        assert!(f.flush_range(f.len(), 1).is_err());
+        drop(f);
+
+        let data = std::fs::read(&path).unwrap();
+        for (r, &offset) in records.iter().zip(offsets.iter()) {
+            let offset = offset as usize;
+            assert_eq!(&r[..], &data[offset..offset + r.len()]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1 +1,5 @@
-mod mmap;
+pub mod compact;
+mod crc;
+pub mod mmap;
+pub mod sstable;
+pub mod wal;
@@ -0,0 +1,436 @@
+use crate::disk::crc::crc32;
+use crate::disk::mmap::mmap;
+use crate::error::StepDbError;
+use crate::memory::bloom;
+use crate::memory::bloom::BloomFilter;
+use crate::memory::entry::{Entry, Value};
+use crate::memory::iterator::SkipListIter;
+use crate::memory::keys::{parse_key, parse_ts, same_key};
+use crate::memory::utils::{compare_keys, compare_keys_no_ts};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+// Layout of a sstable file, all integers are little-endian u32:
+//
+// | block 0 | block 1 | ... | block n | index | bloom | range | footer |
+//
+// block:  | key_len | key | value_len | value | ... , value is encoded by Value::encode_value
+// index:  | key_len | last key of the block | block offset | block len | ...
+// bloom:  the bitmap of the BloomFilter built from all keys without their version suffix
+// range:  | key_len | smallest key | key_len | largest key |, empty if there are no keys
+// footer: | index offset | index len | bloom offset | bloom len | range offset | range len |
+//         | crc32 |, the crc32 covers every byte of the file before it
+const BLOCK_SIZE: usize = 4 * 1024;
+const FOOTER_SIZE: usize = 7 * 4;
+const BLOOM_FALSE_POSITIVE: f64 = 0.01;
+
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u32,
+    len: u32,
+}
+
+pub struct SSTable {
+    data: Mmap,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    range: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// write_sstable writes the entries of iter in sorted order to path.
+// The first item of a SkipListIter is the head node of the skip list, it is skipped.
+pub fn write_sstable(iter: SkipListIter, path: &Path) -> anyhow::Result<()> {
+    let entries = iter.skip(1).map(|e| {
+        let v = Value {
+            meta: e.meta,
+            v: e.value,
+            expires_at: e.expires_at,
+            version: e.version,
+        };
+        let mut enc = vec![0; v.encoded_size()];
+        v.encode_value(&mut enc);
+        (e.key, enc)
+    });
+    write_entries(entries, path)
+}
+
+// write_entries writes keys with their encoded values to path, the keys must be in
+// compare_keys order.
+pub(crate) fn write_entries(
+    entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    let mut index = Vec::new();
+    let mut keys = Vec::new();
+    let mut block_start = 0;
+
+    for (key, enc) in entries {
+        put_bytes(&mut buf, &key);
+        put_bytes(&mut buf, &enc);
+        keys.push(key);
+
+        if buf.len() - block_start >= BLOCK_SIZE {
+            index.push(BlockHandle {
+                last_key: keys.last().unwrap().clone(),
+                offset: block_start as u32,
+                len: (buf.len() - block_start) as u32,
+            });
+            block_start = buf.len();
+        }
+    }
+    if buf.len() > block_start {
+        index.push(BlockHandle {
+            last_key: keys.last().unwrap().clone(),
+            offset: block_start as u32,
+            len: (buf.len() - block_start) as u32,
+        });
+    }
+
+    let index_offset = buf.len();
+    for h in index.iter() {
+        put_bytes(&mut buf, &h.last_key);
+        put_u32(&mut buf, h.offset);
+        put_u32(&mut buf, h.len);
+    }
+    let index_len = buf.len() - index_offset;
+
+    let mut bf = bloom::new(keys.len().max(1) as isize, BLOOM_FALSE_POSITIVE);
+    for k in keys.iter() {
+        bf.add_key(parse_key(k));
+    }
+    let bloom_offset = buf.len();
+    buf.extend_from_slice(bf.as_bytes());
+    let bloom_len = buf.len() - bloom_offset;
+
+    let range_offset = buf.len();
+    if let (Some(first), Some(last)) = (keys.first(), keys.last()) {
+        put_bytes(&mut buf, first);
+        put_bytes(&mut buf, last);
+    }
+    let range_len = buf.len() - range_offset;
+
+    put_u32(&mut buf, index_offset as u32);
+    put_u32(&mut buf, index_len as u32);
+    put_u32(&mut buf, bloom_offset as u32);
+    put_u32(&mut buf, bloom_len as u32);
+    put_u32(&mut buf, range_offset as u32);
+    put_u32(&mut buf, range_len as u32);
+    let crc = crc32(&buf);
+    put_u32(&mut buf, crc);
+
+    let mut f = File::create(path)?;
+    f.write_all(&buf)?;
+    // the table must be durable before the wal records it holds are dropped
+    f.sync_all()?;
+    Ok(())
+}
+
+impl SSTable {
+    // open maps the sstable at path. A file that doesn't match its checksum, e.g. one cut
+    // short by a crash or damaged on disk, fails with StepDbError::Corruption.
+    pub fn open(path: &Path) -> anyhow::Result<SSTable> {
+        let fd = File::open(path)?;
+        let size = fd.metadata()?.len() as usize;
+        let corruption = || StepDbError::Corruption(format!("sstable {:?}", path));
+        if size < FOOTER_SIZE {
+            return Err(corruption().into());
+        }
+        let data = mmap(&fd, size)?;
+        // Nothing is read before the checksum is verified, the offsets in a damaged
+        // footer could point anywhere.
+        if crc32(&data[..size - 4]) != get_u32(&data, size - 4) {
+            return Err(corruption().into());
+        }
+
+        let footer = &data[size - FOOTER_SIZE..];
+        let index_offset = get_u32(footer, 0) as usize;
+        let index_len = get_u32(footer, 4) as usize;
+        let bloom_offset = get_u32(footer, 8) as usize;
+        let bloom_len = get_u32(footer, 12) as usize;
+        let range_offset = get_u32(footer, 16) as usize;
+        let range_len = get_u32(footer, 20) as usize;
+        for (end, limit) in [
+            (index_offset + index_len, bloom_offset),
+            (bloom_offset + bloom_len, range_offset),
+            (range_offset + range_len, size - FOOTER_SIZE),
+        ] {
+            if end > limit {
+                return Err(StepDbError::InvalidOffset(end).into());
+            }
+        }
+
+        let mut index = Vec::new();
+        let raw = &data[index_offset..index_offset + index_len];
+        let mut pos = 0;
+        while pos < raw.len() {
+            let (last_key, n) = get_bytes(raw, pos);
+            pos += n;
+            let handle = BlockHandle {
+                last_key: last_key.to_vec(),
+                offset: get_u32(raw, pos),
+                len: get_u32(raw, pos + 4),
+            };
+            let end = handle.offset as usize + handle.len as usize;
+            if end > index_offset {
+                return Err(StepDbError::InvalidOffset(end).into());
+            }
+            index.push(handle);
+            pos += 8;
+        }
+        let bloom = bloom::from_bytes(data[bloom_offset..bloom_offset + bloom_len].to_vec());
+        let range = if range_len == 0 {
+            None
+        } else {
+            let raw = &data[range_offset..range_offset + range_len];
+            let (first, n) = get_bytes(raw, 0);
+            let (last, _) = get_bytes(raw, n);
+            Some((first.to_vec(), last.to_vec()))
+        };
+
+        Ok(SSTable {
+            data,
+            index,
+            bloom,
+            range,
+        })
+    }
+
+    // key_range returns the smallest and the largest key in the table, None if it is empty.
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        self.range
+            .as_ref()
+            .map(|(a, b)| (a.as_slice(), b.as_slice()))
+    }
+
+    // may_contain tells whether the user key of key lies within the key range of the table,
+    // whatever its version. A table it doesn't need not be searched at all.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let Some((first, last)) = self.key_range() else {
+            return false;
+        };
+        let key = parse_key(key);
+        compare_keys_no_ts(parse_key(first), key) <= 0
+            && compare_keys_no_ts(key, parse_key(last)) <= 0
+    }
+
+    // get returns the value of the first key >= key if it is a version of the same user key,
+    // so with key@ts it finds the newest version <= ts, the same as SkipList::get.
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        if !self.may_contain(key) || !self.bloom.contains(parse_key(key)) {
+            return None;
+        }
+        // The first block whose last key >= key is the only one that can hold the key.
+        let i = self
+            .index
+            .partition_point(|h| compare_keys(&h.last_key, key) < 0);
+        let h = self.index.get(i)?;
+
+        let block = &self.data[h.offset as usize..(h.offset + h.len) as usize];
+        let mut pos = 0;
+        while pos < block.len() {
+            let (k, n) = get_bytes(block, pos);
+            pos += n;
+            let (v, n) = get_bytes(block, pos);
+            pos += n;
+            if compare_keys(k, key) >= 0 {
+                if !same_key(k, key) {
+                    return None;
+                }
+                let mut ret = Value::default();
+                // the table passed its checksum when it was opened
+                ret.decode_value(v).expect("value of an sstable");
+                return Some(ret);
+            }
+        }
+        None
+    }
+}
+
+impl SSTable {
+    // iter yields every entry of the table in key order, decoding the blocks one by one as
+    // it goes. The version of an entry is the timestamp of its key.
+    pub fn iter(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.entries().map(|(k, v)| {
+            let mut value = Value::default();
+            value.decode_value(v).expect("value of an sstable");
+            value.version = parse_ts(k);
+            value.into_entry(k.to_vec())
+        })
+    }
+
+    // entries yields every key with its encoded value, in key order.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.index.iter().flat_map(|h| {
+            let block = &self.data[h.offset as usize..(h.offset + h.len) as usize];
+            let mut pos = 0;
+            std::iter::from_fn(move || {
+                if pos >= block.len() {
+                    return None;
+                }
+                let (k, n) = get_bytes(block, pos);
+                pos += n;
+                let (v, n) = get_bytes(block, pos);
+                pos += n;
+                Some((k, v))
+            })
+        })
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    put_u32(buf, b.len() as u32);
+    buf.extend_from_slice(b);
+}
+
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+// get_bytes returns the length-prefixed bytes at pos and the number of bytes consumed.
+fn get_bytes(buf: &[u8], pos: usize) -> (&[u8], usize) {
+    let len = get_u32(buf, pos) as usize;
+    (&buf[pos + 4..pos + 4 + len], 4 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::sstable::{write_sstable, SSTable};
+    use crate::error::StepDbError;
+    use crate::memory::entry::new_entry;
+    use crate::memory::keys::{key_with_ts, parse_key};
+    use crate::memory::skiplist::new_versioned_skip_list;
+
+    #[test]
+    fn test_sstable() {
+        let path = std::env::temp_dir().join(format!("step-db-{}.sst", std::process::id()));
+        let mut list = new_versioned_skip_list(1 << 20);
+        for i in 0..1000 {
+            let k = key_with_ts(format!("key{:06}", i).as_bytes(), 1);
+            let v = format!("val{}", i);
+            list.add(new_entry(&k, v.as_bytes())).unwrap();
+        }
+        write_sstable(list.iter(), &path).unwrap();
+
+        let table = SSTable::open(&path).unwrap();
+        assert!(table.index.len() > 1);
+        for i in 0..1000 {
+            let k = format!("key{:06}", i);
+            let v = table.get(&key_with_ts(k.as_bytes(), 1)).unwrap();
+            assert_eq!(format!("val{}", i).as_bytes(), v.v);
+            // a newer read sees the version, an older one doesn't
+            assert!(table.get(&key_with_ts(k.as_bytes(), 2)).is_some());
+            assert!(table.get(&key_with_ts(k.as_bytes(), 0)).is_none());
+        }
+        for i in 1000..1010 {
+            let k = format!("key{:06}", i);
+            assert!(!table.bloom.contains(k.as_bytes()));
+            assert!(table.get(&key_with_ts(k.as_bytes(), 1)).is_none());
+        }
+        let keys: Vec<_> = table.entries().map(|(k, _)| k.to_vec()).collect();
+        assert_eq!(1000, keys.len());
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_iter() {
+        let path = std::env::temp_dir().join(format!("step-db-iter-{}.sst", std::process::id()));
+        let mut list = new_versioned_skip_list(1 << 20);
+        // enough entries to span several blocks, added out of order
+        let n = 1000;
+        for i in (0..n).rev() {
+            let k = key_with_ts(format!("key{:04}", i).as_bytes(), i + 1);
+            list.add(new_entry(&k, format!("value{}", i).as_bytes()))
+                .unwrap();
+        }
+        write_sstable(list.iter(), &path).unwrap();
+        let t = SSTable::open(&path).unwrap();
+
+        let mut i = 0;
+        for e in t.iter() {
+            assert_eq!(format!("key{:04}", i).as_bytes(), parse_key(&e.key));
+            assert_eq!(i + 1, e.version);
+            assert_eq!(format!("value{}", i).into_bytes(), e.value);
+            i += 1;
+        }
+        assert_eq!(n, i);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corruption() {
+        let path = std::env::temp_dir().join(format!("step-db-crc-{}.sst", std::process::id()));
+        let mut list = new_versioned_skip_list(1 << 20);
+        for i in 0..100 {
+            let k = key_with_ts(format!("key{:03}", i).as_bytes(), 1);
+            list.add(new_entry(&k, b"value")).unwrap();
+        }
+        write_sstable(list.iter(), &path).unwrap();
+        let good = std::fs::read(&path).unwrap();
+        assert!(SSTable::open(&path).is_ok());
+
+        let mut damaged = Vec::new();
+        // a flipped bit in a block, in the footer and in the checksum itself
+        for pos in [10, good.len() - 10, good.len() - 1] {
+            let mut buf = good.clone();
+            buf[pos] ^= 1;
+            damaged.push(buf);
+        }
+        // cut short, down to less than a footer
+        damaged.push(good[..good.len() - 100].to_vec());
+        damaged.push(good[..8].to_vec());
+        for buf in damaged {
+            std::fs::write(&path, buf).unwrap();
+            let err = SSTable::open(&path).err().unwrap();
+            assert!(
+                matches!(
+                    err.downcast_ref::<StepDbError>(),
+                    Some(StepDbError::Corruption(_))
+                ),
+                "{}",
+                err
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_key_range() {
+        let path = std::env::temp_dir().join(format!("step-db-range-{}.sst", std::process::id()));
+        let mut list = new_versioned_skip_list(1 << 20);
+        for i in 10..20 {
+            let k = key_with_ts(format!("key{:02}", i).as_bytes(), 1);
+            list.add(new_entry(&k, b"v")).unwrap();
+        }
+        let (first, last) = list.key_range().unwrap();
+        write_sstable(list.iter(), &path).unwrap();
+
+        let table = SSTable::open(&path).unwrap();
+        assert_eq!(Some((&first[..], &last[..])), table.key_range());
+        assert_eq!(key_with_ts(b"key10", 1), first);
+        assert_eq!(key_with_ts(b"key19", 1), last);
+        // the range is over user keys, any version of key10 and key19 is inside
+        assert!(table.may_contain(&key_with_ts(b"key10", u64::MAX)));
+        assert!(table.may_contain(&key_with_ts(b"key19", 0)));
+        assert!(table.may_contain(&key_with_ts(b"key15x", 1)));
+        // out of range lookups stop before the bloom filter and the blocks
+        for k in ["key09", "key2", "a", "z"] {
+            assert!(!table.may_contain(&key_with_ts(k.as_bytes(), 1)));
+            assert!(table.get(&key_with_ts(k.as_bytes(), 1)).is_none());
+        }
+
+        let empty = new_versioned_skip_list(1 << 16);
+        write_sstable(empty.iter(), &path).unwrap();
+        let table = SSTable::open(&path).unwrap();
+        assert_eq!(None, table.key_range());
+        assert!(!table.may_contain(&key_with_ts(b"key10", 1)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
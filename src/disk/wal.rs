@@ -0,0 +1,194 @@
+use crate::disk::crc::crc32;
+use crate::memory::entry::Entry;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Layout of the write-ahead log, all integers are little-endian u32:
+//
+// | record 0 | record 1 | ... |
+//
+// record: | entry len | crc32 of the entry | entry |, entry is encoded by Entry::encode
+//
+// A crash can leave the last record cut short, replay stops at the first record that is
+// incomplete or doesn't match its checksum and the log is truncated there.
+const HEADER_SIZE: usize = 2 * 4;
+
+// Wal logs the entries of the memtable until they are flushed to sstables. Appends are
+// buffered, sync_all is the point where everything appended so far is durable. With group
+// commit on, appends are synced once per batch of group_commit appends too.
+pub struct Wal {
+    w: BufWriter<File>,
+    // 0 leaves syncing to sync_all, otherwise every group_commit appends are synced at once
+    group_commit: usize,
+    // appends since the last sync
+    pending: usize,
+    // number of syncs that reached the file, for tests
+    pub(crate) syncs: u64,
+}
+
+// open opens the log at path, creating it if needed, and returns it with the entries it
+// holds, oldest first.
+pub fn open(path: &Path) -> anyhow::Result<(Wal, Vec<Entry>)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let buf = std::fs::read(path)?;
+    let (entries, valid) = replay(&buf);
+    if valid < buf.len() {
+        // drop the torn tail, new records must follow the last good one
+        file.set_len(valid as u64)?;
+        file.sync_all()?;
+    }
+    let wal = Wal {
+        w: BufWriter::new(file),
+        group_commit: 0,
+        pending: 0,
+        syncs: 0,
+    };
+    Ok((wal, entries))
+}
+
+// replay decodes the records of buf, it returns the entries and the length of the
+// records that are intact.
+fn replay(buf: &[u8]) -> (Vec<Entry>, usize) {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + HEADER_SIZE <= buf.len() {
+        let len = u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[pos + 4..pos + 8].try_into().unwrap());
+        let start = pos + HEADER_SIZE;
+        let Some(data) = buf.get(start..start + len) else {
+            break;
+        };
+        if crc32(data) != crc {
+            break;
+        }
+        let Ok((e, _)) = Entry::decode(data) else {
+            break;
+        };
+        entries.push(e);
+        pos = start + len;
+    }
+    (entries, pos)
+}
+
+impl Wal {
+    pub fn append(&mut self, e: &Entry) -> anyhow::Result<()> {
+        let mut data = Vec::new();
+        e.encode(&mut data);
+        self.w.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.w.write_all(&crc32(&data).to_le_bytes())?;
+        self.w.write_all(&data)?;
+        self.pending += 1;
+        if self.group_commit > 0 && self.pending >= self.group_commit {
+            self.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // sync_all writes out the buffered records and fsyncs the file, it returns once
+    // everything appended so far is durable.
+    pub fn sync_all(&mut self) -> anyhow::Result<()> {
+        self.w.flush()?;
+        self.w.get_ref().sync_all()?;
+        self.pending = 0;
+        self.syncs += 1;
+        Ok(())
+    }
+
+    // set_group_commit syncs the log once every batch appends, 0 turns it off.
+    pub fn set_group_commit(&mut self, batch: usize) {
+        self.group_commit = batch;
+    }
+
+    // truncate drops every record, once they are all flushed to sstables.
+    pub fn truncate(&mut self) -> anyhow::Result<()> {
+        self.w.flush()?;
+        self.w.get_ref().set_len(0)?;
+        self.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk::wal;
+    use crate::memory::entry::new_entry;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("step-db-{}-{}.wal", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_sync_all() {
+        let path = temp_path("sync");
+        let _ = std::fs::remove_file(&path);
+        let (mut w, entries) = wal::open(&path).unwrap();
+        assert!(entries.is_empty());
+        for i in 0..10u8 {
+            let mut e = new_entry(&[b'k', i], &[b'v', i]);
+            e.version = i as u64;
+            w.append(&e).unwrap();
+        }
+        w.sync_all().unwrap();
+        drop(w);
+
+        let (_, entries) = wal::open(&path).unwrap();
+        assert_eq!(10, entries.len());
+        for (i, e) in entries.iter().enumerate() {
+            assert_eq!(vec![b'k', i as u8], e.key);
+            assert_eq!(vec![b'v', i as u8], e.value);
+            assert_eq!(i as u64, e.version);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let path = temp_path("torn");
+        let _ = std::fs::remove_file(&path);
+        let (mut w, _) = wal::open(&path).unwrap();
+        w.append(&new_entry(b"k1", b"v1")).unwrap();
+        w.append(&new_entry(b"k2", b"v2")).unwrap();
+        w.sync_all().unwrap();
+        drop(w);
+        // a record cut short by a crash
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(&[9, 0, 0, 0, 1, 2]).unwrap();
+        drop(f);
+
+        let (mut w, entries) = wal::open(&path).unwrap();
+        assert_eq!(2, entries.len());
+        w.append(&new_entry(b"k3", b"v3")).unwrap();
+        w.sync_all().unwrap();
+        drop(w);
+        let (_, entries) = wal::open(&path).unwrap();
+        let keys: Vec<_> = entries.into_iter().map(|e| e.key).collect();
+        assert_eq!(vec![b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec()], keys);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path = temp_path("group");
+        let _ = std::fs::remove_file(&path);
+        let (mut w, _) = wal::open(&path).unwrap();
+        w.set_group_commit(4);
+        for i in 0..10u8 {
+            w.append(&new_entry(&[i], &[i])).unwrap();
+        }
+        // one sync per batch of 4, the last 2 appends wait for the next batch
+        assert_eq!(2, w.syncs);
+        w.sync_all().unwrap();
+        assert_eq!(3, w.syncs);
+        drop(w);
+        assert_eq!(10, wal::open(&path).unwrap().1.len());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt;
+
+// StepDbError is the error of the operations that can fail on their input or their data
+// rather than on I/O. It converts into anyhow::Error, callers of the anyhow APIs get the
+// variant back with downcast_ref.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepDbError {
+    // the arena has no room left for an allocation
+    ArenaFull,
+    // an offset read from a file points outside of it
+    InvalidOffset(usize),
+    // a file doesn't match its checksum
+    Corruption(String),
+    // a key longer than a node can hold, with its length
+    KeyTooLarge(usize),
+    // a value longer than a node can hold, with its encoded length
+    ValueTooLarge(usize),
+    // bytes that don't decode to what they should hold
+    Decode(String),
+}
+
+impl fmt::Display for StepDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepDbError::ArenaFull => write!(f, "arena is full"),
+            StepDbError::InvalidOffset(offset) => write!(f, "invalid offset {}", offset),
+            StepDbError::Corruption(what) => write!(f, "{} is corrupt", what),
+            StepDbError::KeyTooLarge(len) => write!(f, "key of {} bytes is too large", len),
+            StepDbError::ValueTooLarge(len) => write!(f, "value of {} bytes is too large", len),
+            StepDbError::Decode(what) => write!(f, "can't decode {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StepDbError {}
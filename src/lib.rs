@@ -1,2 +1,4 @@
-mod disk;
-mod memory;
+pub mod db;
+pub mod disk;
+pub mod error;
+pub mod memory;
@@ -1,3 +1,4 @@
+mod error;
 mod memory;
 
 fn main() {}
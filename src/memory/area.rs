@@ -1,11 +1,19 @@
+use crate::error::StepDbError;
 use crate::memory::entry::Value;
-use crate::memory::skiplist::{Node, MAX_HEIGHT};
+use crate::memory::skiplist::{KeyOrder, Node, MAX_HEIGHT};
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::mem;
-use std::rc::Rc;
-use std::sync::atomic::AtomicU32;
+use std::ptr;
+use std::slice;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU32, AtomicU64};
 
+// Alignment in the arena: nodes start at offsets aligned to 8, NODE_ALIGN is the mask of
+// the low bits rounded away, so Node and its atomics are properly aligned. Keys and values
+// are byte strings and are packed without alignment. allocate_aligned serves anything else
+// that needs an alignment of its own.
 const OFFSET_SIZE: usize = std::mem::size_of::<u32>();
 const NODE_ALIGN: usize = std::mem::size_of::<u64>() - 1;
 const MAX_NODE_SIZE: usize = std::mem::size_of::<Node>();
@@ -13,104 +21,296 @@ const MAX_NODE_SIZE: usize = std::mem::size_of::<Node>();
 pub struct Area {
     n: AtomicU32,
     is_grow: bool,
-    buf: RefCell<Vec<u8>>,
+    // The buffer is backed by u64 words so its base is 8-byte aligned, an offset aligned
+    // by put_node is then a properly aligned address for Node and its atomics.
+    // It is never resized, all access goes through ptr, see get_node for the rules.
+    _buf: Box<[u64]>,
+    ptr: *mut u8,
+    cap: usize,
+    // Freed value slots as (offset, size), bucketed by the largest power of two <= size.
+    free: RefCell<BTreeMap<u32, Vec<(u32, u32)>>>,
 }
 
 impl Area {
+    // new creates an arena of n bytes, rounded up to a multiple of 8.
+    // Offset 0 is reserved as the null offset: get_node(0) is None and a zero tower slot
+    // means there is no next node. So the allocation cursor starts at 1 and allocate never
+    // hands out offset 0.
     pub(crate) fn new(n: u32) -> Area {
+        assert!(n >= 1, "arena size must be at least 1 byte");
+        let cap = (n as usize).div_ceil(8) * 8;
+        // A &Node always spans a full tower, pad the end so a short node at the end of
+        // the arena doesn't reach past the buffer.
+        Area::with_buf(
+            cap,
+            vec![0u64; (cap + MAX_NODE_SIZE) / 8].into_boxed_slice(),
+        )
+    }
+
+    // with_buf creates an arena of cap bytes on buf, which must be zeroed and hold cap
+    // bytes and the padding of new.
+    fn with_buf(cap: usize, mut buf: Box<[u64]>) -> Area {
+        debug_assert_eq!((cap + MAX_NODE_SIZE) / 8, buf.len());
+        let ptr = buf.as_mut_ptr() as *mut u8;
         Area {
             n: AtomicU32::new(1),
             is_grow: false,
-            buf: RefCell::new(vec![0; n as usize]),
+            _buf: buf,
+            ptr,
+            cap,
+            free: RefCell::new(BTreeMap::new()),
         }
     }
 
-    pub(crate) fn get_buf(&self) -> std::cell::Ref<'_, Vec<u8>> {
-        self.buf.borrow()
+    // into_buf returns the buffer of the arena, zeroed.
+    fn into_buf(self) -> Box<[u64]> {
+        let mut buf = self._buf;
+        buf.fill(0);
+        buf
+    }
+
+    // get_buf is a view of the whole arena for tests, don't keep it across writes.
+    #[cfg(test)]
+    pub(crate) fn get_buf(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.cap) }
     }
-    pub(crate) fn get_buf_mut(&self) -> std::cell::RefMut<'_, Vec<u8>> {
-        self.buf.borrow_mut()
+
+    fn allocate(&self, sz: u32) -> Result<u32, StepDbError> {
+        self.try_allocate(sz).ok_or(StepDbError::ArenaFull)
     }
 
-    fn allocate(&self, sz: u32) -> u32 {
-        let offset = self.n.fetch_add(sz, Relaxed);
-        if !self.is_grow {
-            assert!((offset + sz) <= self.get_buf().len() as u32);
-            return offset;
-        }
-        // TODO： increase the capacity of buf
-        offset
+    // try_allocate returns the offset of sz fresh bytes, None if they don't fit. Offsets
+    // are u32, so no arena reaches past 4GB: the end of an allocation is checked before
+    // the cursor moves, it never wraps around to hand out bytes already in use.
+    fn try_allocate(&self, sz: u32) -> Option<u32> {
+        self.n
+            .fetch_update(Relaxed, Relaxed, |offset| {
+                let end = offset.checked_add(sz)?;
+                // TODO： increase the capacity of buf
+                if !self.is_grow && end as usize > self.cap {
+                    return None;
+                }
+                Some(end)
+            })
+            .ok()
     }
-    fn size(&self) -> i64 {
+    pub(crate) fn size(&self) -> i64 {
         self.n.load(Relaxed) as i64
     }
 
-    pub(crate) fn put_node(&self, height: usize) -> u32 {
+    // allocate_aligned returns the offset of sz fresh bytes, a multiple of align, which must
+    // be a power of two. The base of the arena is aligned to 8, so for an align up to 8 the
+    // address is aligned too, a bigger align only holds for the offset.
+    pub(crate) fn allocate_aligned(&self, sz: u32, align: u32) -> Result<u32, StepDbError> {
+        assert!(align.is_power_of_two(), "align must be a power of two");
+        let mask = align - 1;
+        let padded = sz.checked_add(mask).ok_or(StepDbError::ArenaFull)?;
+        let offset = self.allocate(padded)?;
+        Ok((offset + mask) & !mask)
+    }
+
+    pub(crate) fn put_node(&self, height: usize) -> Result<u32, StepDbError> {
         let unused = (MAX_HEIGHT - height) * OFFSET_SIZE;
-        let sz = (MAX_NODE_SIZE - unused + NODE_ALIGN) as u32;
-        let offset = self.allocate(sz);
-        (offset + NODE_ALIGN as u32) & !(NODE_ALIGN as u32)
+        let sz = (MAX_NODE_SIZE - unused) as u32;
+        self.allocate_aligned(sz, NODE_ALIGN as u32 + 1)
     }
 
-    pub(crate) fn put_key(&self, key: Vec<u8>) -> u32 {
+    pub(crate) fn put_key(&self, key: Vec<u8>) -> Result<u32, StepDbError> {
         let key_sz = key.len() as u32;
-        let offset = self.allocate(key_sz);
-        let end = (offset + key_sz) as usize;
-        self.get_buf_mut()[offset as usize..end].copy_from_slice(&key);
-        offset
+        let offset = self.allocate(key_sz)?;
+        // SAFETY: [offset, offset + key_sz) was just allocated, nothing else refers to it.
+        unsafe {
+            ptr::copy_nonoverlapping(key.as_ptr(), self.ptr.add(offset as usize), key.len());
+        }
+        Ok(offset)
     }
 
-    pub(crate) fn put_value(&self, value: &Value) -> u32 {
+    pub(crate) fn put_value(&self, value: &Value) -> Result<u32, StepDbError> {
         let encode_sz = value.encoded_size();
-        let offset = self.allocate(encode_sz as u32) as usize;
-        value.encode_value(&mut self.get_buf_mut()[offset..]);
-        offset as u32
+        let offset = match self.take_free(encode_sz as u32) {
+            Some(offset) => offset,
+            None => self.allocate(encode_sz as u32)?,
+        } as usize;
+        // SAFETY: the slot is either freshly allocated or was freed by free_value, so no
+        // node refers to it any more.
+        let buf = unsafe { slice::from_raw_parts_mut(self.ptr.add(offset), encode_sz) };
+        value.encode_value(buf);
+        Ok(offset as u32)
     }
 
-    pub(crate) fn get_node_mut(&self, offset: u32) -> Option<Rc<&mut Node>> {
-        if offset == 0 {
-            return None;
+    // free_value hands the slot of a value that is no longer referenced back to the arena,
+    // put_value reuses it for a later value that fits.
+    pub(crate) fn free_value(&self, offset: u32, sz: u32) {
+        if offset == 0 || sz == 0 {
+            return;
         }
-        let x = unsafe {
-            mem::transmute::<&mut u8, &mut Node>(&mut self.get_buf_mut()[offset as usize])
-        };
+        let class = 1 << sz.ilog2();
+        self.free
+            .borrow_mut()
+            .entry(class)
+            .or_default()
+            .push((offset, sz));
+    }
 
-        Some(Rc::new(x))
+    // take_free returns a freed slot with room for sz bytes, if any.
+    fn take_free(&self, sz: u32) -> Option<u32> {
+        if sz == 0 {
+            return None;
+        }
+        let mut free = self.free.borrow_mut();
+        // Slots in the bucket of sz may be smaller than sz, so check their size.
+        let class = 1 << sz.ilog2();
+        if let Some(slots) = free.get_mut(&class) {
+            if let Some(i) = slots.iter().position(|&(_, size)| size >= sz) {
+                return Some(slots.swap_remove(i).0);
+            }
+        }
+        // Any slot in a bigger bucket fits.
+        let (_, slots) = free
+            .range_mut(class * 2..)
+            .find(|(_, slots)| !slots.is_empty())?;
+        slots.pop().map(|(offset, _)| offset)
     }
 
-    pub(crate) fn get_node(&self, offset: u32) -> Option<Rc<&Node>> {
+    // get_node returns the node at offset, None for the null offset.
+    //
+    // Safety contract of the arena: offset must have been returned by put_node. The header
+    // of a node (key_offset, key_size, height) is written by init_node only, before the
+    // offset is linked into any tower, and never changes after. Once published the node is
+    // only changed through its atomics, so any number of &Node may coexist with inserts.
+    // Debug builds check the offset against the allocated part of the arena, a stale or
+    // made up offset fails there rather than reading zeroed memory as a node.
+    pub(crate) fn get_node(&self, offset: u32) -> Option<&Node> {
         if offset == 0 {
             return None;
         }
-        let x = unsafe { mem::transmute::<&u8, &Node>(&self.get_buf()[offset as usize]) };
-        println!("get_node node:{:?}", x);
-        Some(Rc::new(x))
+        debug_assert_eq!(0, offset as usize % mem::align_of::<Node>());
+        debug_assert!(
+            offset < self.n.load(Relaxed),
+            "node offset {} is past the {} bytes allocated in the arena",
+            offset,
+            self.n.load(Relaxed)
+        );
+        Some(unsafe { &*(self.ptr.add(offset as usize) as *const Node) })
+    }
+
+    // offset_of returns the offset of n, a node returned by get_node.
+    pub(crate) fn offset_of(&self, n: &Node) -> u32 {
+        debug_assert!(
+            (n as *const Node as usize).wrapping_sub(self.ptr as usize) < self.cap,
+            "node is not in this arena"
+        );
+        (n as *const Node as usize - self.ptr as usize) as u32
+    }
+
+    // init_node writes the header of the node at node_offset. It must be called once, right
+    // after put_node and before the node is reachable from the list.
+    pub(crate) fn init_node(
+        &self,
+        node_offset: u32,
+        key_offset: u32,
+        key_size: u16,
+        height: u16,
+        value: u64,
+    ) {
+        debug_assert_eq!(0, node_offset as usize % mem::align_of::<Node>());
+        let node = unsafe { self.ptr.add(node_offset as usize) as *mut Node };
+        // SAFETY: the node is not published yet, no reference to it exists. Fields are
+        // written through the raw pointer without creating a &mut Node.
+        unsafe {
+            ptr::addr_of_mut!((*node).key_offset).write(key_offset);
+            ptr::addr_of_mut!((*node).key_size).write(key_size);
+            ptr::addr_of_mut!((*node).height).write(height);
+            ptr::addr_of_mut!((*node).value).write(AtomicU64::new(value));
+        }
     }
 
     pub(crate) fn get_key(&self, offset: u32, sz: u16) -> Vec<u8> {
-        let offset = offset as usize;
-        let end = offset + sz as usize;
-        println!("offset:{},end:{}", offset, end);
-        self.get_buf()[offset..end].to_vec()
+        self.key_bytes(offset, sz).to_vec()
     }
+
+    // key_bytes and value_bytes borrow a key and an encoded value from the arena rather than
+    // copying them out. A value slot can be freed and reused by a later update, so the
+    // slices mustn't be kept across writes to the list.
+    pub(crate) fn key_bytes(&self, offset: u32, sz: u16) -> &[u8] {
+        let end = offset as usize + sz as usize;
+        assert!(end <= self.cap);
+        unsafe { slice::from_raw_parts(self.ptr.add(offset as usize), sz as usize) }
+    }
+
+    // compare_key_at orders the key at offset against other by cmp, reading the key in
+    // place. The lists compare keys with it while they walk the towers, so a search
+    // doesn't copy every key it passes.
+    pub(crate) fn compare_key_at(
+        &self,
+        offset: u32,
+        sz: u16,
+        other: &[u8],
+        cmp: &KeyOrder,
+    ) -> Ordering {
+        cmp(self.key_bytes(offset, sz), other)
+    }
+
+    pub(crate) fn value_bytes(&self, offset: u32, sz: u32) -> &[u8] {
+        let end = offset as usize + sz as usize;
+        assert!(end <= self.cap);
+        unsafe { slice::from_raw_parts(self.ptr.add(offset as usize), sz as usize) }
+    }
+
     pub fn get_value(&self, offset: u32, sz: u32) -> Value {
-        let end = (offset + sz) as usize;
         let mut ret = Value::default();
-        ret.decode_value(&self.get_buf()[offset as usize..end]);
+        ret.decode_value(self.value_bytes(offset, sz))
+            .expect("value in the arena");
         ret
     }
+}
+
+// ArenaPool keeps the buffers of arenas of one size that are done with, and hands them
+// out again for new arenas of that size, so rotating memtables doesn't allocate and free
+// a whole arena each time.
+pub(crate) struct ArenaPool {
+    size: u32,
+    free: Vec<Box<[u64]>>,
+}
+
+impl ArenaPool {
+    pub(crate) fn new(size: u32) -> ArenaPool {
+        ArenaPool {
+            size,
+            free: Vec::new(),
+        }
+    }
 
-    pub fn get_node_offset(&self, node: &Node) -> u32 {
-        let node_ptr = node as *const Node as *const u8;
-        let arena_start = self.get_buf().as_ptr();
-        unsafe { node_ptr.offset_from(arena_start) as u32 }
+    // get returns an empty arena of the size of the pool, on a recycled buffer if any.
+    pub(crate) fn get(&mut self) -> Area {
+        match self.free.pop() {
+            Some(buf) => Area::with_buf((self.size as usize).div_ceil(8) * 8, buf),
+            None => Area::new(self.size),
+        }
+    }
+
+    // put takes the buffer of area back for a later get, it is zeroed here. An arena of
+    // another size is dropped.
+    pub(crate) fn put(&mut self, area: Area) {
+        if area.cap == (self.size as usize).div_ceil(8) * 8 {
+            self.free.push(area.into_buf());
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.free.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::area::Area;
+    use crate::error::StepDbError;
+    use crate::memory::area::{Area, ArenaPool};
     use crate::memory::entry::Value;
+    use crate::memory::keys::key_with_ts;
+    use crate::memory::skiplist::{Node, MAX_HEIGHT};
+    use crate::memory::utils::compare_keys;
+    use std::sync::atomic::Ordering::Relaxed;
 
     #[test]
     fn test_area() {
@@ -124,12 +324,12 @@ mod tests {
         };
         let area = Area::new(1000);
 
-        let node_offset = area.put_node(height);
+        let node_offset = area.put_node(height).unwrap();
 
-        let key_offset = area.put_key(k.clone());
+        let key_offset = area.put_key(k.clone()).unwrap();
         let key_size = k.len();
 
-        let value_offset = area.put_value(&v);
+        let value_offset = area.put_value(&v).unwrap();
         let value_size = v.encoded_size();
 
         let node_target = area.get_node(node_offset).unwrap();
@@ -139,4 +339,200 @@ mod tests {
         assert_eq!(k, key_target);
         assert_eq!(v.v, value_target.v);
     }
+
+    #[test]
+    fn test_free_value() {
+        let area = Area::new(1000);
+        let v = Value {
+            v: Vec::from("no step,no miles"),
+            ..Default::default()
+        };
+        let sz = v.encoded_size() as u32;
+        let offset = area.put_value(&v).unwrap();
+        area.free_value(offset, sz);
+        assert_eq!(offset, area.put_value(&v).unwrap());
+
+        // a smaller value fits in a freed slot, a bigger one doesn't
+        area.free_value(offset, sz);
+        let big = Value {
+            v: vec![1; 64],
+            ..Default::default()
+        };
+        assert_ne!(offset, area.put_value(&big).unwrap());
+        let small = Value {
+            v: vec![1; 2],
+            ..Default::default()
+        };
+        assert_eq!(offset, area.put_value(&small).unwrap());
+        assert_eq!(
+            small.v,
+            area.get_value(offset, small.encoded_size() as u32).v
+        );
+    }
+
+    #[test]
+    fn test_compare_key_at() {
+        let area = Area::new(1 << 16);
+        // short keys, compared byte by byte, and versioned ones of every length up to 3
+        let mut keys: Vec<Vec<u8>> = vec![vec![]];
+        for len in 1..=3 {
+            for i in 0..3u32.pow(len) {
+                let k: Vec<u8> = (0..len)
+                    .map(|j| b'a' + (i / 3u32.pow(j) % 3) as u8)
+                    .collect();
+                keys.push(key_with_ts(&k, u64::from(i % 2)));
+                keys.push(k);
+            }
+        }
+        let offsets: Vec<u32> = keys
+            .iter()
+            .map(|k| area.put_key(k.clone()).unwrap())
+            .collect();
+        let default = |a: &[u8], b: &[u8]| compare_keys(a, b).cmp(&0);
+        for (k, &offset) in keys.iter().zip(offsets.iter()) {
+            for other in keys.iter() {
+                let copied = area.get_key(offset, k.len() as u16);
+                assert_eq!(
+                    default(&copied, other),
+                    area.compare_key_at(offset, k.len() as u16, other, &default)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_arena_pool() {
+        let mut pool = ArenaPool::new(1000);
+        let area = pool.get();
+        let ptr = area.get_buf().as_ptr();
+        area.put_key(vec![0xff; 100]).unwrap();
+        pool.put(area);
+        assert_eq!(1, pool.len());
+
+        // the same buffer comes back, zeroed and empty
+        let area = pool.get();
+        assert_eq!(0, pool.len());
+        assert_eq!(ptr, area.get_buf().as_ptr());
+        assert_eq!(1000usize.div_ceil(8) * 8, area.get_buf().len());
+        assert!(area.get_buf().iter().all(|b| *b == 0));
+        assert_eq!(1, area.size());
+        // a second arena is a new buffer
+        let other = pool.get();
+        assert_ne!(ptr, other.get_buf().as_ptr());
+
+        // an arena of another size isn't kept
+        pool.put(Area::new(64));
+        assert_eq!(0, pool.len());
+    }
+
+    #[test]
+    fn test_allocate_aligned() {
+        let area = Area::new(4096);
+        for sz in [1, 3, 16, 17, 100] {
+            // unaligned bytes in between
+            area.allocate(sz % 7 + 1).unwrap();
+            let offset = area.allocate_aligned(sz, 16).unwrap();
+            assert_eq!(0, offset % 16, "{}", sz);
+            assert!(offset + sz <= area.n.load(Relaxed));
+        }
+        // an align of 1 wastes nothing
+        let next = area.n.load(Relaxed);
+        assert_eq!(next, area.allocate_aligned(5, 1).unwrap());
+    }
+
+    #[test]
+    fn test_allocate_overflow() {
+        let mut area = Area::new(64);
+        assert_eq!(Some(1), area.try_allocate(8));
+        // past the capacity
+        assert_eq!(None, area.try_allocate(64));
+        assert_eq!(9, area.n.load(Relaxed));
+
+        // near the u32 boundary the end of an allocation would wrap, even without a
+        // capacity to check it against
+        area.is_grow = true;
+        area.n.store(u32::MAX - 4, Relaxed);
+        assert_eq!(None, area.try_allocate(8));
+        assert_eq!(u32::MAX - 4, area.n.load(Relaxed));
+        assert_eq!(Some(u32::MAX - 4), area.try_allocate(4));
+    }
+
+    #[test]
+    fn test_allocate_full() {
+        let area = Area::new(64);
+        assert_eq!(Err(StepDbError::ArenaFull), area.allocate(128));
+        assert_eq!(Err(StepDbError::ArenaFull), area.put_key(vec![0; 128]));
+        assert_eq!(Err(StepDbError::ArenaFull), area.put_node(MAX_HEIGHT));
+        assert!(area.put_key(vec![0; 32]).is_ok());
+    }
+
+    #[test]
+    fn test_area_null_offset() {
+        let area = Area::new(1000);
+        assert!(area.get_node(0).is_none());
+        for sz in [1, 7, 8, 33] {
+            assert_ne!(0, area.allocate(sz).unwrap());
+        }
+        assert_ne!(0, area.put_node(1).unwrap());
+    }
+
+    #[test]
+    fn test_node_ref_during_writes() {
+        let area = Area::new(1 << 12);
+        let offset = area.put_node(3).unwrap();
+        area.init_node(offset, 0, 0, 3, 0);
+        let node = area.get_node(offset).unwrap();
+        // writes to other parts of the arena must not disturb a node held by reference
+        for i in 0..20 {
+            let other = area.put_node(i % 3 + 1).unwrap();
+            area.init_node(other, 1, 1, 1, 0);
+            node.tower[0].store(other, Relaxed);
+            area.put_key(vec![0xff; 5]).unwrap();
+            area.put_value(&Value {
+                v: vec![0xff; 9],
+                ..Default::default()
+            })
+            .unwrap();
+            assert_eq!(3, node.height);
+            assert_eq!(other, node.tower[0].load(Relaxed));
+            assert_eq!(0, node.tower[2].load(Relaxed));
+        }
+    }
+
+    #[test]
+    fn test_node_align() {
+        let area = Area::new(1 << 16);
+        for i in 0..200 {
+            // keys of odd sizes leave the cursor unaligned
+            area.put_key(vec![0; i % 7 + 1]).unwrap();
+            let offset = area.put_node(i % MAX_HEIGHT + 1).unwrap();
+            assert_eq!(0, offset % 8);
+            let node = area.get_node(offset).unwrap();
+            assert_eq!(0, (node as *const Node as usize) % 8);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "past the 1 bytes allocated")]
+    fn test_get_node_unallocated() {
+        let area = Area::new(1000);
+        area.get_node(8);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "node is not in this arena")]
+    fn test_offset_of_foreign_node() {
+        let a = Area::new(1000);
+        let b = Area::new(1000);
+        let offset = a.put_node(1).unwrap();
+        b.offset_of(a.get_node(offset).unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_area_zero_size() {
+        Area::new(0);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::memory::hash::hash32;
 use std::cmp::{max, min};
 use std::f64::consts::LN_2;
 
@@ -7,6 +8,12 @@ pub struct BloomFilter {
     k: u8,
 }
 
+// The false positive rate a filter is built for is clamped to [MIN_FALSE_POSITIVE,
+// MAX_FALSE_POSITIVE]. A rate of 0 would need an infinite bitmap, a rate of 1 or more an
+// empty one.
+const MIN_FALSE_POSITIVE: f64 = 1e-4;
+const MAX_FALSE_POSITIVE: f64 = 0.5;
+
 pub fn new(num_entries: isize, false_positive: f64) -> BloomFilter {
     init_filter(num_entries, false_positive)
 }
@@ -19,13 +26,40 @@ fn bloom_bits(n: isize, fp: f64) -> f64 {
     -(n as f64 * fp.ln()) / LN_2.powi(2)
 }
 
+// from_bytes rebuilds a filter from a bitmap produced by BloomFilter::as_bytes,
+// the last byte of the bitmap holds k.
+pub(crate) fn from_bytes(bitmap: Vec<u8>) -> BloomFilter {
+    let k = bitmap.last().copied().unwrap_or(0);
+    BloomFilter {
+        bitmap,
+        k: k.clamp(1, 30),
+    }
+}
+
+// init_filter builds a filter for num_entries keys, at least 1, and false_positive out of
+// the range above is clamped into it, so bad arguments still give a usable filter.
 pub fn init_filter(num_entries: isize, false_positive: f64) -> BloomFilter {
+    let num_entries = max(1, num_entries);
+    let false_positive = if false_positive.is_nan() {
+        MAX_FALSE_POSITIVE
+    } else {
+        false_positive.clamp(MIN_FALSE_POSITIVE, MAX_FALSE_POSITIVE)
+    };
+    let bits = bloom_bits(num_entries, false_positive);
+    let bits_per_key = max(0, (bits / num_entries as f64).ceil() as isize);
+    new_with_bits_per_key(num_entries, bits_per_key)
+}
+
+// new_with_bits_per_key builds a filter for num_entries keys with bits_per_key bits each,
+// for callers that know the size they want rather than the false positive rate, 10 bits
+// per key is about 1%. The bitmap has at least 64 bits.
+pub fn new_with_bits_per_key(num_entries: isize, bits_per_key: isize) -> BloomFilter {
     let mut bf = BloomFilter {
         bitmap: Vec::new(),
         k: 0,
     };
-    let bits = bloom_bits(num_entries, false_positive);
-    let bits_per_key = max(0, (bits / num_entries as f64).ceil() as isize);
+    let num_entries = max(1, num_entries);
+    let bits_per_key = max(0, bits_per_key);
 
     // k = (m/n)*ln2
     // k == Number of hash times/functions
@@ -55,8 +89,25 @@ impl BloomFilter {
         }
         true
     }
-    fn may_exist_key(&self, k: &[u8]) -> bool {
-        self.may_exist(hash(k))
+    // add_key adds key to the filter, for a filter built up front from a known set of keys.
+    pub fn add_key(&mut self, key: &[u8]) {
+        self.insert(hash(key));
+    }
+
+    // contains reports whether key may have been added, a false positive is possible but
+    // a false negative is not.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.may_exist(hash(key))
+    }
+
+    // may_exist_batch tells for each of keys whether it may be in the filter,
+    // the result is in the same order as keys.
+    pub(crate) fn may_exist_batch(&self, keys: &[&[u8]]) -> Vec<bool> {
+        if self.bitmap.len() < 2 {
+            return vec![false; keys.len()];
+        }
+        let bits = 8 * (self.bitmap.len() - 1) as u32;
+        keys.iter().map(|k| self.probe(hash(k), bits)).collect()
     }
 
     fn may_exist(&self, h: u32) -> bool {
@@ -64,6 +115,11 @@ impl BloomFilter {
             return false;
         }
         let bits = 8 * (self.bitmap.len() - 1) as u32;
+        self.probe(h, bits)
+    }
+
+    // probe checks the k bits of h, bits is the number of bits in the bitmap.
+    fn probe(&self, h: u32, bits: u32) -> bool {
         let delta = (h >> 17) | (h << 15);
         let mut h = h;
         for _ in 0..self.k {
@@ -76,9 +132,11 @@ impl BloomFilter {
         }
         true
     }
-    fn allow_key(&mut self, k: &[u8]) -> bool {
+    pub(crate) fn allow_key(&mut self, k: &[u8]) -> bool {
         self.allow(hash(k))
     }
+    // allow checks for h and adds it if it is missing, in one call, for the doorkeeper of
+    // the cache.
     pub(crate) fn allow(&mut self, h: u32) -> bool {
         let already = self.may_exist(h);
         if !already {
@@ -86,6 +144,40 @@ impl BloomFilter {
         }
         already
     }
+    // estimated_fpr estimates the current false positive rate as (set bits / bits)^k,
+    // a filter loaded beyond its capacity shows a rate above the one it was built for.
+    pub fn estimated_fpr(&self) -> f64 {
+        let fill = self.fill_ratio();
+        if fill == 1.0 {
+            return 1.0;
+        }
+        fill.powi(self.k as i32)
+    }
+
+    // bit_count is the number of bits set in the bitmap, the byte holding k is not counted.
+    pub fn bit_count(&self) -> usize {
+        self.bits().iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    // fill_ratio is the share of the bits of the bitmap that are set, a filter near 1.0
+    // reports almost every key as present.
+    pub fn fill_ratio(&self) -> f64 {
+        let bits = self.bits();
+        if bits.is_empty() {
+            return 0.0;
+        }
+        self.bit_count() as f64 / (8 * bits.len()) as f64
+    }
+
+    // bits is the bitmap without the trailing byte holding k.
+    fn bits(&self) -> &[u8] {
+        &self.bitmap[..self.bitmap.len().saturating_sub(1)]
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bitmap
+    }
+
     pub fn reset(&mut self) {
         for v in self.bitmap.iter_mut() {
             *v = 0;
@@ -94,12 +186,14 @@ impl BloomFilter {
 }
 
 fn hash(bytes: &[u8]) -> u32 {
-    murmurhash32::murmurhash3(bytes)
+    hash32(bytes)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::bloom::new;
+    use crate::memory::bloom::{
+        new, new_with_bits_per_key, MAX_FALSE_POSITIVE, MIN_FALSE_POSITIVE,
+    };
 
     #[test]
     fn test_bloom() {
@@ -110,11 +204,141 @@ mod tests {
         bf.allow_key(k1);
         bf.allow_key(k2);
 
-        let exist1 = bf.may_exist_key(k1);
-        let exist2 = bf.may_exist_key(k2);
-        let exist3 = bf.may_exist_key(k3);
+        let exist1 = bf.contains(k1);
+        let exist2 = bf.contains(k2);
+        let exist3 = bf.contains(k3);
         assert!(exist1);
         assert!(exist2);
         assert!(!exist3);
     }
+
+    #[test]
+    fn test_add_key() {
+        let keys: Vec<String> = (0..500).map(|i| format!("key{}", i)).collect();
+        let mut bf = new(keys.len() as isize, 0.01);
+        for k in keys.iter() {
+            bf.add_key(k.as_bytes());
+        }
+        for k in keys.iter() {
+            assert!(bf.contains(k.as_bytes()));
+        }
+        let absent = (500..1500)
+            .filter(|i| bf.contains(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(absent < 30, "{}", absent);
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        let cases = [
+            ((1000, 0.0), (1000, MIN_FALSE_POSITIVE)),
+            ((1000, -1.0), (1000, MIN_FALSE_POSITIVE)),
+            ((1000, 1.0), (1000, MAX_FALSE_POSITIVE)),
+            ((1000, f64::NAN), (1000, MAX_FALSE_POSITIVE)),
+            ((0, 0.01), (1, 0.01)),
+            ((-5, 0.01), (1, 0.01)),
+        ];
+        for ((n, fp), (want_n, want_fp)) in cases {
+            let mut bf = new(n, fp);
+            let want = new(want_n, want_fp);
+            assert_eq!(want.bitmap.len(), bf.bitmap.len(), "{} {}", n, fp);
+            assert_eq!(want.k, bf.k, "{} {}", n, fp);
+            assert!((1..=30).contains(&bf.k));
+            bf.allow_key(b"key");
+            assert!(bf.contains(b"key"));
+        }
+    }
+
+    #[test]
+    fn test_bits_per_key() {
+        for (n, bits_per_key, want_k) in [(1000, 10, 6), (1000, 20, 13), (5000, 4, 2)] {
+            let mut bf = new_with_bits_per_key(n, bits_per_key);
+            assert_eq!(
+                ((n * bits_per_key) as usize).div_ceil(8) + 1,
+                bf.bitmap.len()
+            );
+            assert_eq!(want_k, bf.k, "{}", bits_per_key);
+            bf.allow_key(b"key");
+            assert!(bf.contains(b"key"));
+        }
+        // the derivation from a false positive rate lands on the same filter
+        let bf = new_with_bits_per_key(1000, 10);
+        let derived = new(1000, 0.01);
+        assert_eq!(derived.bitmap.len(), bf.bitmap.len());
+        assert_eq!(derived.k, bf.k);
+        // small filters keep 64 bits, k stays in range
+        let bf = new_with_bits_per_key(1, 1);
+        assert_eq!(9, bf.bitmap.len());
+        assert_eq!(1, bf.k);
+        assert_eq!(30, new_with_bits_per_key(10, 100).k);
+    }
+
+    #[test]
+    fn test_may_exist_batch() {
+        let mut bf = new(1000, 0.01);
+        let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+        for k in keys.iter().step_by(2) {
+            bf.allow_key(k.as_bytes());
+        }
+        let batch: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+        let got = bf.may_exist_batch(&batch);
+        assert_eq!(keys.len(), got.len());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(bf.contains(k.as_bytes()), got[i]);
+            if i % 2 == 0 {
+                assert!(got[i]);
+            }
+        }
+        // with a 1% false positive rate most absent keys are reported absent
+        assert!(got.iter().skip(1).step_by(2).filter(|&&b| b).count() < 10);
+        assert!(bf.may_exist_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_estimated_fpr() {
+        let mut bf = new(100, 0.01);
+        assert_eq!(0.0, bf.estimated_fpr());
+        let mut last = 0.0;
+        let mut i = 0;
+        while bf.estimated_fpr() < 0.1 {
+            bf.allow_key(format!("key{}", i).as_bytes());
+            let fpr = bf.estimated_fpr();
+            assert!(fpr >= last);
+            last = fpr;
+            i += 1;
+        }
+        // it only crosses 10% well past the 100 keys the filter was sized for
+        assert!(i > 100, "{}", i);
+        // around the capacity the estimate is close to the configured rate
+        let mut bf = new(100, 0.01);
+        for i in 0..100 {
+            bf.allow_key(format!("key{}", i).as_bytes());
+        }
+        assert!(bf.estimated_fpr() < 0.03, "{}", bf.estimated_fpr());
+
+        bf.bitmap.iter_mut().for_each(|b| *b = 0xff);
+        assert_eq!(1.0, bf.estimated_fpr());
+    }
+
+    #[test]
+    fn test_fill_ratio() {
+        let mut bf = new(100, 0.01);
+        assert_eq!(0.0, bf.fill_ratio());
+        assert_eq!(0, bf.bit_count());
+        let total = 8 * (bf.bitmap.len() - 1);
+        let mut last = 0.0;
+        for i in 0..500 {
+            bf.allow_key(format!("key{}", i).as_bytes());
+            let fill = bf.fill_ratio();
+            assert!(fill >= last);
+            assert_eq!(bf.bit_count() as f64 / total as f64, fill);
+            last = fill;
+        }
+        // five times the keys the filter was sized for leave few bits unset
+        assert!(last > 0.9, "{}", last);
+        assert!(bf.bit_count() <= total);
+
+        bf.reset();
+        assert_eq!(0.0, bf.fill_ratio());
+    }
 }
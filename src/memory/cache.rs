@@ -1,27 +1,125 @@
 use crate::memory::bloom::BloomFilter;
+use crate::memory::clock::{Clock, SystemClock};
 use crate::memory::counter::CMSketch;
-use crate::memory::lru::{new_lru, new_slru, Map, SegmentedLRU, StoreItem, WindowLRU};
+use crate::memory::entry::ttl_at;
+use crate::memory::hash::{hash_key, CONFLICT_SEED};
+use crate::memory::lru::{
+    new_lru, new_slru, Item, Map, SegmentedLRU, StoreItem, WindowLRU, STAGE_ONE, STAGE_TWO,
+};
 use crate::memory::{bloom, counter};
-use std::cell::RefCell;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::hash::{DefaultHasher, Hash, Hasher};
+use std::hash::Hash;
+use std::io::{Read, Write};
 use std::marker::PhantomData;
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Cache<K, V> {
-    m: RwLock<u8>,
+    m: RwLock<Policy<V>>,
+    size: usize,
+    // expiry is checked against clock
+    clock: Arc<dyn Clock>,
+    _pd: PhantomData<K>,
+}
+
+// Iter yields the key hash and a clone of the value of every cached item.
+// It holds the read lock of the cache until it is dropped.
+pub struct Iter<'a, V> {
+    _unused: RwLockReadGuard<'a, Policy<V>>,
+    items: std::vec::IntoIter<Item<V>>,
+}
+
+impl<V: Clone> Iterator for Iter<'_, V> {
+    type Item = (u64, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.items.next()?;
+        let item = item.lock().unwrap();
+        Some((item.key, item.value.clone()))
+    }
+}
+
+// CacheEntry is a key of the cache looked up once by Cache::entry, cached or not. It holds
+// the write lock of the cache until it is dropped.
+pub enum CacheEntry<'a, V> {
+    Occupied(OccupiedEntry<'a, V>),
+    Vacant(VacantEntry<'a, V>),
+}
+
+pub struct OccupiedEntry<'a, V> {
+    p: RwLockWriteGuard<'a, Policy<V>>,
+    item: Item<V>,
+    key_hash: u64,
+    conflict_hash: u64,
+}
+
+impl<V: Clone> OccupiedEntry<'_, V> {
+    pub fn get(&self) -> V {
+        self.item.lock().unwrap().value.clone()
+    }
+
+    // update applies f to the cached value in place.
+    pub fn update(&mut self, f: impl FnOnce(&mut V)) {
+        f(&mut self.item.lock().unwrap().value);
+    }
+
+    // remove deletes the item from the cache and returns its value.
+    pub fn remove(mut self) -> V {
+        self.p.del(self.key_hash, self.conflict_hash);
+        self.item.lock().unwrap().value.clone()
+    }
+}
+
+pub struct VacantEntry<'a, V> {
+    p: RwLockWriteGuard<'a, Policy<V>>,
+    key_hash: u64,
+    conflict_hash: u64,
+}
+
+impl<V: Clone> VacantEntry<'_, V> {
+    // insert sets the value of the key, it goes through admission as in Cache::set.
+    pub fn insert(mut self, value: V) -> bool {
+        let ret = self.p.set(self.key_hash, self.conflict_hash, value, 1, 0);
+        notify_evicted(self.p);
+        ret
+    }
+}
+
+// Policy holds the state guarded by Cache::m.
+#[derive(Debug)]
+struct Policy<V> {
     lru: WindowLRU<V>,
     slru: SegmentedLRU<V>,
     watch_dog: BloomFilter,
+    // false skips watch_dog, admission is decided on the frequencies in c alone
+    use_doorkeeper: bool,
     c: CMSketch,
+    // t counts the gets and sets since the last reset of c and watch_dog, both are
+    // reset when it reaches threshold so old frequencies fade out
     t: i32,
     threshold: i32,
     data: Map<V>,
-    _pd: PhantomData<K>,
+    on_evict: EvictListener<V>,
+    // the items set evicted, for on_evict once the write lock is released
+    evicted: Vec<(u64, V)>,
+}
+
+// EvictListener is called with the key hash and the value of every evicted item.
+struct EvictListener<V>(Option<Arc<dyn Fn(u64, V) + Send + Sync>>);
+
+impl<V> std::fmt::Debug for EvictListener<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
 }
 
+// SAMPLE_FACTOR times the cache size is the number of operations between two resets of
+// the frequency sketch and the doorkeeper.
+const SAMPLE_FACTOR: usize = 10;
+
 // size is the number of data to be cached
 
 impl<K, V> Cache<K, V>
@@ -30,6 +128,11 @@ where
     V: Clone,
 {
     pub fn new(size: usize) -> Self {
+        Cache::with_clock(size, Arc::new(SystemClock))
+    }
+
+    // with_clock creates a cache that reads the time from clock to expire items.
+    pub fn with_clock(size: usize, clock: Arc<dyn Clock>) -> Self {
         // LRU window size，1% of Total
         let lru_pct = 0.01;
         let lru_sz = ((lru_pct * size as f64) as usize).max(1);
@@ -40,148 +143,683 @@ where
         let slru_one = ((0.2 * slru_sz as f64) as usize).max(1);
         // SLRU stage one size,80% of SLRU
         let slru_two = slru_sz - slru_one;
-        let data = Rc::new(RefCell::new(HashMap::with_capacity(size)));
+        let data = Arc::new(Mutex::new(HashMap::with_capacity(size)));
         Cache {
-            m: Default::default(),
-            lru: new_lru(lru_sz, Rc::clone(&data)),
-            slru: new_slru(slru_one, slru_two, Rc::clone(&data)),
-            watch_dog: bloom::new(size as isize, 0.01),
-            c: counter::new(size as u64),
-            t: 0,
-            threshold: 0,
-            data,
+            m: RwLock::new(Policy {
+                lru: new_lru(lru_sz, Arc::clone(&data)),
+                slru: new_slru(slru_one, slru_two, Arc::clone(&data)),
+                watch_dog: bloom::new(size as isize, 0.01),
+                use_doorkeeper: true,
+                c: counter::new(size as u64),
+                t: 0,
+                threshold: (size * SAMPLE_FACTOR).min(i32::MAX as usize) as i32,
+                data,
+                on_evict: EvictListener(None),
+                evicted: Vec::new(),
+            }),
+            size,
+            clock,
             _pd: PhantomData,
         }
     }
-    fn set(&mut self, key: K, value: V) -> bool {
-        let _unused = self.m.write().expect("set k-v pairs fail");
+    pub fn set(&self, key: K, value: V) -> bool {
+        self.set_with_cost(key, value, 1)
+    }
+
+    // on_evict sets f to be called with the key hash and the value of every item the cache
+    // drops to make room, whether it falls out of the window, is refused admission to the
+    // SLRU, or falls out of the SLRU. Deleted, cleared and expired items are not reported.
+    // f is called after the cache lock is released, so it may use the cache, but calls
+    // from several threads may run at once.
+    pub fn on_evict(&mut self, f: impl Fn(u64, V) + Send + Sync + 'static) {
+        self.m.get_mut().expect("set evict listener fail").on_evict =
+            EvictListener(Some(Arc::new(f)));
+    }
+
+    // preload_frequencies primes the sketch with the known access counts of keys, e.g. the
+    // hot keys before a restart, so admission favors them from the start. No value is
+    // cached and the counts don't age the cache. It is lighter than save and load.
+    pub fn preload_frequencies(&mut self, keys: &[(K, u32)]) {
+        let p = self.m.get_mut().expect("preload frequencies fail");
+        for (key, count) in keys {
+            let key_hash = hash_key(key, 0);
+            p.c.increment_n(key_hash, *count);
+        }
+    }
+
+    // set_doorkeeper turns the doorkeeper in front of admission on or off, it is on by
+    // default. Off, a candidate is admitted on its frequency alone and the bloom filter
+    // is not touched, which spares small caches and uniform workloads its cost.
+    pub fn set_doorkeeper(&mut self, on: bool) {
+        self.m
+            .get_mut()
+            .expect("set doorkeeper fail")
+            .use_doorkeeper = on;
+    }
+
+    // set_and_return is set returning the value key had before, if it was cached. A
+    // present key is overwritten in place, a new key goes through admission as in set.
+    pub fn set_and_return(&self, key: K, value: V) -> Option<V> {
+        let mut p = self.m.write().expect("set k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        let Some(item) = p.live_item(key_hash, conflict_hash, self.clock.now_unix()) else {
+            p.set(key_hash, conflict_hash, value, 1, 0);
+            notify_evicted(p);
+            return None;
+        };
+        // An overwrite counts as a set, see Policy::age.
+        p.age();
+        let mut item = item.lock().unwrap();
+        item.expires_at = 0;
+        Some(std::mem::replace(&mut item.value, value))
+    }
+
+    // set_with_cost sets a k-v pair taking up cost of the cache capacity,
+    // items are evicted until the total cost of the cached items fits.
+    pub fn set_with_cost(&self, key: K, value: V, cost: i64) -> bool {
+        let mut p = self.m.write().expect("set k-v pairs fail");
 
         // keyHash is used for quick lookup, conflictHash is used to check for conflicts
         let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        let ret = p.set(key_hash, conflict_hash, value, cost, 0);
+        notify_evicted(p);
+        ret
+    }
+
+    // set_with_ttl sets a k-v pair that expires ttl from now, an expired item is not
+    // returned by get any more.
+    pub fn set_with_ttl(&self, key: K, value: V, ttl: Duration) -> bool {
+        let mut p = self.m.write().expect("set k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        let expires_at = self.clock.now_unix() + ttl.as_secs();
+        let ret = p.set(key_hash, conflict_hash, value, 1, expires_at);
+        notify_evicted(p);
+        ret
+    }
+
+    // key_to_hash returns the key hash and the conflict hash of k, see memory::hash.
+    pub(crate) fn key_to_hash(&self, k: &K) -> (u64, u64)
+    where
+        K: Hash,
+    {
+        // TODO: if it is a number does it need to be done?
+        (hash_key(k, 0), hash_key(k, CONFLICT_SEED))
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut p = self.m.write().expect("get k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(key);
+        p.get(key_hash, conflict_hash, self.clock.now_unix())
+            .map(|(v, _)| v)
+    }
+
+    // get_many is get for every key in keys, under one acquisition of the write lock. The
+    // values come back in the order of keys, each hit counts as an access.
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let mut p = self.m.write().expect("get k-v pairs fail");
+
+        let now = self.clock.now_unix();
+        keys.iter()
+            .map(|key| {
+                let (key_hash, conflict_hash) = self.key_to_hash(key);
+                p.get(key_hash, conflict_hash, now).map(|(v, _)| v)
+            })
+            .collect()
+    }
+
+    // update applies f to the cached value of key in place, under the write lock, and
+    // counts as an access like get does. It returns false if key is not cached.
+    pub fn update(&self, key: &K, f: impl FnOnce(&mut V)) -> bool {
+        let mut p = self.m.write().expect("update k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(key);
+        let Some(item) = p.access(key_hash, conflict_hash, self.clock.now_unix()) else {
+            return false;
+        };
+        f(&mut item.lock().unwrap().value);
+        true
+    }
+
+    // entry looks key up once for a later get, update, remove or insert. A cached key
+    // counts as an access like get. The write lock is held as long as the entry lives.
+    pub fn entry(&self, key: K) -> CacheEntry<'_, V> {
+        let mut p = self.m.write().expect("get cache entry fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        match p.access(key_hash, conflict_hash, self.clock.now_unix()) {
+            Some(item) => CacheEntry::Occupied(OccupiedEntry {
+                p,
+                item,
+                key_hash,
+                conflict_hash,
+            }),
+            None => CacheEntry::Vacant(VacantEntry {
+                p,
+                key_hash,
+                conflict_hash,
+            }),
+        }
+    }
+
+    // get_with_ttl is get along with how long the value lives on, the ttl is None for an
+    // item set without one.
+    pub fn get_with_ttl(&self, key: &K) -> Option<(V, Option<Duration>)> {
+        let mut p = self.m.write().expect("get k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(key);
+        let (v, expires_at) = p.get(key_hash, conflict_hash, self.clock.now_unix())?;
+        Some((v, ttl_at(expires_at, self.clock.now_unix())))
+    }
+
+    // contains reports whether key is cached and not expired, as get would find it. Unlike
+    // get it neither clones the value nor counts as an access.
+    pub fn contains(&self, key: &K) -> bool {
+        let p = self.m.read().expect("contains k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(key);
+        p.live_item(key_hash, conflict_hash, self.clock.now_unix())
+            .is_some()
+    }
+
+    // frequency is the access frequency of key as estimated by the sketch admission is
+    // decided on, whether key is cached or not. It doesn't count as an access.
+    pub fn frequency(&self, key: &K) -> i64 {
+        let p = self.m.read().expect("get frequency fail");
+
+        let (key_hash, _) = self.key_to_hash(key);
+        p.c.estimate(key_hash)
+    }
+
+    // compare_and_set sets key to new only if its current value equals expected, or if
+    // key is absent when expected is None. The check and the set happen under one
+    // acquisition of the write lock, so no other set can slip in between.
+    // A present key is updated in place and keeps its place in the LRU lists.
+    pub fn compare_and_set(&self, key: &K, expected: Option<&V>, new: V) -> bool
+    where
+        V: PartialEq,
+    {
+        let mut p = self.m.write().expect("compare and set k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(key);
+        let Some(item) = p.live_item(key_hash, conflict_hash, self.clock.now_unix()) else {
+            let ret = expected.is_none() && p.set(key_hash, conflict_hash, new, 1, 0);
+            notify_evicted(p);
+            return ret;
+        };
+        let mut item = item.lock().unwrap();
+        if Some(&item.value) != expected {
+            return false;
+        }
+        p.age();
+        item.value = new;
+        item.expires_at = 0;
+        true
+    }
+
+    // get_or_insert_with returns the cached value of key, or computes it with f and sets it
+    // when the key is absent, all under a single acquisition of the write lock.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+        let mut p = self.m.write().expect("get or insert k-v pairs fail");
+
+        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        if let Some((v, _)) = p.get(key_hash, conflict_hash, self.clock.now_unix()) {
+            return v;
+        }
+        let v = f();
+        p.set(key_hash, conflict_hash, v.clone(), 1, 0);
+        notify_evicted(p);
+        v
+    }
 
+    // len returns the number of items currently cached.
+    pub fn len(&self) -> usize {
+        let p = self.m.read().expect("get cache len fail");
+        let len = p.data.lock().unwrap().len();
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // capacity returns the size the cache was created with.
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    // iter visits every cached item once, in unspecified order.
+    // The read lock is held for the whole iteration.
+    pub fn iter(&self) -> Iter<'_, V> {
+        let guard = self.m.read().expect("iterate cache fail");
+        let items: Vec<_> = guard
+            .data
+            .lock()
+            .unwrap()
+            .values()
+            .map(Arc::clone)
+            .collect();
+        Iter {
+            _unused: guard,
+            items: items.into_iter(),
+        }
+    }
+
+    // keys returns the key hash of every cached item, in unspecified order.
+    pub fn keys(&self) -> impl Iterator<Item = u64> {
+        let p = self.m.read().expect("iterate cache fail");
+        let keys: Vec<_> = p.data.lock().unwrap().keys().copied().collect();
+        keys.into_iter()
+    }
+
+    // debug_dump describes the lists of the cache for debugging: the window, then stage one
+    // and stage two of the SLRU, each from the most to the least recently used item, with
+    // the key hash, the stage and the estimated access frequency of every item.
+    pub fn debug_dump(&self) -> String {
+        let p = self.m.read().expect("dump cache fail");
+        let mut out = String::new();
+        let mut section = |name: &str, items: &mut dyn Iterator<Item = &Item<V>>| {
+            out.push_str(name);
+            out.push_str(":\n");
+            for item in items {
+                let i = item.lock().unwrap();
+                out.push_str(&format!(
+                    "  key={} stage={} freq={}\n",
+                    i.key,
+                    i.stage,
+                    p.c.estimate(i.key)
+                ));
+            }
+        };
+        section("window", &mut p.lru.items());
+        section(
+            "stage one",
+            &mut p
+                .slru
+                .items()
+                .filter(|i| i.lock().unwrap().stage == STAGE_ONE),
+        );
+        section(
+            "stage two",
+            &mut p
+                .slru
+                .items()
+                .filter(|i| i.lock().unwrap().stage == STAGE_TWO),
+        );
+        out
+    }
+
+    // force_age ages the access frequencies now, as if threshold operations had passed,
+    // e.g. after a known shift of the workload. The items stay cached.
+    pub fn force_age(&self) {
+        self.m.write().expect("age cache fail").reset_frequencies();
+    }
+
+    // clear removes all items and forgets all access frequencies.
+    pub fn clear(&self) {
+        self.m.write().expect("clear cache fail").clear();
+    }
+
+    pub fn del(&self, key: K) -> Option<u64> {
+        let mut p = self.m.write().expect("get k-v pairs fail");
+        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+        p.del(key_hash, conflict_hash)
+    }
+}
+
+// Saved is the form Cache::save writes, the items are in the order of
+// WindowLRU::items and then SegmentedLRU::items.
+#[derive(Serialize, Deserialize)]
+struct Saved<V> {
+    items: Vec<SavedItem<V>>,
+    sketch: Vec<u8>,
+    bloom: Vec<u8>,
+    t: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedItem<V> {
+    stage: u8,
+    key: u64,
+    conflict: u64,
+    cost: i64,
+    expires_at: u64,
+    value: V,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    // save writes the items with their place in the LRUs along with the access frequencies,
+    // so load can bring the cache back warm.
+    pub fn save(&self, w: impl Write) -> anyhow::Result<()> {
+        let p = self.m.read().expect("save cache fail");
+        let items = p
+            .lru
+            .items()
+            .chain(p.slru.items())
+            .map(|item| {
+                let i = item.lock().unwrap();
+                SavedItem {
+                    stage: i.stage,
+                    key: i.key,
+                    conflict: i.conflict,
+                    cost: i.cost,
+                    expires_at: i.expires_at,
+                    value: i.value.clone(),
+                }
+            })
+            .collect();
+        let saved = Saved {
+            items,
+            sketch: p.c.to_bytes(),
+            bloom: p.watch_dog.as_bytes().to_vec(),
+            t: p.t,
+        };
+        serde_json::to_writer(w, &saved)?;
+        Ok(())
+    }
+
+    // load reads a cache written by save into a cache of size. Items that don't fit in
+    // size any more are dropped, the least recently used first.
+    pub fn load(r: impl Read, size: usize) -> anyhow::Result<Cache<K, V>> {
+        let saved: Saved<V> = serde_json::from_reader(r)?;
+        let cache = Cache::new(size);
+        {
+            let mut p = cache.m.write().expect("load cache fail");
+            p.c = CMSketch::from_bytes(&saved.sketch)?;
+            p.watch_dog = bloom::from_bytes(saved.bloom);
+            p.t = saved.t;
+            for i in saved.items {
+                let item = StoreItem {
+                    stage: i.stage,
+                    key: i.key,
+                    conflict: i.conflict,
+                    cost: i.cost,
+                    expires_at: i.expires_at,
+                    value: i.value,
+                };
+                if item.stage == 0 {
+                    p.lru.restore(item);
+                } else {
+                    p.slru.restore(item);
+                }
+            }
+        }
+        Ok(cache)
+    }
+}
+
+// CacheLike is what callers need from a cache, so they can run with caching turned off.
+pub trait CacheLike<K, V> {
+    fn get(&self, key: &K) -> Option<V>;
+    fn set(&self, key: K, value: V) -> bool;
+    fn del(&self, key: K) -> Option<u64>;
+}
+
+impl<K, V> CacheLike<K, V> for Cache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        Cache::get(self, key)
+    }
+    fn set(&self, key: K, value: V) -> bool {
+        Cache::set(self, key, value)
+    }
+    fn del(&self, key: K) -> Option<u64> {
+        Cache::del(self, key)
+    }
+}
+
+// NoopCache stores nothing, every get misses.
+#[derive(Debug, Default)]
+pub struct NoopCache;
+
+impl<K, V> CacheLike<K, V> for NoopCache {
+    fn get(&self, _key: &K) -> Option<V> {
+        None
+    }
+    fn set(&self, _key: K, _value: V) -> bool {
+        false
+    }
+    fn del(&self, _key: K) -> Option<u64> {
+        None
+    }
+}
+
+// notify_evicted hands the items evicted under p to the evict listener. The write lock is
+// released first, so a listener that calls into the cache doesn't deadlock.
+fn notify_evicted<V>(mut p: RwLockWriteGuard<'_, Policy<V>>) {
+    if p.evicted.is_empty() {
+        return;
+    }
+    let evicted = std::mem::take(&mut p.evicted);
+    let f = p.on_evict.0.clone();
+    drop(p);
+    if let Some(f) = f {
+        for (key, value) in evicted {
+            f(key, value);
+        }
+    }
+}
+
+impl<V> Policy<V>
+where
+    V: Clone,
+{
+    fn set(
+        &mut self,
+        key_hash: u64,
+        conflict_hash: u64,
+        value: V,
+        cost: i64,
+        expires_at: u64,
+    ) -> bool {
+        self.age();
+        // An item already cached under key_hash is replaced. Left linked it would hold on
+        // to its cost and, once evicted, take the new item out of the map with it.
+        self.unlink(key_hash);
         // The newly added memory items are first placed in the window LRU, so stage = 0
         let item = StoreItem {
             stage: 0,
             key: key_hash,
             conflict: conflict_hash,
+            cost,
+            expires_at,
             value,
         };
 
         // If the window is full, the evicted data is returned
-        if let Some(lru_victim) = self.lru.add(item) {
+        for lru_victim in self.lru.add(item) {
             // If there is evicted data from the window, we need to find a victim from the stageOne part of the SLRU
             // and perform a comparison between the two
             if let Some(slru_victim) = self.slru.victim() {
-                // The window LRU's evicted data can enter stageOne since the SLRU is not full
-                if !self.watch_dog.allow(lru_victim.borrow().key as u32) {
-                    return true;
+                let victim_hash = slru_victim.lock().unwrap().key;
+                let candidate_hash = lru_victim.lock().unwrap().key;
+                if !self.admit(victim_hash, candidate_hash) {
+                    self.evicted(&lru_victim);
+                    continue;
                 }
+            }
+            // The window LRU's evicted data can enter stageOne since the SLRU is not full,
+            // or it is more frequent than the SLRU victim
+            for slru_victim in self.slru.add(lru_victim) {
+                self.evicted(&slru_victim);
+            }
+        }
+        true
+    }
 
-                let lru_count = self.c.estimate(lru_victim.borrow().key);
-                let slru_count = self.c.estimate(slru_victim.borrow().key);
+    // admit is the TinyLFU decision on whether candidate, falling out of the window, may
+    // take the place of victim in the SLRU. The candidate has to pass the doorkeeper, then
+    // it must have been used at least as often as the victim.
+    fn admit(&mut self, victim_hash: u64, candidate_hash: u64) -> bool {
+        if self.use_doorkeeper && !self.watch_dog.allow(candidate_hash as u32) {
+            return false;
+        }
+        self.c.estimate(candidate_hash) >= self.c.estimate(victim_hash)
+    }
 
-                if lru_count < slru_count {
-                    return true;
-                }
-            } else {
-                // The window LRU's evicted data can enter stageOne since the SLRU is not full
-                self.slru.add(lru_victim);
-                return true;
-            }
-        } else {
-            return true;
+    // evicted keeps an item dropped by set for the evict listener, see notify_evicted.
+    fn evicted(&mut self, item: &Item<V>) {
+        if self.on_evict.0.is_some() {
+            let i = item.lock().unwrap();
+            self.evicted.push((i.key, i.value.clone()));
         }
-        false
     }
 
-    fn key_to_hash(&self, k: &K) -> (u64, u64)
-    where
-        K: Hash,
-    {
-        let mut hasher = DefaultHasher::new();
-        k.hash(&mut hasher);
-        let h1 = hasher.finish();
-        // TODO: if it is a number does it need to be done?
-        let mut hasher = xxhash_rust::xxh3::Xxh3::default();
-        k.hash(&mut hasher);
-        let h2 = hasher.finish();
-        (h1, h2)
+    // get returns the value and the expiry of the item, an item expired at now is not
+    // returned.
+    fn get(&mut self, key_hash: u64, conflict_hash: u64, now: u64) -> Option<(V, u64)> {
+        let item = self.access(key_hash, conflict_hash, now)?;
+        let i = item.lock().unwrap();
+        Some((i.value.clone(), i.expires_at))
     }
 
-    fn get(&mut self, key: &K) -> Option<V> {
-        let _unused = self.m.write().expect("get k-v pairs fail");
+    // access returns the live item of key and counts it as an access, bumping its frequency
+    // and its recency in the LRU list it is in. Only the sketch counts reads, the doorkeeper
+    // is left to admit.
+    fn access(&mut self, key_hash: u64, conflict_hash: u64, now: u64) -> Option<Item<V>> {
+        self.age();
 
+        let item = self.live_item(key_hash, conflict_hash, now)?;
+        self.c.increment(key_hash);
+
+        if item.lock().unwrap().stage == 0 {
+            self.lru.get(key_hash);
+        } else {
+            self.slru.get(Arc::clone(&item));
+        }
+        Some(item)
+    }
+
+    fn clear(&mut self) {
+        self.data.lock().unwrap().clear();
+        self.lru.clear();
+        self.slru.clear();
+        self.c.clear();
+        self.watch_dog.reset();
+        self.t = 0;
+    }
+
+    // age counts an operation, every threshold operations the sketch and the doorkeeper
+    // are reset. Sets count too, otherwise a write-only load would fill the doorkeeper
+    // with the keys of window victims and never empty it.
+    fn age(&mut self) {
         self.t += 1;
-        if self.t == self.threshold {
-            self.c.reset();
-            self.watch_dog.reset();
-            self.t = 0;
+        if self.t >= self.threshold {
+            self.reset_frequencies();
         }
+    }
 
-        let (key_hash, conflict_hash) = self.key_to_hash(&key);
+    fn reset_frequencies(&mut self) {
+        self.c.reset();
+        self.watch_dog.reset();
+        self.t = 0;
+    }
 
-        let mut item_opt = None;
-        if let Some(item) = self.data.borrow().get(&key_hash) {
-            let item_ref = item.borrow();
-            if item_ref.conflict != conflict_hash {
+    // live_item returns the item of key if it is cached and not expired at now. Unlike
+    // get it doesn't count as an access.
+    fn live_item(&self, key_hash: u64, conflict_hash: u64, now: u64) -> Option<Item<V>> {
+        let item = Arc::clone(self.data.lock().unwrap().get(&key_hash)?);
+        {
+            let i = item.lock().unwrap();
+            if i.conflict != conflict_hash || (i.expires_at != 0 && i.expires_at <= now) {
                 return None;
             }
-            self.watch_dog.allow(key_hash as u32);
-            self.c.increment(key_hash);
-
-            if item_ref.stage == 0 {
-                self.lru.get(item_ref.key);
-            } else {
-                item_opt = Some(Rc::clone(&item));
-            }
-            return Some(item_ref.value.clone());
         }
-        if let Some(item) = item_opt {
-            let v = item.borrow().value.clone();
-            self.slru.get(item);
-            return Some(v);
+        Some(item)
+    }
+
+    // del unlinks the item of key from its LRU list as well, so it can't be picked as a
+    // victim after it is gone.
+    fn del(&mut self, key_hash: u64, conflict_hash: u64) -> Option<u64> {
+        let conflict = self
+            .data
+            .lock()
+            .unwrap()
+            .get(&key_hash)?
+            .lock()
+            .unwrap()
+            .conflict;
+        if conflict_hash != conflict {
+            return None;
         }
-        None
+        self.unlink(key_hash);
+        Some(conflict)
     }
-    pub fn del(&self, key: K) -> Option<u64> {
-        let _unused = self.m.write().expect("get k-v pairs fail");
-        let (key_hash, conflict_hash) = self.key_to_hash(&key);
-        if let Some(val) = self.data.borrow().get(&key_hash) {
-            let item = val.borrow();
-            if conflict_hash != item.conflict {
-                return None;
-            }
-            self.data.borrow_mut().remove(&key_hash);
-            return Some(item.conflict);
+
+    // unlink takes the item of key_hash, if any, out of its LRU list and the map.
+    fn unlink(&mut self, key_hash: u64) -> Option<Item<V>> {
+        let stage = self
+            .data
+            .lock()
+            .unwrap()
+            .get(&key_hash)?
+            .lock()
+            .unwrap()
+            .stage;
+        if stage == 0 {
+            self.lru.remove(key_hash)
+        } else {
+            self.slru.remove(key_hash)
         }
-        None
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::cache::Cache;
+    use crate::memory::bloom;
+    use crate::memory::cache::{Cache, CacheEntry, CacheLike, NoopCache, Policy};
+    use crate::memory::clock::MockClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock, Weak};
+    use std::time::Duration;
 
     #[test]
     fn test_key_to_hash() {
         let a = 12314u64;
         let c = Cache::<u64, u64>::new(100);
         let (h1, h2) = c.key_to_hash(&a);
-        // 3962117728473627647,9330451337157661844
-        assert_eq!(h1, 3962117728473627647);
-        assert_eq!(h2, 9330451337157661844);
+        assert_eq!(h1, 9330451337157661844);
+        assert_eq!(h2, 3702504848840823680);
 
         let a = "hello ferris".to_string();
         let c = Cache::<String, u64>::new(100);
         let (h1, h2) = c.key_to_hash(&a);
-        // 12643562960511582310,17903442243031495094
-        assert_eq!(h1, 12643562960511582310);
-        assert_eq!(h2, 17903442243031495094);
+        assert_eq!(h1, 17903442243031495094);
+        assert_eq!(h2, 17115048271090234781);
+    }
+
+    #[test]
+    fn test_sketch_and_doorkeeper_hash() {
+        // the doorkeeper and the sketch both see the key hash of a key
+        let cache = Cache::<String, u64>::new(100);
+        let key = "key".to_string();
+        let (key_hash, _) = cache.key_to_hash(&key);
+        assert_eq!(key_hash, Cache::<String, u64>::new(10).key_to_hash(&key).0);
+
+        let mut p = cache.m.write().unwrap();
+        p.admit(0, key_hash);
+        let mut want = bloom::new(100, 0.01);
+        want.allow(key_hash as u32);
+        assert_eq!(want.as_bytes(), p.watch_dog.as_bytes());
+        drop(p);
+
+        cache.set(key.clone(), 1);
+        cache.get(&key);
+        assert_eq!(1, cache.m.read().unwrap().c.estimate(key_hash));
     }
 
     #[test]
     fn test_cache_basic_crud() {
-        let mut cache = Cache::<String, String>::new(5);
+        let cache = Cache::<String, String>::new(5);
 
         for i in 0..10 {
             let key = format!("key{}", i);
@@ -202,4 +840,618 @@ mod tests {
         }
         println!("at last: {:?}", cache);
     }
+
+    #[test]
+    fn test_compare_and_set() {
+        let cache = Cache::<String, String>::new(100);
+        let key = "key".to_string();
+        // None expects the key to be absent
+        assert!(cache.compare_and_set(&key, None, "v1".to_string()));
+        assert!(!cache.compare_and_set(&key, None, "v2".to_string()));
+        assert_eq!(Some("v1".to_string()), cache.get(&key));
+
+        assert!(!cache.compare_and_set(&key, Some(&"v0".to_string()), "v2".to_string()));
+        assert_eq!(Some("v1".to_string()), cache.get(&key));
+        assert!(cache.compare_and_set(&key, Some(&"v1".to_string()), "v2".to_string()));
+        assert_eq!(Some("v2".to_string()), cache.get(&key));
+
+        cache.del(key.clone());
+        assert!(!cache.compare_and_set(&key, Some(&"v2".to_string()), "v3".to_string()));
+        assert_eq!(None, cache.get(&key));
+    }
+
+    #[test]
+    fn test_set_and_return() {
+        let cache = Cache::<String, String>::new(100);
+        let key = "key".to_string();
+        assert_eq!(None, cache.set_and_return(key.clone(), "v1".to_string()));
+        assert_eq!(
+            Some("v1".to_string()),
+            cache.set_and_return(key.clone(), "v2".to_string())
+        );
+        assert_eq!(Some("v2".to_string()), cache.get(&key));
+        assert_eq!(1, cache.len());
+
+        // an expired value is not returned
+        cache.set_with_ttl("ttl".to_string(), "old".to_string(), Duration::ZERO);
+        assert_eq!(
+            None,
+            cache.set_and_return("ttl".to_string(), "new".to_string())
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        // Tally counts its clones, update must not make any.
+        #[derive(Debug, Default)]
+        struct Tally {
+            n: u64,
+            clones: Arc<AtomicUsize>,
+        }
+        impl Clone for Tally {
+            fn clone(&self) -> Self {
+                self.clones.fetch_add(1, Ordering::Relaxed);
+                Tally {
+                    n: self.n,
+                    clones: Arc::clone(&self.clones),
+                }
+            }
+        }
+
+        let cache = Cache::<String, Tally>::new(100);
+        let key = "counter".to_string();
+        assert!(!cache.update(&key, |t| t.n += 1));
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        cache.set(
+            key.clone(),
+            Tally {
+                n: 0,
+                clones: Arc::clone(&clones),
+            },
+        );
+        for _ in 0..10 {
+            assert!(cache.update(&key, |t| t.n += 1));
+        }
+        assert_eq!(0, clones.load(Ordering::Relaxed));
+        assert_eq!(10, cache.get(&key).unwrap().n);
+    }
+
+    #[test]
+    fn test_entry() {
+        let cache = Cache::<String, u64>::new(100);
+        let key = "counter".to_string();
+        for _ in 0..5 {
+            match cache.entry(key.clone()) {
+                CacheEntry::Occupied(mut e) => e.update(|v| *v += 1),
+                CacheEntry::Vacant(e) => assert!(e.insert(0)),
+            }
+        }
+        assert_eq!(Some(4), cache.get(&key));
+
+        let CacheEntry::Occupied(e) = cache.entry(key.clone()) else {
+            panic!("{} is cached", key);
+        };
+        assert_eq!(4, e.get());
+        assert_eq!(4, e.remove());
+        assert!(!cache.contains(&key));
+        assert!(matches!(cache.entry(key), CacheEntry::Vacant(_)));
+    }
+
+    #[test]
+    fn test_reads_skip_doorkeeper() {
+        let cache = Cache::<u64, u64>::new(100);
+        for i in 0..10 {
+            cache.set(i, i);
+        }
+        let (key_hash, _) = cache.key_to_hash(&3);
+        let (bloom, count) = {
+            let p = cache.m.read().unwrap();
+            (p.watch_dog.as_bytes().to_vec(), p.c.estimate(key_hash))
+        };
+        for _ in 0..5 {
+            assert_eq!(Some(3), cache.get(&3));
+        }
+        let p = cache.m.read().unwrap();
+        assert_eq!(bloom, p.watch_dog.as_bytes());
+        assert_eq!(count + 5, p.c.estimate(key_hash));
+    }
+
+    #[test]
+    fn test_debug_dump() {
+        let cache = Cache::<u64, u64>::new(100);
+        for i in 0..10 {
+            cache.set(i, i);
+        }
+        for _ in 0..5 {
+            cache.get(&3);
+        }
+        let dump = cache.debug_dump();
+        let (key_hash, _) = cache.key_to_hash(&3);
+        let (_, stage_two) = dump.split_once("stage two:\n").unwrap();
+        assert!(
+            stage_two.contains(&format!("key={} stage=2 freq=", key_hash)),
+            "{}",
+            dump
+        );
+        // the most recent set is in the window
+        let (key_hash, _) = cache.key_to_hash(&9);
+        let (window, _) = dump.split_once("stage one:").unwrap();
+        assert!(
+            window.contains(&format!("key={} stage=0", key_hash)),
+            "{}",
+            dump
+        );
+    }
+
+    #[test]
+    fn test_get_many() {
+        let cache = Cache::<u64, u64>::new(100);
+        for k in [1, 2, 3] {
+            cache.set(k, k * 10);
+        }
+        let keys = [1, 4, 2, 5, 3];
+        let estimates = |cache: &Cache<u64, u64>| -> Vec<i64> {
+            let p = cache.m.read().unwrap();
+            keys.iter()
+                .map(|k| p.c.estimate(cache.key_to_hash(k).0))
+                .collect()
+        };
+        let before = estimates(&cache);
+
+        assert_eq!(
+            vec![Some(10), None, Some(20), None, Some(30)],
+            cache.get_many(&keys)
+        );
+        let after = estimates(&cache);
+        for (i, k) in keys.iter().enumerate() {
+            let hits = if *k <= 3 { 1 } else { 0 };
+            assert_eq!(before[i] + hits, after[i], "key {}", k);
+        }
+        assert!(cache.get_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_frequency() {
+        let cache = Cache::<u64, u64>::new(100);
+        assert_eq!(0, cache.frequency(&1));
+        cache.set(1, 10);
+        let mut last = cache.frequency(&1);
+        for _ in 0..5 {
+            cache.get(&1);
+            let f = cache.frequency(&1);
+            assert!(f > last, "{} {}", f, last);
+            last = f;
+        }
+        // asking doesn't count as an access
+        assert_eq!(last, cache.frequency(&1));
+        // only hits are counted
+        cache.get(&2);
+        assert_eq!(0, cache.frequency(&2));
+    }
+
+    #[test]
+    fn test_preload_frequencies() {
+        let mut cache = Cache::<u64, u64>::new(100);
+        for i in 0..100 {
+            cache.set(i, i);
+        }
+        // every victim the SLRU can offer has been read twice
+        for _ in 0..2 {
+            for i in 0..100 {
+                cache.get(&i);
+            }
+        }
+        cache.preload_frequencies(&[(1000, 10)]);
+        assert!(cache.frequency(&1000) >= 10);
+        assert!(!cache.contains(&1000));
+        assert_eq!(300, cache.m.read().unwrap().t);
+
+        // each set pushes the one before out of the window, to compete with the SLRU
+        cache.set(1000, 1000);
+        cache.set(2000, 2000);
+        cache.set(3000, 3000);
+        assert!(cache.contains(&1000));
+        assert!(!cache.contains(&2000));
+    }
+
+    #[test]
+    fn test_no_doorkeeper() {
+        let mut cache = Cache::<u64, u64>::new(100);
+        cache.set_doorkeeper(false);
+        for i in 0..100 {
+            cache.set(i, i);
+        }
+        cache.get(&0);
+        // a key never seen before is admitted past the window on its first set, the
+        // victims have been read at most once
+        cache.set(1000, 1000);
+        cache.set(2000, 2000);
+        assert!(cache.contains(&1000));
+        assert_eq!(100, cache.len());
+
+        let p = cache.m.read().unwrap();
+        assert_eq!(bloom::new(100, 0.01).as_bytes(), p.watch_dog.as_bytes());
+    }
+
+    #[test]
+    fn test_force_age() {
+        let cache = Cache::<u64, u64>::new(100);
+        cache.set(1, 1);
+        for _ in 0..8 {
+            cache.get(&1);
+        }
+        let (key_hash, _) = cache.key_to_hash(&1);
+        let before = cache.m.read().unwrap().c.estimate(key_hash);
+        assert!(before >= 8, "{}", before);
+
+        cache.force_age();
+        let p = cache.m.read().unwrap();
+        assert!(p.c.estimate(key_hash) < before);
+        assert_eq!(0, p.t);
+        drop(p);
+        assert_eq!(Some(1), cache.get(&1));
+    }
+
+    #[test]
+    fn test_aging_on_set() {
+        let cache = Cache::<u64, u64>::new(100);
+        let threshold = cache.m.read().unwrap().threshold;
+        assert_eq!(1000, threshold);
+        for i in 0..threshold as u64 * 5 + 10 {
+            cache.set(i, i);
+        }
+        {
+            let p = cache.m.read().unwrap();
+            // only the sets since the last reset are counted
+            assert_eq!(10, p.t);
+            assert!(p.watch_dog.estimated_fpr() < 0.01);
+        }
+        // keys set after the last reset are still admitted past the window
+        let recent = (threshold as u64 * 5..threshold as u64 * 5 + 10)
+            .filter(|k| cache.contains(k))
+            .count();
+        assert!(recent > 1, "{}", recent);
+
+        // overwrites in place count as sets too
+        let key = threshold as u64 * 5;
+        assert!(cache.contains(&key));
+        assert_eq!(Some(key), cache.set_and_return(key, 1));
+        assert_eq!(11, cache.m.read().unwrap().t);
+        assert!(cache.compare_and_set(&key, Some(&1), 2));
+        assert_eq!(12, cache.m.read().unwrap().t);
+        // a compare that fails doesn't set anything
+        assert!(!cache.compare_and_set(&key, Some(&1), 3));
+        assert_eq!(12, cache.m.read().unwrap().t);
+        for i in 0..threshold as u64 - 12 {
+            if i % 2 == 0 {
+                cache.set_and_return(key, i);
+            } else {
+                cache.compare_and_set(&key, Some(&(i - 1)), i);
+            }
+        }
+        // an overwrite-only load resets the frequencies as well
+        assert_eq!(0, cache.m.read().unwrap().t);
+    }
+
+    #[test]
+    fn test_on_evict() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = Cache::<u64, u64>::new(100);
+        let e = Arc::clone(&evicted);
+        cache.on_evict(move |key, v| e.lock().unwrap().push((key, v)));
+        for i in 0..200 {
+            cache.set(i, i);
+        }
+
+        let evicted = evicted.lock().unwrap();
+        // every set past the capacity pushes an item out
+        assert!(evicted.len() >= 100, "{}", evicted.len());
+        for (key, v) in evicted.iter() {
+            assert!(!cache.contains(v));
+            assert_eq!(cache.key_to_hash(v).0, *key);
+        }
+        assert_eq!(200, evicted.len() + cache.len());
+    }
+
+    #[test]
+    fn test_on_evict_uses_cache() {
+        let slot: Arc<OnceLock<Weak<Cache<u64, u64>>>> = Arc::new(OnceLock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut cache = Cache::<u64, u64>::new(100);
+        let (s, c) = (Arc::clone(&slot), Arc::clone(&calls));
+        cache.on_evict(move |_, v| {
+            let cache = s.get().unwrap().upgrade().unwrap();
+            // the evicted item is gone by the time the listener runs
+            assert!(!cache.contains(&v));
+            assert_eq!(None, cache.get(&v));
+            // a set from the listener evicts again, its items come to the listener too
+            if v % 10 == 0 {
+                cache.set(v + 1000, v);
+            }
+            c.fetch_add(1, Ordering::Relaxed);
+        });
+        let cache = Arc::new(cache);
+        slot.set(Arc::downgrade(&cache)).unwrap();
+
+        for i in 0..200 {
+            cache.set(i, i);
+        }
+        cache.set_and_return(200, 200);
+        cache.compare_and_set(&201, None, 201);
+        cache.get_or_insert_with(202, || 202);
+        if let CacheEntry::Vacant(e) = cache.entry(203) {
+            e.insert(203);
+        }
+        assert!(calls.load(Ordering::Relaxed) >= 100);
+    }
+
+    #[test]
+    fn test_del_unlinks() {
+        let cache = Cache::<u64, u64>::new(100);
+        for i in 0..50 {
+            cache.set(i, i);
+        }
+        // 49 is in the window, 0 in the slru
+        for key in [49, 0] {
+            assert!(cache.del(key).is_some());
+            assert!(!cache.contains(&key));
+            let (key_hash, _) = cache.key_to_hash(&key);
+            let p = cache.m.read().unwrap();
+            assert!(!p.data.lock().unwrap().contains_key(&key_hash));
+            assert!(p
+                .lru
+                .items()
+                .chain(p.slru.items())
+                .all(|i| i.lock().unwrap().key != key_hash));
+        }
+        assert_eq!(48, cache.len());
+        assert!(cache.del(0).is_none());
+    }
+
+    #[test]
+    fn test_set_existing_key() {
+        let cache = Cache::<u64, u64>::new(10);
+        for round in 0..50 {
+            for i in 0..5 {
+                cache.set(i, i * 100 + round);
+            }
+        }
+        for i in 0..5 {
+            assert_eq!(Some(i * 100 + 49), cache.get(&i), "key {}", i);
+        }
+        assert_eq!(5, cache.len());
+        // one linked item per key
+        let p = cache.m.read().unwrap();
+        assert_eq!(5, p.lru.items().chain(p.slru.items()).count());
+    }
+
+    #[test]
+    fn test_contains() {
+        let cache = Cache::<String, String>::new(5);
+        for i in 0..10 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+        }
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            let contains = cache.contains(&key);
+            assert_eq!(cache.get(&key).is_some(), contains);
+        }
+        assert!(!cache.contains(&"absent".to_string()));
+
+        // an expired item is not contained any more
+        cache.set_with_ttl("ttl".to_string(), "v".to_string(), Duration::ZERO);
+        assert!(!cache.contains(&"ttl".to_string()));
+        assert!(cache.get(&"ttl".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let cache = Cache::<String, String>::new(100);
+        for i in 0..50 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+        }
+        // make key0..key9 hot, they move on to stage two
+        for _ in 0..5 {
+            for i in 0..10 {
+                cache.get(&format!("key{}", i));
+            }
+        }
+        let mut buf = Vec::new();
+        cache.save(&mut buf).unwrap();
+
+        let loaded = Cache::<String, String>::load(buf.as_slice(), 100).unwrap();
+        assert_eq!(cache.len(), loaded.len());
+        {
+            let (p, q) = (cache.m.read().unwrap(), loaded.m.read().unwrap());
+            for i in 0..50 {
+                let (h, _) = cache.key_to_hash(&format!("key{}", i));
+                assert_eq!(p.c.estimate(h), q.c.estimate(h));
+                let stage = |p: &Policy<String>| p.data.lock().unwrap()[&h].lock().unwrap().stage;
+                assert_eq!(stage(&p), stage(&q));
+            }
+            assert_eq!(p.watch_dog.as_bytes(), q.watch_dog.as_bytes());
+        }
+        for i in 0..50 {
+            let key = format!("key{}", i);
+            assert_eq!(cache.get(&key), loaded.get(&key));
+        }
+
+        assert!(Cache::<String, String>::load(&b"{}"[..], 100).is_err());
+    }
+
+    #[test]
+    fn test_cache_like() {
+        fn run(c: &impl CacheLike<String, String>) -> usize {
+            for i in 0..10 {
+                c.set(format!("key{}", i), format!("val{}", i));
+            }
+            let mut hits = 0;
+            for i in 0..10 {
+                if let Some(v) = c.get(&format!("key{}", i)) {
+                    assert_eq!(format!("val{}", i), v);
+                    hits += 1;
+                }
+            }
+            c.del("key0".to_string());
+            assert_eq!(None, c.get(&"key0".to_string()));
+            hits
+        }
+        assert_eq!(10, run(&Cache::<String, String>::new(100)));
+        assert_eq!(0, run(&NoopCache));
+    }
+
+    #[test]
+    fn test_get_with_ttl() {
+        let cache = Cache::<String, String>::new(100);
+        cache.set_with_ttl("a".to_string(), "1".to_string(), Duration::from_secs(60));
+        cache.set("b".to_string(), "2".to_string());
+
+        let (v, ttl) = cache.get_with_ttl(&"a".to_string()).unwrap();
+        assert_eq!("1", v);
+        let ttl = ttl.unwrap();
+        assert!(ttl <= Duration::from_secs(60) && ttl >= Duration::from_secs(58));
+        assert_eq!(
+            Some(("2".to_string(), None)),
+            cache.get_with_ttl(&"b".to_string())
+        );
+        assert_eq!(None, cache.get_with_ttl(&"c".to_string()));
+
+        // a zero ttl expires right away
+        cache.set_with_ttl("d".to_string(), "4".to_string(), Duration::ZERO);
+        assert_eq!(None, cache.get(&"d".to_string()));
+    }
+
+    #[test]
+    fn test_expire_with_mock_clock() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let cache = Cache::<String, String>::with_clock(100, clock.clone());
+        cache.set_with_ttl("a".to_string(), "1".to_string(), Duration::from_secs(60));
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(
+            Some(("1".to_string(), Some(Duration::from_secs(1)))),
+            cache.get_with_ttl(&"a".to_string())
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(None, cache.get(&"a".to_string()));
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let cache = Cache::<String, String>::new(100);
+        let mut calls = 0;
+        let v = cache.get_or_insert_with("key".to_string(), || {
+            calls += 1;
+            "val".to_string()
+        });
+        assert_eq!("val", v);
+        assert_eq!(1, calls);
+
+        let v = cache.get_or_insert_with("key".to_string(), || {
+            calls += 1;
+            "other".to_string()
+        });
+        assert_eq!("val", v);
+        assert_eq!(1, calls);
+        assert_eq!(Some("val".to_string()), cache.get(&"key".to_string()));
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = Cache::<String, String>::new(100);
+        for i in 0..50 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+        }
+        for _ in 0..3 {
+            assert_eq!(Some("val49".to_string()), cache.get(&"key49".to_string()));
+        }
+        let (h, _) = cache.key_to_hash(&"key49".to_string());
+        assert!(cache.m.read().unwrap().c.estimate(h) > 0);
+
+        cache.clear();
+        {
+            let p = cache.m.read().unwrap();
+            assert!(p.data.lock().unwrap().is_empty());
+            assert_eq!(0, p.c.estimate(h));
+            assert_eq!(0, p.t);
+        }
+        for i in 0..50 {
+            assert_eq!(None, cache.get(&format!("key{}", i)));
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let cache = Cache::<String, String>::new(5);
+        assert!(cache.is_empty());
+        assert_eq!(5, cache.capacity());
+        for i in 0..20 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+            assert_eq!((i + 1).min(5), cache.len());
+        }
+        cache.del("key19".to_string());
+        assert_eq!(4, cache.len());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_iter() {
+        let cache = Cache::<String, String>::new(100);
+        for i in 0..10 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+        }
+        let mut values: Vec<_> = cache.iter().map(|(_, v)| v).collect();
+        values.sort();
+        let mut expect: Vec<_> = (0..10).map(|i| format!("val{}", i)).collect();
+        expect.sort();
+        assert_eq!(expect, values);
+
+        let mut keys: Vec<_> = cache.keys().collect();
+        keys.sort();
+        let mut expect: Vec<_> = (0..10)
+            .map(|i| cache.key_to_hash(&format!("key{}", i)).0)
+            .collect();
+        expect.sort();
+        assert_eq!(expect, keys);
+    }
+
+    #[test]
+    fn test_concurrent_get_set() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Cache<String, String>>();
+
+        let cache = Cache::<String, String>::new(1000);
+        std::thread::scope(|s| {
+            for t in 0..2 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for i in 0..500 {
+                        let key = format!("key{}-{}", t, i);
+                        cache.set(key.clone(), format!("val{}", i));
+                        if let Some(v) = cache.get(&key) {
+                            assert_eq!(format!("val{}", i), v);
+                        }
+                    }
+                });
+            }
+        });
+        assert!(cache.len() <= 1000);
+    }
+
+    #[test]
+    fn test_set_with_cost() {
+        let cache = Cache::<String, String>::new(100);
+        for i in 0..100 {
+            cache.set(format!("key{}", i), format!("val{}", i));
+        }
+        assert_eq!(100, cache.len());
+
+        // the big item is admitted into the SLRU once it leaves the window
+        cache.set_with_cost("big".to_string(), "big".to_string(), 30);
+        cache.set("small".to_string(), "small".to_string());
+        assert_eq!(Some("big".to_string()), cache.get(&"big".to_string()));
+        assert!(cache.len() <= 100 - 30 + 2);
+    }
 }
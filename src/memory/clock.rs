@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Clock is the time source of everything that expires, times are unix seconds, the unit
+// of expires_at.
+pub trait Clock: Debug + Send + Sync {
+    fn now_unix(&self) -> u64;
+}
+
+// SystemClock reads the system time.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_unix(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+}
+
+// MockClock only moves when told to, tests use it to expire things deterministically.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> MockClock {
+        MockClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.now.fetch_add(d.as_secs(), Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_unix(&self) -> u64 {
+        self.now.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::clock::{Clock, MockClock, SystemClock};
+    use std::time::Duration;
+
+    #[test]
+    fn test_clock() {
+        let c = MockClock::new(1_000);
+        assert_eq!(1_000, c.now_unix());
+        c.advance(Duration::from_secs(60));
+        assert_eq!(1_060, c.now_unix());
+        // sub-second durations don't move a clock counting seconds
+        c.advance(Duration::from_millis(999));
+        assert_eq!(1_060, c.now_unix());
+
+        assert!(SystemClock.now_unix() > 1_600_000_000);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::error::StepDbError;
 use rand::RngCore;
 use std::array::from_fn;
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::fmt;
 
 const CM_DEPTH: usize = 4;
 
@@ -13,6 +15,15 @@ pub struct CMSketch {
 }
 
 pub fn new(num_counters: u64) -> CMSketch {
+    new_with_width(num_counters, 4)
+}
+
+// new_with_width creates a sketch whose counters are width bits wide, 4 or 8. A 4-bit
+// counter stops at 15 and two of them share a byte, an 8-bit counter counts up to 255 but
+// takes a byte of its own, so the sketch needs twice the memory for the same
+// num_counters.
+pub fn new_with_width(num_counters: u64, width: u8) -> CMSketch {
+    assert!(width == 4 || width == 8, "counter width must be 4 or 8");
     if num_counters == 0 {
         panic!("invalid num_counters");
     }
@@ -28,19 +39,117 @@ pub fn new(num_counters: u64) -> CMSketch {
     // 0000,0000|0000,0000|0000,0000
     // 0000,0000|0000,0000|0000,0000
     CMSketch {
-        rows: from_fn(|_| new_row(num_counters)),
+        rows: from_fn(|_| new_row_with_width(num_counters, width)),
         seed: from_fn(|_| rng.next_u64()),
         mask,
     }
 }
 
+// MergeMode tells how CMSketch::merge combines two counters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeMode {
+    // Max keeps the bigger counter, for sketches that may have counted the same accesses.
+    Max,
+    // Sum adds the counters, saturating at the counter maximum, for sketches over
+    // disjoint accesses.
+    Sum,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MergeError {
+    MaskMismatch,
+    SeedMismatch,
+    DifferentWidth,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::MaskMismatch => write!(f, "sketches have different sizes"),
+            MergeError::SeedMismatch => write!(f, "sketches have different seeds"),
+            MergeError::DifferentWidth => write!(f, "sketches have different counter widths"),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
 impl CMSketch {
+    // new_like returns an empty sketch with the size and seeds of self,
+    // so the two can be merged later.
+    pub fn new_like(&self) -> CMSketch {
+        CMSketch {
+            rows: from_fn(|i| new_row_with_width(self.mask + 1, self.rows[i].width)),
+            seed: self.seed,
+            mask: self.mask,
+        }
+    }
+
+    // merge folds the counters of other into self, both must have the same size and seeds.
+    pub fn merge(&mut self, other: &CMSketch, mode: MergeMode) -> Result<(), MergeError> {
+        if self.mask != other.mask {
+            return Err(MergeError::MaskMismatch);
+        }
+        if self.seed != other.seed {
+            return Err(MergeError::SeedMismatch);
+        }
+        if self.rows[0].width != other.rows[0].width {
+            return Err(MergeError::DifferentWidth);
+        }
+        for (row, other) in self.rows.iter_mut().zip(other.rows.iter()) {
+            row.merge(other, mode);
+        }
+        Ok(())
+    }
+
+    // resize changes the number of counters to new_num_counters, rounded up to a power of
+    // 2, and keeps the seeds. The sketch doesn't know the hashes behind its counters, so
+    // the counts can't be moved exactly. Growing copies each counter to every new counter
+    // its hashes can land on, an estimate stays the same, its collisions included, until
+    // aging wears them off. Shrinking adds up the counters folded onto one, saturating,
+    // so an estimate can only go up.
+    pub fn resize(&mut self, new_num_counters: u64) {
+        if new_num_counters == 0 {
+            panic!("invalid num_counters");
+        }
+        let num_counters = next_power_of_two(new_num_counters);
+        let mask = num_counters - 1;
+        for row in self.rows.iter_mut() {
+            let mut resized = new_row_with_width(num_counters, row.width);
+            if mask >= self.mask {
+                for n in 0..num_counters {
+                    resized.set(n, row.get(n & self.mask));
+                }
+            } else {
+                for n in 0..=self.mask {
+                    let folded = resized.get(n & mask).saturating_add(row.get(n));
+                    resized.set(n & mask, folded);
+                }
+            }
+            *row = resized;
+        }
+        self.mask = mask;
+    }
+
     pub fn increment(&mut self, hashed: u64) {
         for (i, row) in self.rows.iter_mut().enumerate() {
             row.increment((hashed ^ self.seed[i]) & self.mask);
         }
     }
 
+    // increment_n adds count to the counters of hashed, same as count calls to increment.
+    pub fn increment_n(&mut self, hashed: u64, count: u32) {
+        for (i, row) in self.rows.iter_mut().enumerate() {
+            row.increment_n((hashed ^ self.seed[i]) & self.mask, count);
+        }
+    }
+
+    pub fn increment_many(&mut self, hashes: &[u64]) {
+        for &hashed in hashes {
+            self.increment(hashed);
+        }
+    }
+
     pub fn estimate(&self, hashed: u64) -> i64 {
         let mut m = 255;
         for (i, row) in self.rows.iter().enumerate() {
@@ -49,43 +158,199 @@ impl CMSketch {
         m as i64
     }
 
+    // is_saturated reports whether the estimate of hashed is pinned at the counter maximum,
+    // 15 or 255 depending on the width, further accesses of it are no longer counted.
+    pub fn is_saturated(&self, hashed: u64) -> bool {
+        self.estimate(hashed) == self.rows[0].max() as i64
+    }
+
+    // reset halves every counter, the aging of TinyLFU. It is decay(0.5), done with shifts.
     pub fn reset(&mut self) {
-        let _ = self.rows.iter_mut().map(|x| x.reset());
+        for row in self.rows.iter_mut() {
+            row.reset();
+        }
+    }
+
+    // decay multiplies every counter by factor, rounding down. A factor below 0.5 forgets
+    // old accesses faster than reset, one above it keeps them longer.
+    pub fn decay(&mut self, factor: f64) {
+        assert!((0.0..=1.0).contains(&factor), "factor must be in [0, 1]");
+        for row in self.rows.iter_mut() {
+            row.decay(factor);
+        }
     }
 
     pub fn clear(&mut self) {
-        let _ = self.rows.iter_mut().map(|x| x.clear());
+        for row in self.rows.iter_mut() {
+            row.clear();
+        }
+    }
+
+    // to_bytes encodes the sketch as | mask | seed 0..3 | row 0..3 |, integers are
+    // little-endian u64. The seeds are kept so the estimates are the same after from_bytes.
+    // The counter width follows from the length of the rows.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let row_len = self.rows[0].data.len();
+        let mut buf = Vec::with_capacity(8 * (1 + CM_DEPTH) + row_len * CM_DEPTH);
+        buf.extend_from_slice(&self.mask.to_le_bytes());
+        for seed in self.seed.iter() {
+            buf.extend_from_slice(&seed.to_le_bytes());
+        }
+        for row in self.rows.iter() {
+            buf.extend_from_slice(&row.data);
+        }
+        buf
+    }
+
+    // from_bytes decodes a sketch written by to_bytes, StepDbError::Decode if buf is
+    // malformed.
+    pub fn from_bytes(buf: &[u8]) -> Result<CMSketch, StepDbError> {
+        let malformed = |why: &str| StepDbError::Decode(format!("frequency sketch, {}", why));
+        let header = 8 * (1 + CM_DEPTH);
+        if buf.len() < header {
+            return Err(malformed("shorter than its header"));
+        }
+        let get_u64 = |i: usize| u64::from_le_bytes(buf[i * 8..i * 8 + 8].try_into().unwrap());
+        let mask = get_u64(0);
+        let num_counters = mask.checked_add(1).ok_or_else(|| malformed("bad mask"))?;
+        if !num_counters.is_power_of_two() {
+            return Err(malformed("bad mask"));
+        }
+        let rows_len = Some((buf.len() - header) as u64);
+        let width = if rows_len == (num_counters / 2).checked_mul(CM_DEPTH as u64) {
+            4
+        } else if rows_len == num_counters.checked_mul(CM_DEPTH as u64) {
+            8
+        } else {
+            return Err(malformed("rows of the wrong length"));
+        };
+        // a single 4-bit counter would take half a byte per row
+        if width == 4 && mask == 0 {
+            return Err(malformed("bad mask"));
+        }
+        let row_len = (buf.len() - header) / CM_DEPTH;
+        let rows = &buf[header..];
+        Ok(CMSketch {
+            rows: from_fn(|i| CmRow {
+                data: rows[i * row_len..(i + 1) * row_len].to_vec(),
+                width,
+            }),
+            seed: from_fn(|i| get_u64(i + 1)),
+            mask,
+        })
     }
 }
 
 #[derive(Debug)]
 pub struct CmRow {
     data: Vec<u8>,
+    // bits per counter, 4 packs two counters in a byte, 8 gives each counter a byte
+    width: u8,
 }
 
 pub fn new_row(num_counters: u64) -> CmRow {
+    new_row_with_width(num_counters, 4)
+}
+
+pub fn new_row_with_width(num_counters: u64, width: u8) -> CmRow {
     CmRow {
-        data: vec![0; (num_counters / 2) as usize],
+        data: vec![0; (num_counters * width as u64 / 8) as usize],
+        width,
     }
 }
 
 impl CmRow {
+    fn max(&self) -> u8 {
+        if self.width == 8 {
+            u8::MAX
+        } else {
+            15
+        }
+    }
+
     pub fn get(&self, n: u64) -> u8 {
+        if self.width == 8 {
+            return self.data[n as usize];
+        }
         (self.data[n as usize / 2].wrapping_shr(((n & 1) * 4) as u32)) & 0x0f
     }
 
     pub fn increment(&mut self, n: u64) {
+        if self.width == 8 {
+            self.data[n as usize] = self.data[n as usize].saturating_add(1);
+            return;
+        }
+        self.set_nibble(n, self.get(n).saturating_add(1));
+    }
+
+    // increment_n adds count to the counter n, saturating at the counter maximum.
+    pub fn increment_n(&mut self, n: u64, count: u32) {
+        if self.width == 8 {
+            let v = &mut self.data[n as usize];
+            *v = min(*v as u32 + count, u8::MAX as u32) as u8;
+            return;
+        }
+        let v = min(self.get(n) as u32 + count, 15);
+        self.set_nibble(n, v as u8);
+    }
+
+    // set stores v in counter n, clamped to the counter maximum.
+    fn set(&mut self, n: u64, v: u8) {
+        if self.width == 8 {
+            self.data[n as usize] = v;
+            return;
+        }
+        self.set_nibble(n, v);
+    }
+
+    // set_nibble sets the 4-bit counter n to v clamped to 15, the other counter sharing its
+    // byte is masked out of the write.
+    fn set_nibble(&mut self, n: u64, v: u8) {
         let i = n as usize / 2;
-        let s = (n & 1) * 4;
-        let v = (self.data[i].wrapping_shr(s as u32)) & 0x0f;
-        if v < 15 {
-            self.data[i] += 1u64.wrapping_shl(s as u32) as u8;
+        let s = ((n & 1) * 4) as u32;
+        let mask = 0x0f << s;
+        self.data[i] = (self.data[i] & !mask) | (min(v, 15) << s);
+    }
+
+    fn merge(&mut self, other: &CmRow, mode: MergeMode) {
+        if self.width == 8 {
+            for (a, &b) in self.data.iter_mut().zip(other.data.iter()) {
+                *a = match mode {
+                    MergeMode::Max => max(*a, b),
+                    MergeMode::Sum => a.saturating_add(b),
+                };
+            }
+            return;
+        }
+        for (a, &b) in self.data.iter_mut().zip(other.data.iter()) {
+            let (lo, hi) = match mode {
+                MergeMode::Max => (max(*a & 0x0f, b & 0x0f), max(*a >> 4, b >> 4)),
+                MergeMode::Sum => (
+                    min((*a & 0x0f) + (b & 0x0f), 15),
+                    min((*a >> 4) + (b >> 4), 15),
+                ),
+            };
+            *a = hi << 4 | lo;
         }
     }
 
     pub fn reset(&mut self) {
+        // Shifting a byte of two 4-bit counters moves a bit of the high one into the low
+        // one, the mask drops it.
+        let mask = if self.width == 8 { 0xff } else { 0x77 };
         for byte in &mut self.data.iter_mut() {
-            *byte = (*byte >> 1) & 0x77;
+            *byte = (*byte >> 1) & mask;
+        }
+    }
+
+    fn decay(&mut self, factor: f64) {
+        let scale = |v: u8| (v as f64 * factor) as u8;
+        for byte in self.data.iter_mut() {
+            *byte = if self.width == 8 {
+                scale(*byte)
+            } else {
+                scale(*byte >> 4) << 4 | scale(*byte & 0x0f)
+            };
         }
     }
 
@@ -108,6 +373,7 @@ fn next_power_of_two(x: u64) -> u64 {
     let mut x = x - 1;
     x |= x.wrapping_shr(1);
     x |= x.wrapping_shr(2);
+    x |= x.wrapping_shr(4);
     x |= x.wrapping_shr(8);
     x |= x.wrapping_shr(16);
     x |= x.wrapping_shr(32);
@@ -116,7 +382,9 @@ fn next_power_of_two(x: u64) -> u64 {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::StepDbError;
     use crate::memory::counter;
+    use crate::memory::counter::{CMSketch, MergeError, MergeMode};
 
     #[test]
     fn test_counter() {
@@ -126,6 +394,243 @@ mod tests {
         c.increment(h);
         c.increment(h);
         let v = c.estimate(h);
-        assert_eq!(v, 3)
+        assert_eq!(v, 3);
+
+        c.reset();
+        assert_eq!(c.estimate(h), 1);
+        c.clear();
+        assert_eq!(c.estimate(h), 0);
+    }
+
+    #[test]
+    fn test_nibbles_apart() {
+        // counters 0 and 1 share a byte
+        let mut row = counter::new_row(4);
+        for _ in 0..20 {
+            row.increment(0);
+        }
+        assert_eq!((15, 0), (row.get(0), row.get(1)));
+        for i in 1..=20 {
+            row.increment(1);
+            assert_eq!((15, i.min(15)), (row.get(0), row.get(1)));
+        }
+
+        let mut row = counter::new_row(4);
+        row.increment_n(1, 100);
+        assert_eq!((0, 15), (row.get(0), row.get(1)));
+        row.increment_n(0, 3);
+        row.increment(0);
+        assert_eq!((4, 15), (row.get(0), row.get(1)));
+        assert_eq!((0, 0), (row.get(2), row.get(3)));
+    }
+
+    #[test]
+    fn test_increment_n() {
+        for n in [0, 1, 5, 14, 15, 16, 100] {
+            let mut a = counter::new(64);
+            let mut b = counter::new(64);
+            b.seed = a.seed;
+            // the neighbour sharing the byte must not be touched
+            a.increment(7);
+            b.increment(7);
+            a.increment_n(6, n);
+            for _ in 0..n {
+                b.increment(6);
+            }
+            assert_eq!(b.estimate(6), a.estimate(6));
+            assert_eq!(n.min(15) as i64, a.estimate(6));
+            assert_eq!(1, a.estimate(7));
+        }
+
+        let mut c = counter::new(64);
+        c.increment_many(&[3, 9, 3, 3]);
+        assert_eq!(3, c.estimate(3));
+        assert_eq!(1, c.estimate(9));
+    }
+
+    #[test]
+    fn test_is_saturated() {
+        let mut c = counter::new(64);
+        for i in 0..20 {
+            assert_eq!(i >= 15, c.is_saturated(7), "{}", i);
+            c.increment(7);
+        }
+        assert!(c.is_saturated(7));
+        assert!(!c.is_saturated(8));
+        // aging halves the counters, the key can be counted again
+        c.reset();
+        assert!(!c.is_saturated(7));
+    }
+
+    #[test]
+    fn test_width_8() {
+        let mut c = counter::new_with_width(64, 8);
+        for i in 1..=300 {
+            c.increment(7);
+            assert_eq!(i.min(255), c.estimate(7));
+        }
+        assert!(c.is_saturated(7));
+        c.reset();
+        assert_eq!(127, c.estimate(7));
+        c.increment_n(9, 1000);
+        assert_eq!(255, c.estimate(9));
+
+        // the width survives the codec
+        let d = CMSketch::from_bytes(&c.to_bytes()).unwrap();
+        assert_eq!(127, d.estimate(7));
+        let mut e = d.new_like();
+        e.increment_n(7, 100);
+        e.merge(&d, MergeMode::Sum).unwrap();
+        assert_eq!(227, e.estimate(7));
+        let mut narrow = counter::new(64);
+        narrow.seed = e.seed;
+        assert_eq!(
+            Err(MergeError::DifferentWidth),
+            e.merge(&narrow, MergeMode::Max)
+        );
+    }
+
+    #[test]
+    fn test_decay() {
+        for width in [4, 8] {
+            let mut c = counter::new_with_width(64, width);
+            c.increment_n(1, 12);
+            c.increment_n(2, 7);
+            c.increment_n(3, 3);
+            c.decay(0.25);
+            assert_eq!(3, c.estimate(1));
+            assert_eq!(1, c.estimate(2));
+            assert_eq!(0, c.estimate(3));
+            c.decay(1.0);
+            assert_eq!(3, c.estimate(1));
+
+            // decay(0.5) is reset
+            let mut a = counter::new_with_width(64, width);
+            for h in 0..64 {
+                a.increment_n(h, h as u32 % 16);
+            }
+            let mut b = CMSketch::from_bytes(&a.to_bytes()).unwrap();
+            a.reset();
+            b.decay(0.5);
+            assert_eq!(a.to_bytes(), b.to_bytes());
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = counter::new(64);
+        let mut b = a.new_like();
+        for _ in 0..3 {
+            a.increment(1);
+        }
+        a.increment(2);
+        for _ in 0..5 {
+            b.increment(1);
+        }
+        for _ in 0..12 {
+            b.increment(3);
+        }
+
+        let mut max = a.new_like();
+        max.merge(&a, MergeMode::Max).unwrap();
+        max.merge(&b, MergeMode::Max).unwrap();
+        assert_eq!(5, max.estimate(1));
+        assert_eq!(1, max.estimate(2));
+        assert_eq!(12, max.estimate(3));
+
+        a.merge(&b, MergeMode::Sum).unwrap();
+        assert_eq!(8, a.estimate(1));
+        assert_eq!(1, a.estimate(2));
+        assert_eq!(12, a.estimate(3));
+        a.merge(&b, MergeMode::Sum).unwrap();
+        assert_eq!(13, a.estimate(1));
+        assert_eq!(15, a.estimate(3));
+
+        assert_eq!(
+            Err(MergeError::SeedMismatch),
+            a.merge(&counter::new(64), MergeMode::Max)
+        );
+        assert_eq!(
+            Err(MergeError::MaskMismatch),
+            a.merge(&counter::new(128), MergeMode::Max)
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut c = counter::new(64);
+        let hashes: Vec<u64> = (0..32u64)
+            .map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+            .collect();
+        for (i, &h) in hashes.iter().enumerate() {
+            c.increment_n(h, i as u32 % 16);
+        }
+        let before: Vec<i64> = hashes.iter().map(|&h| c.estimate(h)).collect();
+
+        // growing keeps every estimate
+        c.resize(1000);
+        assert_eq!(1023, c.mask);
+        assert_eq!(
+            before,
+            hashes.iter().map(|&h| c.estimate(h)).collect::<Vec<_>>()
+        );
+        let fresh = 0xdead_beef_u64;
+        let base = c.estimate(fresh);
+        c.increment_n(fresh, 3);
+        assert_eq!(base + 3, c.estimate(fresh));
+
+        // shrinking only makes estimates go up
+        c.resize(16);
+        assert_eq!(15, c.mask);
+        for (&h, &b) in hashes.iter().zip(before.iter()) {
+            assert!(c.estimate(h) >= b);
+        }
+
+        let mut c = counter::new_with_width(64, 8);
+        c.increment_n(fresh, 200);
+        c.resize(4096);
+        assert_eq!(4095, c.mask);
+        assert_eq!(200, c.estimate(fresh));
+    }
+
+    #[test]
+    fn test_counter_codec() {
+        let mut c = counter::new(100);
+        for h in 0..50u64 {
+            for _ in 0..h % 7 {
+                c.increment(h * 7919);
+            }
+        }
+        let buf = c.to_bytes();
+        let d = CMSketch::from_bytes(&buf).unwrap();
+        assert_eq!(c.seed, d.seed);
+        assert_eq!(c.mask, d.mask);
+        for h in 0..100u64 {
+            assert_eq!(c.estimate(h * 7919), d.estimate(h * 7919));
+        }
+        assert_eq!(buf, d.to_bytes());
+
+        assert!(CMSketch::from_bytes(&buf[..buf.len() - 1]).is_err());
+        assert!(matches!(
+            CMSketch::from_bytes(&[]),
+            Err(StepDbError::Decode(_))
+        ));
+
+        // crafted headers: the row length of a huge mask overflows, a mask of 0 leaves
+        // empty 4-bit rows
+        for mask in [u64::MAX >> 1, 0] {
+            let mut crafted = buf[..8 * 5].to_vec();
+            crafted[..8].copy_from_slice(&mask.to_le_bytes());
+            assert!(
+                matches!(CMSketch::from_bytes(&crafted), Err(StepDbError::Decode(_))),
+                "{}",
+                mask
+            );
+        }
+        // one 8-bit counter per row is a valid sketch
+        let mut one = buf[..8 * 5].to_vec();
+        one[..8].copy_from_slice(&0u64.to_le_bytes());
+        one.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(1, CMSketch::from_bytes(&one).unwrap().estimate(7));
     }
 }
@@ -1,5 +1,25 @@
+use crate::error::StepDbError;
+use crate::memory::clock::{Clock, SystemClock};
+use std::time::Duration;
+
 const MAX_VAR_INT_LEN64: usize = 10;
 
+// ttl_at returns the lifetime left at now of something expiring at expires_at,
+// None if expires_at is 0, which means it never expires.
+pub(crate) fn ttl_at(expires_at: u64, now: u64) -> Option<Duration> {
+    if expires_at == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(expires_at.saturating_sub(now)))
+}
+
+// BIT_DELETE in meta marks a tombstone, the key was deleted at that version.
+pub const BIT_DELETE: u8 = 1 << 0;
+
+// BIT_VALUE_POINTER in meta marks a value holding an encoded ValuePointer, the real bytes
+// live in the value log.
+pub const BIT_VALUE_POINTER: u8 = 1 << 1;
+
 #[derive(Debug, Default)]
 pub struct Value {
     pub meta: u8,
@@ -9,6 +29,49 @@ pub struct Value {
 }
 
 impl Value {
+    pub fn builder() -> ValueBuilder {
+        ValueBuilder::default()
+    }
+
+    // with_ttl returns a value that expires ttl from now, expires_at is in unix seconds.
+    pub fn with_ttl(value: &[u8], ttl: Duration) -> Value {
+        Value::with_ttl_at(value, ttl, SystemClock.now_unix())
+    }
+
+    // with_ttl_at is with_ttl for a value set at now, now comes from a Clock.
+    pub(crate) fn with_ttl_at(value: &[u8], ttl: Duration, now: u64) -> Value {
+        Value::builder()
+            .value(value)
+            .expires_at(now + ttl.as_secs())
+            .build()
+    }
+
+    // ttl_at returns how long the value lives on after now, None if it never expires.
+    pub fn ttl_at(&self, now: u64) -> Option<Duration> {
+        ttl_at(self.expires_at, now)
+    }
+
+    // to_entry returns the entry of key with this value, the inverse of Value::from(&Entry).
+    pub fn to_entry(&self, key: Vec<u8>) -> Entry {
+        Value {
+            v: self.v.clone(),
+            ..*self
+        }
+        .into_entry(key)
+    }
+
+    // into_entry is to_entry moving the bytes of the value into the entry.
+    pub fn into_entry(self, key: Vec<u8>) -> Entry {
+        Entry {
+            key,
+            value: self.v,
+            expires_at: self.expires_at,
+            meta: self.meta,
+            version: self.version,
+            ..Default::default()
+        }
+    }
+
     pub fn encoded_size(&self) -> usize {
         let sz = self.v.len() + 1; // meta
         let enc = size_varint(self.expires_at);
@@ -16,11 +79,19 @@ impl Value {
         sz + enc
     }
 
-    pub fn decode_value(&mut self, buf: &[u8]) {
-        self.meta = buf[0];
-        let (expires_at, sz) = decode_uvarint(&buf[1..]);
+    // decode_value reads a value written by encode_value, a buf that is cut short or whose
+    // expires_at doesn't fit a u64 is a Decode error and leaves the value as it was.
+    pub fn decode_value(&mut self, buf: &[u8]) -> Result<(), StepDbError> {
+        let (meta, expires_at, v) = split_value(buf)?;
+        self.meta = meta;
         self.expires_at = expires_at;
-        self.v = buf[1 + sz as usize..].to_vec();
+        self.v = v.to_vec();
+        Ok(())
+    }
+
+    // value_bytes is the bytes of the value encoded in buf, decode_value without the copy.
+    pub(crate) fn value_bytes(buf: &[u8]) -> Result<&[u8], StepDbError> {
+        Ok(split_value(buf)?.2)
     }
 
     pub fn encode_value(&self, b: &mut [u8]) -> u32 {
@@ -33,6 +104,89 @@ impl Value {
     }
 }
 
+#[derive(Debug, Default)]
+pub struct ValueBuilder {
+    value: Value,
+}
+
+impl From<&Entry> for Value {
+    fn from(e: &Entry) -> Value {
+        Value::builder()
+            .meta(e.meta)
+            .value(&e.value)
+            .expires_at(e.expires_at)
+            .version(e.version)
+            .build()
+    }
+}
+
+impl ValueBuilder {
+    pub fn meta(mut self, meta: u8) -> Self {
+        self.value.meta = meta;
+        self
+    }
+
+    pub fn value(mut self, v: &[u8]) -> Self {
+        self.value.v = Vec::from(v);
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: u64) -> Self {
+        self.value.expires_at = expires_at;
+        self
+    }
+
+    pub fn version(mut self, version: u64) -> Self {
+        self.value.version = version;
+        self
+    }
+
+    pub fn build(self) -> Value {
+        self.value
+    }
+}
+
+// ValuePointer locates a value in the value log.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ValuePointer {
+    pub file_id: u32,
+    pub offset: u32,
+    pub len: u32,
+}
+
+const VALUE_POINTER_SIZE: usize = 12;
+
+impl ValuePointer {
+    // encode returns | file_id | offset | len |, all little-endian u32.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(VALUE_POINTER_SIZE);
+        buf.extend_from_slice(&self.file_id.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> ValuePointer {
+        assert_eq!(VALUE_POINTER_SIZE, buf.len(), "bad value pointer");
+        let get = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        ValuePointer {
+            file_id: get(0),
+            offset: get(4),
+            len: get(8),
+        }
+    }
+}
+
+// ValueLog is the hook to keep big values out of the skiplist. write stores a value and
+// returns where it went, resolve_value reads it back.
+pub struct ValueLog {
+    pub write: WriteValue,
+    pub resolve_value: ResolveValue,
+}
+
+pub type WriteValue = Box<dyn Fn(&[u8]) -> ValuePointer>;
+pub type ResolveValue = Box<dyn Fn(&ValuePointer) -> Vec<u8>>;
+
 fn size_varint(x: u64) -> usize {
     let mut n = 0;
     let mut y = x;
@@ -46,6 +200,20 @@ fn size_varint(x: u64) -> usize {
     n
 }
 
+// split_value splits a value encoded by encode_value into meta, expires_at and the value.
+fn split_value(buf: &[u8]) -> Result<(u8, u64, &[u8]), StepDbError> {
+    let malformed = |why: &str| StepDbError::Decode(format!("value, {}", why));
+    let (&meta, rest) = buf.split_first().ok_or_else(|| malformed("no meta byte"))?;
+    let (expires_at, sz) = decode_uvarint(rest);
+    if sz < 0 {
+        return Err(malformed("expires_at overflows a u64"));
+    }
+    if sz == 0 {
+        return Err(malformed("expires_at is cut short"));
+    }
+    Ok((meta, expires_at, &rest[sz as usize..]))
+}
+
 fn decode_uvarint(buf: &[u8]) -> (u64, isize) {
     let mut x: u64 = 0;
     let mut s: u32 = 0;
@@ -79,7 +247,9 @@ fn encode_uvarint(buf: &mut [u8], x: u64) -> isize {
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::entry::Value;
+    use crate::error::StepDbError;
+    use crate::memory::entry::{new_entry, Entry, Value, ValuePointer};
+    use std::time::Duration;
 
     #[test]
     fn test_uvarint() {}
@@ -100,9 +270,174 @@ mod tests {
             expires_at: 123456,
             version: 1,
         };
-        vv.decode_value(&data[0..end]);
+        vv.decode_value(&data[0..end]).unwrap();
         assert_eq!(v.v, vv.v);
     }
+
+    #[test]
+    fn test_decode_malformed_value() {
+        let mut data = vec![0; 100];
+        let v = Value {
+            meta: 2,
+            v: b"value".to_vec(),
+            expires_at: u64::MAX,
+            version: 0,
+        };
+        let end = v.encode_value(&mut data) as usize;
+        let cases: [&[u8]; 5] = [
+            &[],
+            &[2],
+            // a varint still going at its last byte
+            &data[..5],
+            // eleven continuation bytes
+            &[
+                2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1,
+            ],
+            // ten bytes holding more than 64 bits
+            &[
+                2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+            ],
+        ];
+        for buf in cases {
+            let mut got = Value::default();
+            assert!(
+                matches!(got.decode_value(buf), Err(StepDbError::Decode(_))),
+                "{:?}",
+                buf
+            );
+            assert!(got.v.is_empty() && got.expires_at == 0);
+            assert!(Value::value_bytes(buf).is_err());
+        }
+
+        let mut got = Value::default();
+        got.decode_value(&data[..end]).unwrap();
+        assert_eq!((2, u64::MAX), (got.meta, got.expires_at));
+        assert_eq!(b"value", Value::value_bytes(&data[..end]).unwrap());
+    }
+
+    #[test]
+    fn test_entry_codec() {
+        let cases = [
+            (vec![], vec![], 0, 0, 0),
+            (b"k".to_vec(), vec![], 1, 2, 3),
+            (
+                b"key".to_vec(),
+                b"value".to_vec(),
+                u64::MAX,
+                u8::MAX,
+                u64::MAX,
+            ),
+            (vec![7; 300], vec![9; 70000], 1 << 40, 1, 1 << 20),
+        ];
+        let mut buf = Vec::new();
+        for (key, value, expires_at, meta, version) in cases.iter() {
+            let e = Entry {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: *expires_at,
+                meta: *meta,
+                version: *version,
+                ..Default::default()
+            };
+            e.encode(&mut buf);
+        }
+
+        let mut pos = 0;
+        for (key, value, expires_at, meta, version) in cases.iter() {
+            let (e, n) = Entry::decode(&buf[pos..]).unwrap();
+            pos += n;
+            assert_eq!(key, &e.key);
+            assert_eq!(value, &e.value);
+            assert_eq!(*expires_at, e.expires_at);
+            assert_eq!(*meta, e.meta);
+            assert_eq!(*version, e.version);
+        }
+        assert_eq!(buf.len(), pos);
+
+        // every cut of an entry and a length past the end are errors, not panics
+        let mut buf = Vec::new();
+        new_entry(b"key", b"value").encode(&mut buf);
+        for end in 0..buf.len() {
+            assert!(
+                matches!(Entry::decode(&buf[..end]), Err(StepDbError::Decode(_))),
+                "{}",
+                end
+            );
+        }
+        let mut huge = vec![0xff; 9];
+        huge.push(0x01);
+        assert!(Entry::decode(&huge).is_err());
+        assert!(Entry::decode(&[0xff; 11]).is_err());
+    }
+
+    #[test]
+    fn test_value_pointer_codec() {
+        let vp = ValuePointer {
+            file_id: 1,
+            offset: 4096,
+            len: 1 << 20,
+        };
+        assert_eq!(vp, ValuePointer::decode(&vp.encode()));
+    }
+
+    #[test]
+    fn test_value_builder() {
+        let v = Value::builder()
+            .meta(2)
+            .value(b"no step,no miles")
+            .expires_at(123456)
+            .version(7)
+            .build();
+        assert_eq!(2, v.meta);
+        assert_eq!(b"no step,no miles".to_vec(), v.v);
+        assert_eq!(123456, v.expires_at);
+        assert_eq!(7, v.version);
+
+        let v = Value::builder().value(b"1").build();
+        assert_eq!(0, v.meta);
+        assert_eq!(0, v.expires_at);
+        assert_eq!(0, v.version);
+    }
+
+    #[test]
+    fn test_entry_value_round_trip() {
+        let mut e = new_entry(b"key", b"value");
+        e.meta = 0x4;
+        e.expires_at = 123456;
+        e.version = 7;
+        let v = Value::from(&e);
+        assert_eq!(
+            (0x4, b"value".to_vec(), 123456, 7),
+            (v.meta, v.v.clone(), v.expires_at, v.version)
+        );
+
+        for back in [v.to_entry(b"key".to_vec()), v.into_entry(b"key".to_vec())] {
+            assert_eq!(e.key, back.key);
+            assert_eq!(e.value, back.value);
+            assert_eq!(e.meta, back.meta);
+            assert_eq!(e.expires_at, back.expires_at);
+            assert_eq!(e.version, back.version);
+        }
+    }
+
+    #[test]
+    fn test_value_with_ttl() {
+        let v = Value::with_ttl_at(b"1", Duration::from_secs(60), 1_000_000);
+        assert_eq!(b"1".to_vec(), v.v);
+        assert_eq!(1_000_060, v.expires_at);
+
+        let v = Value::with_ttl(b"1", Duration::from_secs(60));
+        assert!(v.expires_at > 1_000_060);
+    }
+
+    #[test]
+    fn test_value_ttl_at() {
+        let v = Value::with_ttl_at(b"1", Duration::from_secs(60), 1_000_000);
+        assert_eq!(Some(Duration::from_secs(60)), v.ttl_at(1_000_000));
+        assert_eq!(Some(Duration::from_secs(15)), v.ttl_at(1_000_045));
+        assert_eq!(Some(Duration::ZERO), v.ttl_at(2_000_000));
+        assert_eq!(None, Value::builder().value(b"1").build().ttl_at(1_000_000));
+    }
 }
 
 #[derive(Default)]
@@ -125,4 +460,72 @@ pub fn new_entry(key: &[u8], value: &[u8]) -> Entry {
     }
 }
 
-impl Entry {}
+impl Entry {
+    // encode appends the entry to buf as
+    // | key len | key | value len | value | expires_at | meta | version |
+    // lengths, expires_at and version are uvarints, meta is a single byte.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        put_uvarint(buf, self.key.len() as u64);
+        buf.extend_from_slice(&self.key);
+        put_uvarint(buf, self.value.len() as u64);
+        buf.extend_from_slice(&self.value);
+        put_uvarint(buf, self.expires_at);
+        buf.push(self.meta);
+        put_uvarint(buf, self.version);
+    }
+
+    // decode decodes an entry written by encode from the front of buf,
+    // it returns the entry and the number of bytes consumed. A buf that is cut short or
+    // holds a bad varint is a Decode error.
+    pub fn decode(buf: &[u8]) -> Result<(Entry, usize), StepDbError> {
+        let mut pos = 0;
+        let key_len = get_uvarint(buf, &mut pos)?;
+        let key = get_slice(buf, &mut pos, key_len)?.to_vec();
+        let value_len = get_uvarint(buf, &mut pos)?;
+        let value = get_slice(buf, &mut pos, value_len)?.to_vec();
+        let expires_at = get_uvarint(buf, &mut pos)?;
+        let meta = get_slice(buf, &mut pos, 1)?[0];
+        let version = get_uvarint(buf, &mut pos)?;
+        let e = Entry {
+            key,
+            value,
+            expires_at,
+            meta,
+            version,
+            ..Default::default()
+        };
+        Ok((e, pos))
+    }
+}
+
+fn put_uvarint(buf: &mut Vec<u8>, x: u64) {
+    let mut tmp = [0u8; MAX_VAR_INT_LEN64];
+    let sz = encode_uvarint(&mut tmp, x) as usize;
+    buf.extend_from_slice(&tmp[..sz]);
+}
+
+fn get_uvarint(buf: &[u8], pos: &mut usize) -> Result<u64, StepDbError> {
+    let (x, sz) = decode_uvarint(&buf[*pos..]);
+    if sz <= 0 {
+        return Err(StepDbError::Decode(format!(
+            "entry, bad uvarint at {}",
+            *pos
+        )));
+    }
+    *pos += sz as usize;
+    Ok(x)
+}
+
+// get_slice returns the len bytes at pos and moves pos past them.
+fn get_slice<'a>(buf: &'a [u8], pos: &mut usize, len: u64) -> Result<&'a [u8], StepDbError> {
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| pos.checked_add(len))
+        .filter(|&end| end <= buf.len())
+        .ok_or_else(|| {
+            StepDbError::Decode(format!("entry, {} bytes at {} past the end", len, *pos))
+        })?;
+    let s = &buf[*pos..end];
+    *pos = end;
+    Ok(s)
+}
@@ -0,0 +1,40 @@
+use std::hash::{Hash, Hasher};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+// Every hash in the crate is xxh3, so a key maps to the same bits wherever it is hashed:
+//
+// - A byte key hashes to hash64, a bloom filter takes the low 32 bits of it, hash32.
+// - The cache hashes a K through the Hash trait with hash_key. The CMSketch counts the
+//   key hash and the doorkeeper takes its low 32 bits, like hash32 does. The conflict
+//   hash telling apart two keys with the same key hash is hash_key with CONFLICT_SEED.
+pub(crate) const CONFLICT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+pub(crate) fn hash64(bytes: &[u8]) -> u64 {
+    xxh3_64(bytes)
+}
+
+pub(crate) fn hash32(bytes: &[u8]) -> u32 {
+    hash64(bytes) as u32
+}
+
+pub(crate) fn hash_key<K: Hash + ?Sized>(key: &K, seed: u64) -> u64 {
+    let mut hasher = Xxh3::with_seed(seed);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::hash::{hash32, hash64, hash_key, CONFLICT_SEED};
+
+    #[test]
+    fn test_hash() {
+        // the reference value of xxh3 for no input
+        assert_eq!(0x2D06800538D394C2, hash64(b""));
+        for key in [&b"a"[..], b"key", &[0xff; 100]] {
+            assert_eq!(hash64(key) as u32, hash32(key));
+            assert_eq!(hash_key(key, 0), hash_key(key, 0));
+            assert_ne!(hash_key(key, 0), hash_key(key, CONFLICT_SEED));
+        }
+    }
+}
@@ -1,18 +1,34 @@
-use crate::memory::entry::Entry;
-use crate::memory::skiplist::{Node, SkipList};
-use std::rc::Rc;
+use crate::memory::entry::{Entry, BIT_DELETE};
+use crate::memory::skiplist::{Node, SkipList, SkipListSnapshot};
 
 pub struct SkipListIter<'a> {
     l: &'a SkipList,
-    n: Option<Rc<&'a Node>>,
-    i: bool, // i == true, indicates not the first run
+    n: Option<&'a Node>,
+    i: bool,        // i == true, indicates not the first run
+    watermark: u32, // nodes at or above this offset are skipped
 }
 
-pub fn new(l: &SkipList) -> SkipListIter {
+pub fn new(l: &SkipList) -> SkipListIter<'_> {
+    new_below(l, u32::MAX)
+}
+
+// new_below returns an iterator that skips the nodes at or above the watermark offset.
+pub fn new_below(l: &SkipList, watermark: u32) -> SkipListIter<'_> {
     SkipListIter {
         l,
         n: None,
         i: false,
+        watermark,
+    }
+}
+
+// new_after returns an iterator that starts with the node following n.
+pub fn new_after<'a>(l: &'a SkipList, n: &'a Node) -> SkipListIter<'a> {
+    SkipListIter {
+        l,
+        n: Some(n),
+        i: true,
+        watermark: u32::MAX,
     }
 }
 
@@ -28,7 +44,7 @@ impl Iterator for SkipListIter<'_> {
         return match &self.n {
             None => None,
             Some(x) => {
-                if let Some(next_n) = self.l.get_next(x, 0) {
+                if let Some(next_n) = self.l.get_next_below(x, 0, self.watermark) {
                     self.n = Some(next_n);
                     self.item()
                 } else {
@@ -40,26 +56,88 @@ impl Iterator for SkipListIter<'_> {
     }
 }
 
-impl SkipListIter<'_> {
+impl<'a> SkipListIter<'a> {
+    // valid_entries yields the entries without the head node, tombstones and keys deleted
+    // by SkipList::delete_prefix, the live entries most callers are after.
+    pub fn valid_entries(mut self) -> impl Iterator<Item = Entry> + 'a {
+        let head = self.l.get_head();
+        std::iter::from_fn(move || loop {
+            let e = self.next()?;
+            let is_head = matches!((self.n, head), (Some(n), Some(h)) if std::ptr::eq(n, h));
+            let deleted = e.meta & BIT_DELETE != 0
+                || self.n.is_some_and(|n| self.l.deleted_by_prefix(n, &e.key));
+            if !is_head && !deleted {
+                return Some(e);
+            }
+        })
+    }
+
     fn valid(&self) -> bool {
         self.n.is_some()
     }
 
     fn item(&self) -> Option<Entry> {
-        match &self.n {
-            None => None,
-            Some(n) => {
-                let k = self.l.area.get_key(n.key_offset, n.key_size);
-                let v = self.l.get_value(n);
-                Option::from(Entry {
-                    key: k,
-                    value: v.v,
-                    expires_at: v.expires_at,
-                    meta: v.meta,
-                    version: v.version,
-                    ..Default::default()
-                })
-            }
-        }
+        self.n.map(|n| entry_of(self.l, n))
+    }
+}
+
+// IntoIter consumes a SkipList and yields its entries in key order, the head node is
+// not yielded.
+pub struct IntoIter {
+    l: SkipList,
+    next: u32, // offset of the next node to yield, 0 at the end of the list
+}
+
+impl IntoIterator for SkipList {
+    type Item = Entry;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        let next = self.get_head().unwrap().get_next_offset(0);
+        IntoIter { l: self, next }
     }
 }
+
+impl Iterator for IntoIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.l.area.get_node(self.next)?;
+        self.next = n.get_next_offset(0);
+        Some(entry_of(&self.l, n))
+    }
+}
+
+// StableIter yields the entries of a snapshot in key order, the head node is not yielded.
+// It owns the snapshot rather than borrowing the list, so the list can be written to while
+// it runs: nodes added after the snapshot are at or above its watermark and skipped.
+pub struct StableIter {
+    s: SkipListSnapshot,
+    next: u32, // offset of the next node to yield, 0 at the end of the list
+}
+
+impl IntoIterator for SkipListSnapshot {
+    type Item = Entry;
+    type IntoIter = StableIter;
+
+    fn into_iter(self) -> StableIter {
+        let next = self.first_offset();
+        StableIter { s: self, next }
+    }
+}
+
+impl Iterator for StableIter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.s.l.area.get_node(self.next)?;
+        self.next = self.s.next_offset(n);
+        Some(entry_of(&self.s.l, n))
+    }
+}
+
+// entry_of copies the key and the value of n out of l.
+pub(crate) fn entry_of(l: &SkipList, n: &Node) -> Entry {
+    let k = l.area.get_key(n.key_offset, n.key_size);
+    l.get_value(n).into_entry(k)
+}
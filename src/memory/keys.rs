@@ -0,0 +1,86 @@
+// A versioned key is the user key followed by an 8 bytes suffix holding the version timestamp:
+//
+// | user key | big-endian u64 (u64::MAX - ts) |
+//
+// Storing u64::MAX - ts instead of ts makes newer versions of the same user key sort first
+// when the suffix is compared bytewise, so a seek to key@ts lands on the newest version <= ts.
+//
+// Only the lists created by new_versioned_skip_list, the memtable and the sstables take
+// their keys for versioned keys, a list from new_skip_list compares its keys as they are.
+// In a versioned list any key of 8 bytes or more is a versioned key. A shorter key, the
+// empty key included, carries no timestamp and is a user key as it is. SortedMap stores
+// its keys as key_with_ts(key, 0), so a long one is never split.
+
+const TS_SIZE: usize = std::mem::size_of::<u64>();
+
+// key_with_ts generates a new key by appending ts to key.
+pub fn key_with_ts(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(key.len() + TS_SIZE);
+    out.extend_from_slice(key);
+    out.extend_from_slice(&(u64::MAX - ts).to_be_bytes());
+    out
+}
+
+// parse_key parses the actual key from the key bytes.
+// Keys shorter than the suffix carry no timestamp and are returned as they are.
+pub fn parse_key(key: &[u8]) -> &[u8] {
+    if key.len() < TS_SIZE {
+        key
+    } else {
+        &key[..key.len() - TS_SIZE]
+    }
+}
+
+// parse_ts parses the timestamp from the key bytes, 0 if the key carries no timestamp.
+pub fn parse_ts(key: &[u8]) -> u64 {
+    if key.len() < TS_SIZE {
+        0
+    } else {
+        u64::MAX - u64::from_be_bytes(key[key.len() - TS_SIZE..].try_into().unwrap())
+    }
+}
+
+// same_key checks for key equality ignoring the version timestamp suffix.
+pub fn same_key(src: &[u8], dst: &[u8]) -> bool {
+    if src.len() != dst.len() {
+        return false;
+    }
+    parse_key(src) == parse_key(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::keys::{key_with_ts, parse_key, parse_ts, same_key};
+    use crate::memory::utils::compare_keys;
+
+    #[test]
+    fn test_key_with_ts() {
+        for ts in [0, 1, 42, u64::MAX] {
+            let k = key_with_ts(b"ferris", ts);
+            assert_eq!(b"ferris".len() + 8, k.len());
+            assert_eq!(b"ferris", parse_key(&k));
+            assert_eq!(ts, parse_ts(&k));
+        }
+        // newer versions sort first, and all versions of a key sort before a longer key
+        assert!(compare_keys(&key_with_ts(b"ferris", 2), &key_with_ts(b"ferris", 1)) < 0);
+        assert!(compare_keys(&key_with_ts(b"ferris", 1), &key_with_ts(b"ferrisa", 9)) < 0);
+        assert!(compare_keys(&key_with_ts(b"", 1), &key_with_ts(b"", 1)) == 0);
+        assert_eq!(0, parse_ts(b"short"));
+    }
+
+    #[test]
+    fn test_same_key() {
+        assert!(same_key(
+            &key_with_ts(b"ferris", 1),
+            &key_with_ts(b"ferris", 2)
+        ));
+        assert!(!same_key(
+            &key_with_ts(b"ferris", 1),
+            &key_with_ts(b"crab", 1)
+        ));
+        assert!(!same_key(
+            &key_with_ts(b"ferris", 1),
+            &key_with_ts(b"ferrix", 1)
+        ));
+    }
+}
@@ -1,16 +1,30 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::LinkedList;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
-pub type Item<T> = Rc<RefCell<StoreItem<T>>>;
-pub type Map<T> = Rc<RefCell<HashMap<u64, Item<T>>>>;
+pub type Item<T> = Arc<Mutex<StoreItem<T>>>;
+pub type Map<T> = Arc<Mutex<HashMap<u64, Item<T>>>>;
 
+// The capacity of WindowLRU and SegmentedLRU is measured in cost, each item carries its
+// own cost. With a cost of 1 for every item the capacity is simply the number of items.
 #[derive(Debug)]
 pub struct WindowLRU<T> {
     data: Map<T>,
     cap: usize,
+    used: i64,
     list: LinkedList<Item<T>>,
+    // set in byte capacity mode, the window then counts the bytes of the values instead
+    // of the costs of the items
+    size_of: Option<SizeOf<T>>,
+}
+
+// SizeOf returns the number of bytes a value takes.
+pub struct SizeOf<T>(Box<dyn Fn(&T) -> usize + Send + Sync>);
+
+impl<T> std::fmt::Debug for SizeOf<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SizeOf")
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -18,6 +32,9 @@ pub struct StoreItem<T> {
     pub stage: u8,
     pub key: u64,
     pub conflict: u64,
+    pub cost: i64,
+    // unix seconds, 0 if the item never expires
+    pub expires_at: u64,
     pub value: T,
 }
 
@@ -25,32 +42,101 @@ pub fn new_lru<T>(size: usize, data: Map<T>) -> WindowLRU<T> {
     WindowLRU {
         data,
         cap: size,
+        used: 0,
         list: LinkedList::new(),
+        size_of: None,
+    }
+}
+
+// new_lru_with_byte_cap creates a window holding values of up to cap_bytes bytes in total,
+// size_of tells the size of a value. The cost of the items is ignored.
+pub fn new_lru_with_byte_cap<T>(
+    cap_bytes: usize,
+    data: Map<T>,
+    size_of: impl Fn(&T) -> usize + Send + Sync + 'static,
+) -> WindowLRU<T> {
+    WindowLRU {
+        size_of: Some(SizeOf(Box::new(size_of))),
+        ..new_lru(cap_bytes, data)
     }
 }
 
 impl<T> WindowLRU<T> {
-    pub fn add(&mut self, new_item: StoreItem<T>) -> Option<Rc<RefCell<StoreItem<T>>>> {
-        let item = Rc::new(RefCell::new(new_item));
-
-        // If the window's capacity is not full, directly insert the new item
-        if self.list.len() < self.cap {
-            self.list.push_front(Rc::clone(&item));
-            self.data
-                .borrow_mut()
-                .insert(item.borrow().key, Rc::clone(&item));
-            return None;
-        }
+    // add puts the new item at the front of the window, the items evicted from the tail
+    // to make room for it are returned.
+    pub fn add(&mut self, new_item: StoreItem<T>) -> Vec<Item<T>> {
+        let cost = self.cost_of(&new_item);
+        let item = Arc::new(Mutex::new(new_item));
 
-        // If the window's capacity is full, evict the item from the tail according to the LRU rule
-        let evict_item = self.list.pop_back().unwrap();
-        self.data.borrow_mut().remove(&evict_item.borrow().key);
+        // If the window's capacity is full, evict items from the tail according to the LRU rule
+        let mut evicted = Vec::new();
+        while self.used + cost > self.cap as i64 {
+            let Some(evict_item) = self.list.pop_back() else {
+                break;
+            };
+            let (key, evict_cost) = {
+                let e = evict_item.lock().unwrap();
+                (e.key, self.cost_of(&e))
+            };
+            self.data.lock().unwrap().remove(&key);
+            self.used -= evict_cost;
+            evicted.push(evict_item);
+        }
 
-        self.list.push_front(Rc::clone(&item));
+        self.list.push_front(Arc::clone(&item));
+        self.used += cost;
         self.data
-            .borrow_mut()
-            .insert(item.borrow().key, Rc::clone(&item));
-        Some(evict_item)
+            .lock()
+            .unwrap()
+            .insert(item.lock().unwrap().key, Arc::clone(&item));
+        evicted
+    }
+
+    // items visits the window from the most to the least recently used item.
+    pub fn items(&self) -> impl Iterator<Item = &Item<T>> {
+        self.list.iter()
+    }
+
+    // restore puts item at the tail of the window, restoring the items in the order of
+    // items rebuilds the window. It returns false and drops the item if it doesn't fit.
+    pub fn restore(&mut self, item: StoreItem<T>) -> bool {
+        let cost = self.cost_of(&item);
+        if self.used + cost > self.cap as i64 {
+            return false;
+        }
+        self.used += cost;
+        let key = item.key;
+        let item = Arc::new(Mutex::new(item));
+        self.list.push_back(Arc::clone(&item));
+        self.data.lock().unwrap().insert(key, item);
+        true
+    }
+
+    // peek returns the item of key if it is in the window, unlike get it leaves the
+    // order of the window as it is.
+    pub fn peek(&self, key: u64) -> Option<Item<T>> {
+        peek_stage(&self.data, key, |stage| stage == 0)
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.peek(key).is_some()
+    }
+
+    // remove takes the item of key out of the window and the map.
+    pub fn remove(&mut self, key: u64) -> Option<Item<T>> {
+        self.peek(key)?;
+        let item = self.remove_item_in_list(key)?;
+        self.used -= self.cost_of(&item.lock().unwrap());
+        self.data.lock().unwrap().remove(&key);
+        Some(item)
+    }
+
+    // cost_of is what item takes of the capacity, its size in byte capacity mode.
+    fn cost_of(&self, item: &StoreItem<T>) -> i64 {
+        match &self.size_of {
+            Some(size_of) => (size_of.0)(&item.value) as i64,
+            None => item.cost,
+        }
     }
 
     pub fn get(&mut self, key: u64) {
@@ -58,14 +144,12 @@ impl<T> WindowLRU<T> {
             self.list.push_front(item);
         }
     }
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.used = 0;
+    }
     fn remove_item_in_list(&mut self, key: u64) -> Option<Item<T>> {
-        if let Some(pos) = self.list.iter().position(|i| i.borrow().key == key) {
-            let mut after = self.list.split_off(pos);
-            let ret = after.pop_front();
-            self.list.append(&mut after);
-            return ret;
-        }
-        None
+        remove_item(&mut self.list, key)
     }
 }
 
@@ -74,98 +158,179 @@ pub struct SegmentedLRU<T> {
     data: Map<T>,
     stage_one_cap: usize,
     stage_two_cap: usize,
+    stage_one_used: i64,
+    stage_two_used: i64,
     stage_one: LinkedList<Item<T>>,
     stage_two: LinkedList<Item<T>>,
 }
 
-const STAGE_ONE: u8 = 1;
-const STAGE_TWO: u8 = 2;
+pub(crate) const STAGE_ONE: u8 = 1;
+pub(crate) const STAGE_TWO: u8 = 2;
 
 pub fn new_slru<T>(stage_one_cap: usize, stage_two_cap: usize, data: Map<T>) -> SegmentedLRU<T> {
     SegmentedLRU {
         data,
         stage_one_cap,
         stage_two_cap,
+        stage_one_used: 0,
+        stage_two_used: 0,
         stage_one: LinkedList::new(),
         stage_two: LinkedList::new(),
     }
 }
 
 impl<T> SegmentedLRU<T> {
-    pub fn add(&mut self, item: Item<T>) {
+    // add puts item at the front of stage one, the items evicted from the tail of stage
+    // one to make room for it are returned.
+    pub fn add(&mut self, item: Item<T>) -> Vec<Item<T>> {
         // New items always start in stage one
-        item.borrow_mut().stage = 1;
-        let item = Rc::new(item);
-        // If stage one is not full and the overall capacity is not reached, we're done
-        if self.stage_one.len() < self.stage_one_cap
-            || self.len() < self.stage_one_cap + self.stage_two_cap
-        {
-            self.stage_one.push_front(Rc::clone(&item));
-            self.data
-                .borrow_mut()
-                .insert(item.borrow().key, Rc::clone(&item));
-            return;
+        let (key, cost) = {
+            let mut i = item.lock().unwrap();
+            i.stage = STAGE_ONE;
+            (i.key, i.cost)
+        };
+        let fits = |slru: &Self| {
+            slru.stage_one_used + cost <= slru.stage_one_cap as i64
+                || slru.used() + cost <= slru.cap() as i64
+        };
+
+        // If stage one is not full or the overall capacity is not reached, we're done.
+        // Otherwise, we need to evict from stage one until the new item fits
+        let mut evicted_items = Vec::new();
+        while !fits(self) {
+            let Some(evicted) = self.stage_one.pop_back() else {
+                break;
+            };
+            let (evicted_key, evicted_cost) = {
+                let e = evicted.lock().unwrap();
+                (e.key, e.cost)
+            };
+            self.data.lock().unwrap().remove(&evicted_key);
+            self.stage_one_used -= evicted_cost;
+            evicted_items.push(evicted);
         }
 
-        // Otherwise, we need to evict from stage one
-        let evicted = self.stage_one.pop_back().unwrap();
-        self.data.borrow_mut().remove(&evicted.borrow().key);
+        self.stage_one.push_front(Arc::clone(&item));
+        self.stage_one_used += cost;
+        self.data.lock().unwrap().insert(key, item);
+        evicted_items
+    }
 
-        self.stage_one.push_front(Rc::clone(&item));
-        self.data
-            .borrow_mut()
-            .insert(item.borrow().key, Rc::clone(&item));
+    // peek returns the item of key if it is in either stage, unlike get it neither
+    // reorders the stages nor promotes the item.
+    pub fn peek(&self, key: u64) -> Option<Item<T>> {
+        peek_stage(&self.data, key, |stage| stage != 0)
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.peek(key).is_some()
+    }
+
+    // remove takes the item of key out of its stage and the map.
+    pub fn remove(&mut self, key: u64) -> Option<Item<T>> {
+        let stage = self.peek(key)?.lock().unwrap().stage;
+        let (list, used) = if stage == STAGE_TWO {
+            (&mut self.stage_two, &mut self.stage_two_used)
+        } else {
+            (&mut self.stage_one, &mut self.stage_one_used)
+        };
+        let item = remove_item(list, key)?;
+        *used -= item.lock().unwrap().cost;
+        self.data.lock().unwrap().remove(&key);
+        Some(item)
     }
 
     pub fn get(&mut self, new_item: Item<T>) {
-        if STAGE_TWO == new_item.borrow().stage {
-            if let Some(v) = self.stage_two.pop_back() {
+        let (key, stage, cost) = {
+            let i = new_item.lock().unwrap();
+            (i.key, i.stage, i.cost)
+        };
+        // item already in stage two, just move it to the front
+        if STAGE_TWO == stage {
+            if let Some(v) = remove_item(&mut self.stage_two, key) {
                 self.stage_two.push_front(v);
+            }
+            return;
+        }
+        if self.remove_item_in_stage_one(key).is_none() {
+            return;
+        }
+        // the item can never fit into stage two, keep it in stage one
+        if cost > self.stage_two_cap as i64 {
+            self.stage_one.push_front(new_item);
+            return;
+        }
+        self.stage_one_used -= cost;
+        // if stage two is full, move the old items from its back to stage one
+        while self.stage_two_used + cost > self.stage_two_cap as i64 {
+            let Some(old) = self.stage_two.pop_back() else {
+                break;
             };
+            let old_cost = {
+                let mut o = old.lock().unwrap();
+                o.stage = STAGE_ONE;
+                o.cost
+            };
+            self.stage_two_used -= old_cost;
+            self.stage_one_used += old_cost;
+            self.stage_one.push_front(old);
         }
-        // item in stage one, and stage two is not full yet
-        if self.stage_two.len() < self.stage_two_cap {
-            {
-                self.remove_item_in_stage_one(new_item.borrow().key);
-                new_item.borrow_mut().stage = STAGE_TWO;
-            }
-            self.stage_two.push_front(Rc::clone(&new_item));
+        new_item.lock().unwrap().stage = STAGE_TWO;
+        self.stage_two.push_front(new_item);
+        self.stage_two_used += cost;
+    }
+    // items visits stage one then stage two, each from the most to the least recently
+    // used item.
+    pub fn items(&self) -> impl Iterator<Item = &Item<T>> {
+        self.stage_one.iter().chain(self.stage_two.iter())
+    }
+
+    // restore puts item at the tail of the stage it is marked with, restoring the items in
+    // the order of items rebuilds the segments. It returns false and drops the item if it
+    // doesn't fit.
+    pub fn restore(&mut self, item: StoreItem<T>) -> bool {
+        let cost = item.cost;
+        // Same as add, stage one may take the room stage two doesn't use.
+        let fits = if item.stage == STAGE_TWO {
+            self.stage_two_used + cost <= self.stage_two_cap as i64
         } else {
-            // move old data from stage two to stage one
-            let old = self.stage_two.pop_back().unwrap();
-
-            new_item.borrow_mut().stage = STAGE_TWO;
-            self.stage_two.push_front(Rc::clone(&new_item));
-            self.data
-                .borrow_mut()
-                .insert(new_item.borrow().key, Rc::clone(&new_item));
-
-            old.borrow_mut().stage = STAGE_ONE;
-            if self.stage_one.len() >= self.stage_one_cap {
-                let evicted = self.stage_one.pop_back().unwrap();
-                self.data.borrow_mut().remove(&evicted.borrow().key);
-            }
-            self.stage_one.push_front(Rc::clone(&old));
-            self.data
-                .borrow_mut()
-                .insert(old.borrow().key, Rc::clone(&old));
+            self.stage_one_used + cost <= self.stage_one_cap as i64
+                || self.used() + cost <= self.cap() as i64
+        };
+        if !fits {
+            return false;
         }
+        let (list, used) = if item.stage == STAGE_TWO {
+            (&mut self.stage_two, &mut self.stage_two_used)
+        } else {
+            (&mut self.stage_one, &mut self.stage_one_used)
+        };
+        *used += cost;
+        let key = item.key;
+        let item = Arc::new(Mutex::new(item));
+        list.push_back(Arc::clone(&item));
+        self.data.lock().unwrap().insert(key, item);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.stage_one.clear();
+        self.stage_two.clear();
+        self.stage_one_used = 0;
+        self.stage_two_used = 0;
     }
     fn remove_item_in_stage_one(&mut self, key: u64) -> Option<Item<T>> {
-        if let Some(pos) = self.stage_one.iter().position(|i| i.borrow().key == key) {
-            let mut after = self.stage_one.split_off(pos);
-            let ret = after.pop_front();
-            self.stage_one.append(&mut after);
-            return ret;
-        }
-        None
+        remove_item(&mut self.stage_one, key)
+    }
+    fn used(&self) -> i64 {
+        self.stage_one_used + self.stage_two_used
     }
-    fn len(&self) -> usize {
-        self.stage_one.len() + self.stage_two.len()
+    fn cap(&self) -> usize {
+        self.stage_one_cap + self.stage_two_cap
     }
 
     pub fn victim(&self) -> Option<&Item<T>> {
-        if self.len() < self.stage_one_cap + self.stage_two_cap {
+        if self.used() < self.cap() as i64 {
             return None;
         }
         self.stage_one.back()
@@ -182,12 +347,29 @@ impl<T> SegmentedLRU<T> {
     }
 }
 
+// peek_stage looks key up in the map both LRUs share, the item is returned only if
+// in_stage accepts its stage.
+fn peek_stage<T>(data: &Map<T>, key: u64, in_stage: impl Fn(u8) -> bool) -> Option<Item<T>> {
+    let item = Arc::clone(data.lock().unwrap().get(&key)?);
+    let stage = item.lock().unwrap().stage;
+    in_stage(stage).then_some(item)
+}
+
+fn remove_item<T>(list: &mut LinkedList<Item<T>>, key: u64) -> Option<Item<T>> {
+    if let Some(pos) = list.iter().position(|i| i.lock().unwrap().key == key) {
+        let mut after = list.split_off(pos);
+        let ret = after.pop_front();
+        list.append(&mut after);
+        return ret;
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::memory::lru::{new_lru, StoreItem};
-    use std::cell::RefCell;
+    use crate::memory::lru::{new_lru, new_lru_with_byte_cap, new_slru, Item, StoreItem};
     use std::collections::HashMap;
-    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
 
     struct User {
         name: String,
@@ -195,20 +377,144 @@ mod tests {
 
     #[test]
     fn test_lru() {
-        let data = Rc::new(RefCell::new(HashMap::new()));
+        let data = Arc::new(Mutex::new(HashMap::new()));
         let mut a = new_lru::<User>(100, data);
         let name = "Ferris".to_string();
         let v = StoreItem {
             stage: 0,
             key: 0,
             conflict: 0,
+            cost: 1,
+            expires_at: 0,
             value: User { name: name.clone() },
         };
-        if let Some(ret) = a.add(v) {
-            assert_eq!(name, ret.borrow().value.name)
+        for ret in a.add(v) {
+            assert_eq!(name, ret.lock().unwrap().value.name)
+        }
+    }
+
+    fn item(key: u64) -> StoreItem<u64> {
+        StoreItem {
+            stage: 0,
+            key,
+            conflict: 0,
+            cost: 1,
+            expires_at: 0,
+            value: key,
         }
     }
 
+    #[test]
+    fn test_peek() {
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let mut lru = new_lru::<u64>(2, Arc::clone(&data));
+        let mut slru = new_slru::<u64>(2, 2, Arc::clone(&data));
+        lru.add(item(1));
+        lru.add(item(2));
+        // 1 is the least recently used, peeking at it doesn't change that
+        assert_eq!(1, lru.peek(1).unwrap().lock().unwrap().value);
+        let evicted = lru.add(item(3));
+        assert_eq!(1, evicted.len());
+        assert_eq!(1, evicted[0].lock().unwrap().key);
+        assert!(lru.peek(1).is_none());
+
+        for key in [10, 11, 12, 13] {
+            slru.add(Arc::new(Mutex::new(item(key))));
+        }
+        assert_eq!(10, slru.victim().unwrap().lock().unwrap().key);
+        assert_eq!(10, slru.peek(10).unwrap().lock().unwrap().value);
+        assert_eq!(10, slru.victim().unwrap().lock().unwrap().key);
+        // each structure sees only its own items
+        assert!(slru.peek(2).is_none());
+        assert!(lru.peek(10).is_none());
+        assert_eq!(1, slru.peek(10).unwrap().lock().unwrap().stage);
+    }
+
+    #[test]
+    fn test_remove() {
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let mut lru = new_lru::<u64>(2, Arc::clone(&data));
+        let mut slru = new_slru::<u64>(1, 2, Arc::clone(&data));
+        lru.add(item(1));
+        lru.add(item(2));
+        assert!(lru.contains(1));
+        assert_eq!(1, lru.remove(1).unwrap().lock().unwrap().key);
+        assert!(!lru.contains(1));
+        assert!(lru.remove(1).is_none());
+        assert_eq!(vec![2], keys(lru.items()));
+        // the room of a removed item is free again
+        assert!(lru.add(item(3)).is_empty());
+
+        for key in [10, 11, 12] {
+            slru.add(Arc::new(Mutex::new(item(key))));
+        }
+        slru.get(Arc::clone(&data.lock().unwrap()[&11]));
+        assert_eq!(vec![12, 10, 11], keys(slru.items()));
+        // the window's items are not the slru's to remove
+        assert!(slru.remove(2).is_none());
+        assert!(lru.contains(2));
+        assert!(slru.remove(11).is_some());
+        assert!(slru.remove(10).is_some());
+        assert_eq!(vec![12], keys(slru.items()));
+        assert!(!data.lock().unwrap().contains_key(&10));
+        assert!(slru.victim().is_none());
+    }
+
+    #[test]
+    fn test_slru_add_evicted() {
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let mut slru = new_slru::<u64>(1, 2, Arc::clone(&data));
+        for key in [10, 11, 12] {
+            assert!(slru.add(Arc::new(Mutex::new(item(key)))).is_empty());
+        }
+        // stage one overflows, its tail is handed back and is gone from the map
+        let evicted = slru.add(Arc::new(Mutex::new(item(13))));
+        assert_eq!(vec![10], keys(evicted.iter()));
+        assert!(!data.lock().unwrap().contains_key(&10));
+        // an item costing more than one pushes out as many as it takes
+        let mut big = item(14);
+        big.cost = 2;
+        let evicted = slru.add(Arc::new(Mutex::new(big)));
+        assert_eq!(vec![11, 12], keys(evicted.iter()));
+        assert_eq!(vec![14, 13], keys(slru.items()));
+    }
+
+    #[test]
+    fn test_byte_cap() {
+        let data = Arc::new(Mutex::new(HashMap::new()));
+        let mut lru = new_lru_with_byte_cap(10, Arc::clone(&data), |v: &Vec<u8>| v.len());
+        let sized = |key: u64, size: usize| StoreItem {
+            stage: 0,
+            key,
+            conflict: 0,
+            cost: 1,
+            expires_at: 0,
+            value: vec![0u8; size],
+        };
+        let total = |lru: &super::WindowLRU<Vec<u8>>| -> usize {
+            lru.items().map(|i| i.lock().unwrap().value.len()).sum()
+        };
+        assert!(lru.add(sized(1, 4)).is_empty());
+        assert!(lru.add(sized(2, 4)).is_empty());
+        assert!(lru.add(sized(3, 2)).is_empty());
+        assert_eq!(10, total(&lru));
+        // 3 items of cost 1 are within any count, the bytes are not
+        let evicted = lru.add(sized(4, 7));
+        let evicted: Vec<_> = evicted.iter().map(|i| i.lock().unwrap().key).collect();
+        assert_eq!(vec![1, 2], evicted);
+        let left: Vec<_> = lru.items().map(|i| i.lock().unwrap().key).collect();
+        assert_eq!(vec![4, 3], left);
+        assert_eq!(9, total(&lru));
+
+        lru.remove(4);
+        assert!(lru.add(sized(5, 8)).is_empty());
+        assert_eq!(10, total(&lru));
+    }
+
+    fn keys<'a>(items: impl Iterator<Item = &'a Item<u64>>) -> Vec<u64> {
+        items.map(|i| i.lock().unwrap().key).collect()
+    }
+
     #[test]
     fn test_lru2() {}
 }
@@ -0,0 +1,174 @@
+use crate::error::StepDbError;
+use crate::memory::area::ArenaPool;
+use crate::memory::entry::{Entry, Value};
+use crate::memory::skiplist::{
+    estimate_size, new_skip_list_in, SkipList, MAX_KEY_SIZE, MAX_VALUE_SIZE, MIN_ARENA_SIZE,
+};
+use std::collections::VecDeque;
+
+// Memtable writes into an active SkipList. Once the arena of the active list is about to
+// grow past threshold, the list becomes immutable and waits in the queue to be flushed,
+// a fresh list takes its place. The arenas of flushed lists come back through recycle and
+// are reused for the next lists.
+pub struct Memtable {
+    pool: ArenaPool,
+    arena_size: u32,
+    threshold: i64,
+    active: SkipList,
+    // oldest at the front, newest at the back
+    immutables: VecDeque<SkipList>,
+}
+
+impl Memtable {
+    // threshold is the arena usage in bytes at which the active list is rotated, it must
+    // not be larger than arena_size. It returns ArenaFull for an arena smaller than
+    // MIN_ARENA_SIZE.
+    pub fn new(arena_size: u32, threshold: u32) -> Result<Self, StepDbError> {
+        assert!(
+            threshold <= arena_size,
+            "threshold is larger than the arena"
+        );
+        let mut pool = ArenaPool::new(arena_size);
+        Ok(Memtable {
+            arena_size,
+            threshold: threshold as i64,
+            active: new_skip_list_in(pool.get())?,
+            pool,
+            immutables: VecDeque::new(),
+        })
+    }
+
+    // check returns the error put would return for e without adding it: KeyTooLarge,
+    // ValueTooLarge, or ArenaFull for an entry that doesn't fit even in an empty arena.
+    pub fn check(&self, e: &Entry) -> Result<(), StepDbError> {
+        if e.key.len() > MAX_KEY_SIZE {
+            return Err(StepDbError::KeyTooLarge(e.key.len()));
+        }
+        let size = Value::from(e).encoded_size();
+        if size > MAX_VALUE_SIZE {
+            return Err(StepDbError::ValueTooLarge(size));
+        }
+        if MIN_ARENA_SIZE as i64 + estimate_size(e) > self.arena_size as i64 {
+            return Err(StepDbError::ArenaFull);
+        }
+        Ok(())
+    }
+
+    // put adds e to the active list, rotating it first if e would take it past threshold.
+    // It returns the error of check for an entry the list refuses, nothing is changed then.
+    pub fn put(&mut self, e: Entry) -> Result<(), StepDbError> {
+        self.check(&e)?;
+        if self.active.mem_size() + estimate_size(&e) > self.threshold {
+            self.rotate()?;
+        }
+        self.active.add(e)
+    }
+
+    // get checks the active list first, then the immutable lists from newest to oldest.
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        if let Some(v) = self.active.get(key) {
+            return Some(v);
+        }
+        self.immutables.iter().rev().find_map(|l| l.get(key))
+    }
+
+    pub fn immutables(&self) -> usize {
+        self.immutables.len()
+    }
+
+    // pop_immutable hands out the oldest immutable list, the one to flush first.
+    pub fn pop_immutable(&mut self) -> Option<SkipList> {
+        self.immutables.pop_front()
+    }
+
+    // recycle takes back a list popped by pop_immutable once it is flushed, its arena is
+    // used again by a later list.
+    pub fn recycle(&mut self, list: SkipList) {
+        if let Some(area) = list.into_area() {
+            self.pool.put(area);
+        }
+    }
+
+    // rotate moves the active list to the immutable queue, even if it isn't full,
+    // and starts a fresh one.
+    pub fn rotate(&mut self) -> Result<(), StepDbError> {
+        let old = std::mem::replace(&mut self.active, new_skip_list_in(self.pool.get())?);
+        self.immutables.push_back(old);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::StepDbError;
+    use crate::memory::entry::new_entry;
+    use crate::memory::keys::key_with_ts;
+    use crate::memory::memtable::Memtable;
+    use crate::memory::skiplist::MAX_KEY_SIZE;
+
+    #[test]
+    fn test_memtable_rotate() {
+        let mut m = Memtable::new(1 << 16, 1 << 14).unwrap();
+        let mut n = 0;
+        while m.immutables() == 0 {
+            let k = key_with_ts(format!("key{:06}", n).as_bytes(), 1);
+            m.put(new_entry(&k, &k)).unwrap();
+            n += 1;
+        }
+        // the key that triggered the rotation is in the active list
+        for i in 0..n {
+            let k = key_with_ts(format!("key{:06}", i).as_bytes(), 1);
+            assert_eq!(k, m.get(&k).unwrap().v);
+        }
+        assert!(m.get(&key_with_ts(b"key999999", 1)).is_none());
+
+        // newer versions in the active list shadow the immutable ones
+        let k = key_with_ts(b"key000000", 1);
+        m.put(new_entry(&k, b"new")).unwrap();
+        assert_eq!(b"new".to_vec(), m.get(&k).unwrap().v);
+
+        let old = m.pop_immutable().unwrap();
+        assert_eq!(k, old.get(&k).unwrap().v);
+        assert!(m.pop_immutable().is_none());
+    }
+
+    #[test]
+    fn test_memtable_check() {
+        let mut m = Memtable::new(1 << 12, 1 << 12).unwrap();
+        let k = key_with_ts(b"key", 1);
+        let big = new_entry(&k, &[b'v'; 1 << 12]);
+        assert_eq!(Err(StepDbError::ArenaFull), m.check(&big));
+        assert_eq!(Err(StepDbError::ArenaFull), m.put(big));
+        // a refused entry doesn't rotate the active list
+        assert_eq!(0, m.immutables());
+        let long = new_entry(&[b'k'; MAX_KEY_SIZE + 1], b"v");
+        assert_eq!(
+            Err(StepDbError::KeyTooLarge(MAX_KEY_SIZE + 1)),
+            m.check(&long)
+        );
+
+        let e = new_entry(&k, b"v");
+        m.check(&e).unwrap();
+        m.put(e).unwrap();
+        assert_eq!(b"v".to_vec(), m.get(&k).unwrap().v);
+    }
+    #[test]
+    fn test_memtable_recycle() {
+        let mut m = Memtable::new(1 << 12, 1 << 12).unwrap();
+        for round in 0..4u64 {
+            let k = key_with_ts(b"key", round + 1);
+            m.put(new_entry(&k, b"v")).unwrap();
+            m.rotate().unwrap();
+            // the list that took the place of the rotated one runs on the recycled arena
+            assert_eq!(0, m.pool.len());
+            let list = m.pop_immutable().unwrap();
+            assert_eq!(b"v".to_vec(), list.get(&k).unwrap().v);
+            m.recycle(list);
+            assert_eq!(1, m.pool.len());
+        }
+        // a recycled arena starts out empty
+        m.rotate().unwrap();
+        assert!(m.get(&key_with_ts(b"key", 1)).is_none());
+        assert_eq!(0, m.pool.len());
+    }
+}
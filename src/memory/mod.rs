@@ -1,9 +1,16 @@
 mod area;
-mod bloom;
+pub mod bloom;
 mod cache;
+pub mod clock;
 mod counter;
-mod entry;
-mod iterator;
+pub mod entry;
+mod hash;
+pub(crate) mod iterator;
+pub mod keys;
 mod lru;
-mod skiplist;
-mod utils;
+pub(crate) mod memtable;
+mod sharded_cache;
+pub mod skiplist;
+pub(crate) mod sorted_map;
+pub(crate) mod typed_skiplist;
+pub(crate) mod utils;
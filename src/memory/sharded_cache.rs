@@ -0,0 +1,144 @@
+use crate::memory::cache::{Cache, CacheLike};
+use std::hash::Hash;
+
+// ShardedCache spreads keys over independent Caches, each behind its own lock, so
+// operations on keys of different shards don't wait for each other. A key always goes
+// to the same shard, picked by the high half of its key hash.
+#[derive(Debug)]
+pub struct ShardedCache<K, V> {
+    shards: Vec<Cache<K, V>>,
+}
+
+impl<K, V> ShardedCache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    // new creates num_shards shards sharing size, each holds size / num_shards items.
+    pub fn new(size: usize, num_shards: usize) -> Self {
+        assert!(num_shards > 0, "a sharded cache needs at least one shard");
+        let shard_size = (size / num_shards).max(1);
+        ShardedCache {
+            shards: (0..num_shards).map(|_| Cache::new(shard_size)).collect(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key)
+    }
+
+    pub fn set(&self, key: K, value: V) -> bool {
+        self.shard(&key).set(key, value)
+    }
+
+    pub fn del(&self, key: K) -> Option<u64> {
+        self.shard(&key).del(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> &Cache<K, V> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    // shard_index picks the shard from the high 32 bits of the key hash. The sketch and the
+    // doorkeeper of a shard index by the low bits, keys sharing those would only reach a
+    // fraction of their counters.
+    fn shard_index(&self, key: &K) -> usize {
+        let (key_hash, _) = self.shards[0].key_to_hash(key);
+        ((key_hash >> 32) % self.shards.len() as u64) as usize
+    }
+}
+
+impl<K, V> CacheLike<K, V> for ShardedCache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        ShardedCache::get(self, key)
+    }
+    fn set(&self, key: K, value: V) -> bool {
+        ShardedCache::set(self, key, value)
+    }
+    fn del(&self, key: K) -> Option<u64> {
+        ShardedCache::del(self, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::sharded_cache::ShardedCache;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_shards() {
+        let cache = ShardedCache::<u64, u64>::new(1000, 4);
+        for i in 0..100 {
+            let shard = cache.shard_index(&i);
+            assert_eq!(shard, cache.shard_index(&i));
+            cache.set(i, i);
+            // the key is in its shard only
+            for (j, s) in cache.shards.iter().enumerate() {
+                assert_eq!(j == shard, s.contains(&i));
+            }
+        }
+        assert_eq!(100, cache.len());
+        // keys spread over every shard
+        assert!(cache.shards.iter().all(|s| !s.is_empty()));
+        assert_eq!(Some(7), cache.get(&7));
+        assert!(cache.del(7).is_some());
+        assert_eq!(None, cache.get(&7));
+    }
+
+    #[test]
+    fn test_shard_low_bits() {
+        let cache = ShardedCache::<u64, u64>::new(1000, 4);
+        // the keys of one shard still cover every low bit pattern
+        let mut low = [false; 4];
+        for i in (0..1000).filter(|i| cache.shard_index(i) == 0) {
+            let (key_hash, _) = cache.shards[0].key_to_hash(&i);
+            low[(key_hash % 4) as usize] = true;
+        }
+        assert!(low.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn test_shards_in_parallel() {
+        let cache = ShardedCache::<u64, u64>::new(1000, 2);
+        let a = 0;
+        let b = (1..).find(|k| cache.shard_index(k) != cache.shard_index(&a));
+        let b = b.unwrap();
+        cache.set(a, 0);
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel();
+        let cache = &cache;
+        thread::scope(|s| {
+            // update holds the write lock of the shard of a while its closure runs
+            s.spawn(move || {
+                cache.shard(&a).update(&a, |_| {
+                    locked_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                })
+            });
+            locked_rx.recv().unwrap();
+            s.spawn(move || {
+                cache.set(b, 1);
+                done_tx.send(cache.get(&b)).unwrap();
+            });
+            let got = done_rx.recv_timeout(Duration::from_secs(5));
+            release_tx.send(()).unwrap();
+            assert_eq!(Ok(Some(1)), got);
+        });
+    }
+}
@@ -1,16 +1,37 @@
+use crate::error::StepDbError;
 use crate::memory::area::Area;
-use crate::memory::entry::{Entry, Value};
+use crate::memory::clock::{Clock, SystemClock};
+use crate::memory::entry::{Entry, Value, ValueLog, ValuePointer, BIT_DELETE, BIT_VALUE_POINTER};
 use crate::memory::iterator;
-use crate::memory::iterator::SkipListIter;
+use crate::memory::iterator::{SkipListIter, StableIter};
+use crate::memory::keys::{key_with_ts, parse_key, parse_ts, same_key};
 use crate::memory::utils::compare_keys;
 use rand::random;
-use std::ops::Deref;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 use std::rc::Rc;
 use std::sync::atomic::Ordering::{Acquire, Relaxed};
 use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub const MAX_HEIGHT: usize = 20;
+// A node keeps the size of its key in a u16 and the size of its encoded value in 31 bits,
+// see INLINE_VALUE.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+pub const MAX_VALUE_SIZE: usize = i32::MAX as usize;
+// DEFAULT_P is the probability that a node reaching a level also reaches the next one.
+const DEFAULT_P: f64 = 1.0 / 3.0;
+// EXPIRY_BUCKET is the width in seconds of the buckets of the expiry index.
+const EXPIRY_BUCKET: u64 = 60;
 
+// Node is #[repr(C)] so its in-memory layout is fixed:
+//
+// | value u64 | key_offset u32 | key_size u16 | height u16 | tower [u32; height] |
+//
+// Fields are in host byte order in the arena. Anything written to a file goes through
+// encode_le/decode_le, which use the same layout with every field little-endian.
 #[repr(C)]
 #[derive(Debug, Default)]
 pub struct Node {
@@ -25,63 +46,289 @@ impl Node {
     pub fn get_next_offset(&self, h: i32) -> u32 {
         self.tower[h as usize].load(Relaxed)
     }
-    pub fn get_value_offset(&self) -> (u32, u32) {
+    // get_value_offset returns the offset and the size of the value in the arena, None for
+    // a value inlined in the node.
+    pub fn get_value_offset(&self) -> Option<(u32, u32)> {
         let i = self.value.load(Relaxed);
-        decode_value(i)
+        if i & INLINE_VALUE != 0 {
+            return None;
+        }
+        Some(decode_value(i))
     }
     pub fn set_value(&self, vo: u64) {
         self.value.store(vo, Relaxed);
     }
+
+    // encoded_size_le is the number of bytes encode_le writes for the node.
+    pub fn encoded_size_le(&self) -> usize {
+        NODE_HEADER_SIZE + self.height as usize * 4
+    }
+
+    // encode_le writes the node into buf in the canonical little-endian layout,
+    // only the first height levels of the tower are written.
+    pub fn encode_le(&self, buf: &mut [u8]) -> usize {
+        buf[0..8].copy_from_slice(&self.value.load(Relaxed).to_le_bytes());
+        buf[8..12].copy_from_slice(&self.key_offset.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.key_size.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.height.to_le_bytes());
+        for (i, next) in self.tower[..self.height as usize].iter().enumerate() {
+            let start = NODE_HEADER_SIZE + i * 4;
+            buf[start..start + 4].copy_from_slice(&next.load(Relaxed).to_le_bytes());
+        }
+        self.encoded_size_le()
+    }
+
+    // decode_le reads a node written by encode_le.
+    pub fn decode_le(buf: &[u8]) -> Node {
+        let height = u16::from_le_bytes(buf[14..16].try_into().unwrap());
+        let node = Node {
+            value: AtomicU64::new(u64::from_le_bytes(buf[0..8].try_into().unwrap())),
+            key_offset: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            key_size: u16::from_le_bytes(buf[12..14].try_into().unwrap()),
+            height,
+            ..Default::default()
+        };
+        for i in 0..height as usize {
+            let start = NODE_HEADER_SIZE + i * 4;
+            let next = u32::from_le_bytes(buf[start..start + 4].try_into().unwrap());
+            node.tower[i].store(next, Relaxed);
+        }
+        node
+    }
 }
 
-fn new_node<'a>(area: &'a Area, key: Vec<u8>, v: &'a Value, height: usize) -> Rc<&'a mut Node> {
-    let node_offset = area.put_node(height);
-    let key_offset = area.put_key(key.clone());
-    let val = encode_value(area.put_value(v), v.encoded_size() as u32);
-    let mut node = area.get_node_mut(node_offset).unwrap();
-    {
-        let n = Rc::get_mut(&mut node).unwrap();
-        n.key_offset = key_offset;
-        n.key_size = key.len() as u16;
-        n.height = height as u16;
-        n.value = AtomicU64::from(val);
-        let x = &area.get_buf()[8..104];
-        println!("new_node :{:?}", x.to_vec());
-    }
-    node
+const NODE_HEADER_SIZE: usize = 16;
+
+// Enforce the layout documented on Node.
+const _: () = {
+    assert!(std::mem::offset_of!(Node, value) == 0);
+    assert!(std::mem::offset_of!(Node, key_offset) == 8);
+    assert!(std::mem::offset_of!(Node, key_size) == 12);
+    assert!(std::mem::offset_of!(Node, height) == 14);
+    assert!(std::mem::offset_of!(Node, tower) == NODE_HEADER_SIZE);
+    assert!(std::mem::size_of::<Node>() == NODE_HEADER_SIZE + MAX_HEIGHT * 4);
+};
+
+// new_node allocates a node holding key and v, and returns its offset.
+// The tower is all zero, the caller links the node in.
+fn new_node(area: &Area, key: Vec<u8>, v: &Value, height: usize) -> Result<u32, StepDbError> {
+    let node_offset = area.put_node(height)?;
+    let key_offset = area.put_key(key.clone())?;
+    let val = put_value(area, v)?;
+    area.init_node(
+        node_offset,
+        key_offset,
+        key.len() as u16,
+        height as u16,
+        val,
+    );
+    Ok(node_offset)
 }
 
 pub struct SkipList {
     pub height: AtomicI32,
     pub head_offset: u32,
     pub area: Rc<Area>,
+    // a random u32 at or below p_threshold grows a tower by one level, p * u32::MAX
+    p_threshold: u32,
+    // values longer than the val_threshold of their entry go to vlog when it is set
+    vlog: Option<Rc<ValueLog>>,
+    // ttls are computed against clock
+    clock: Arc<dyn Clock>,
+    // range tombstones of delete_prefix as (prefix, watermark, offsets of the covered nodes
+    // written again since), sorted by prefix
+    prefix_tombstones: Vec<(Vec<u8>, u32, BTreeSet<u32>)>,
+    // offsets of the nodes given a value with an expiry, by expires_at rounded down to
+    // EXPIRY_BUCKET. A node updated since stays in its old bucket too, evict_expired
+    // checks its current value.
+    expiry_index: BTreeMap<u64, Vec<u32>>,
+    // get unlinks the tombstones it finds, see set_read_repair
+    read_repair: bool,
+    // the order of the keys, byte order or compare_keys for a versioned list unless given
+    // to new_skip_list_with_comparator
+    cmp: Rc<KeyOrder>,
+    // keys carry a version suffix, see new_versioned_skip_list
+    versioned: bool,
 }
 
-fn new_skip_list(area_size: u32) -> Box<SkipList> {
-    let mut ret = Box::new(SkipList {
+// Comparator orders the keys of a list, see new_skip_list_with_comparator.
+pub type Comparator = Box<KeyOrder>;
+pub type KeyOrder = dyn Fn(&[u8], &[u8]) -> Ordering;
+
+/// new_skip_list creates an empty list of plain keys, compared and matched byte by byte,
+/// whose nodes, keys and values live in an arena of
+/// area_size bytes. The arena doesn't grow, add returns ArenaFull past its end, so size it for
+/// everything the list will ever hold:
+///
+///   area_size >= entries * (NODE_OVERHEAD + average key len + average value len)
+///
+/// NODE_OVERHEAD covers a node with the tallest tower, its alignment and the value header,
+/// so this is an upper bound. An overwritten value takes a new slot unless the old one can
+/// be reused, leave some room for updates.
+///
+/// ```
+/// use step_db::memory::entry::new_entry;
+/// use step_db::memory::skiplist::{new_skip_list, NODE_OVERHEAD};
+///
+/// // room for 1000 entries with keys and values of up to 16 bytes
+/// let mut list = new_skip_list(1000 * (NODE_OVERHEAD + 16 + 16) as u32);
+/// list.add(new_entry(b"key", b"value")).unwrap();
+/// assert_eq!(b"value".to_vec(), list.search(b"key").v);
+/// assert!(list.search(b"missing").v.is_empty());
+/// ```
+///
+/// It panics if area_size is smaller than MIN_ARENA_SIZE, SkipList::try_new returns an
+/// error instead.
+pub fn new_skip_list(area_size: u32) -> SkipList {
+    new_skip_list_with_p(area_size, DEFAULT_P)
+}
+
+// new_versioned_skip_list creates an empty list of keys built by key_with_ts, see
+// memory::keys. Keys are ordered by compare_keys, a lookup matches any version of its
+// user key and prefixes apply to the user key.
+pub fn new_versioned_skip_list(area_size: u32) -> SkipList {
+    let mut list = new_skip_list(area_size);
+    list.set_versioned();
+    list
+}
+
+// new_skip_list_with_comparator creates an empty list of plain keys that keeps them in
+// the order of cmp instead of byte order. scan_prefix relies on all keys with a prefix being
+// next to each other, which only holds for the default order.
+pub fn new_skip_list_with_comparator(area_size: u32, cmp: Comparator) -> SkipList {
+    let mut list = new_skip_list(area_size);
+    list.cmp = Rc::from(cmp);
+    list
+}
+
+// new_skip_list_with_p creates a skip list whose towers grow one level with probability p.
+// A higher p makes taller towers, which costs memory but shortens searches.
+pub(crate) fn new_skip_list_with_p(area_size: u32, p: f64) -> SkipList {
+    assert!(p > 0.0 && p < 1.0, "p must be in (0, 1)");
+    assert!(
+        area_size as usize >= MIN_ARENA_SIZE,
+        "arena of {} bytes is smaller than MIN_ARENA_SIZE",
+        area_size
+    );
+    new_skip_list_with_area(Area::new(area_size), p).expect("MIN_ARENA_SIZE holds the head node")
+}
+
+// new_skip_list_in creates an empty versioned list in area, an arena from an ArenaPool.
+// It returns ArenaFull if the arena can't hold the head node.
+pub(crate) fn new_skip_list_in(area: Area) -> Result<SkipList, StepDbError> {
+    let mut list = new_skip_list_with_area(area, DEFAULT_P)?;
+    list.set_versioned();
+    Ok(list)
+}
+
+fn new_skip_list_with_area(area: Area, p: f64) -> Result<SkipList, StepDbError> {
+    let mut ret = SkipList {
         height: AtomicI32::new(1),
-        area: Rc::new(Area::new(area_size)),
+        area: Rc::new(area),
         head_offset: 0,
-    });
-    {
-        // let area_tmp = Rc::clone(&ret.area);
-        let v = Value::default();
-        let head = new_node(ret.area.deref(), vec![], &v, MAX_HEIGHT);
-
-        ret.head_offset = ret.area.deref().get_node_offset(Rc::clone(&head).as_ref());
-    }
-    ret
+        p_threshold: (u32::MAX as f64 * p) as u32,
+        vlog: None,
+        clock: Arc::new(SystemClock),
+        prefix_tombstones: Vec::new(),
+        expiry_index: BTreeMap::new(),
+        read_repair: false,
+        cmp: Rc::new(|a: &[u8], b: &[u8]| a.cmp(b)),
+        versioned: false,
+    };
+    let v = Value::default();
+    ret.head_offset = new_node(&ret.area, vec![], &v, MAX_HEIGHT)?;
+    Ok(ret)
 }
 
 impl SkipList {
-    fn add(&mut self, e: Entry) {
+    // new creates an empty list whose arena holds area_size bytes, see new_skip_list.
+    pub fn new(area_size: u32) -> SkipList {
+        new_skip_list(area_size)
+    }
+
+    // try_new is new returning ArenaFull instead of panicking when area_size is smaller
+    // than MIN_ARENA_SIZE.
+    pub fn try_new(area_size: u32) -> Result<SkipList, StepDbError> {
+        if (area_size as usize) < MIN_ARENA_SIZE {
+            return Err(StepDbError::ArenaFull);
+        }
+        new_skip_list_with_area(Area::new(area_size), DEFAULT_P)
+    }
+
+    // add inserts e, or updates the value of its key. It returns an error for an entry that
+    // can't be added: KeyTooLarge for a key longer than MAX_KEY_SIZE, ValueTooLarge for a
+    // value whose encoding is longer than MAX_VALUE_SIZE, ArenaFull when the arena has no
+    // room left for it. The list is unchanged then.
+    pub fn add(&mut self, e: Entry) -> Result<(), StepDbError> {
+        self.add_with_hint(e, None)
+    }
+
+    // add_versioned adds e as version of its key: the key gets the version as timestamp,
+    // so every version is a node of its own and the older ones stay readable. A version
+    // already in the list is never overwritten, false is returned for it. Errors are the
+    // ones of add.
+    pub fn add_versioned(&mut self, mut e: Entry, version: u64) -> Result<bool, StepDbError> {
+        e.key = key_with_ts(&e.key, version);
+        // contains matches any version of the key, only this exact one counts here.
+        let (_, exact) = self.find_near(&e.key, false, true);
+        if exact {
+            return Ok(false);
+        }
+        self.add(e)?;
+        Ok(true)
+    }
+
+    // add_batch sorts the entries by key and inserts them in order. While keys are strictly
+    // increasing, the splice found for the previous key is reused as the starting point of
+    // the next search, so consecutive inserts don't re-walk the tower from the head. It
+    // stops at the first entry add refuses and returns its error, the entries before it
+    // stay in the list.
+    pub fn add_batch(
+        &mut self,
+        entries: impl IntoIterator<Item = Entry>,
+    ) -> Result<(), StepDbError> {
+        let mut entries: Vec<Entry> = entries.into_iter().collect();
+        // Stable sort, so for duplicate keys the last one in the batch wins, same as `add`.
+        entries.sort_by(|a, b| self.compare(&a.key, &b.key));
+
+        let mut hint = [0u32; MAX_HEIGHT + 1];
+        let mut last: Option<Vec<u8>> = None;
+        for e in entries {
+            let monotonic = last
+                .as_ref()
+                .is_some_and(|k| self.compare(k, &e.key).is_lt());
+            if !monotonic {
+                // Fall back to a plain insertion that searches from the head.
+                hint = [0u32; MAX_HEIGHT + 1];
+            }
+            last = Some(e.key.clone());
+            self.add_with_hint(e, Some(&mut hint))?;
+        }
+        Ok(())
+    }
+
+    // add_with_hint inserts e. If hint is given, a non-zero hint[i] is a node on level i whose key
+    // is smaller than e.key, and is used as the start of the search on that level. On return the
+    // hint is updated to the splice of e.key, so it can be reused for a bigger key.
+    fn add_with_hint(
+        &mut self,
+        e: Entry,
+        hint: Option<&mut [u32; MAX_HEIGHT + 1]>,
+    ) -> Result<(), StepDbError> {
+        if e.key.len() > MAX_KEY_SIZE {
+            return Err(StepDbError::KeyTooLarge(e.key.len()));
+        }
+        let mut v = Value::from(&e);
+        if let Some(vlog) = &self.vlog {
+            if e.val_threshold > 0 && e.value.len() as i64 > e.val_threshold {
+                v.v = (vlog.write)(&e.value).encode();
+                v.meta |= BIT_VALUE_POINTER;
+            }
+        }
+        if v.encoded_size() > MAX_VALUE_SIZE {
+            return Err(StepDbError::ValueTooLarge(v.encoded_size()));
+        }
         let key = e.key;
-        let v = Value {
-            meta: e.meta,
-            v: e.value,
-            expires_at: e.expires_at,
-            version: e.version,
-        };
         let list_height = self.height.load(Relaxed);
         let mut prev = [0u32; MAX_HEIGHT + 1];
         let mut next = [0u32; MAX_HEIGHT + 1];
@@ -90,18 +337,18 @@ impl SkipList {
 
         for i in (0..list_height).rev() {
             // Use higher level to speed up for current level.
-            (prev[i as usize], next[i as usize]) =
-                self.find_splice_for_level(&key, prev[(i + 1) as usize], i);
+            let before = match hint.as_deref() {
+                Some(h) if h[i as usize] != 0 => h[i as usize],
+                _ => prev[(i + 1) as usize],
+            };
+            (prev[i as usize], next[i as usize]) = self.find_splice(&key, before, i);
             if prev[i as usize] == next[i as usize] {
-                let vo = area_tmp.put_value(&v);
-                let enc_value = encode_value(vo, v.encoded_size() as u32);
-                let prev_node = area_tmp.get_node_mut(prev[i as usize]).unwrap();
-                prev_node.set_value(enc_value);
-                return;
+                return self.update_node(prev[i as usize], &key, &v);
             }
         }
-        let height = random_height();
-        let mut x = new_node(area_tmp.as_ref(), key.clone(), &v, height);
+        let height = random_height(self.p_threshold);
+        let x_offset = new_node(&area_tmp, key.clone(), &v, height)?;
+        let x = area_tmp.get_node(x_offset).unwrap();
 
         let mut list_height = self.get_height();
         while height > list_height as usize {
@@ -121,19 +368,15 @@ impl SkipList {
                     assert!(i > 1); // This cannot happen in base level.
                                     // We haven't computed prev, next for this level because height exceeds old listHeight.
                                     // For these levels, we expect the lists to be sparse, so we can just search from head.
-                    (prev[i], next[i]) =
-                        self.find_splice_for_level(&key, self.head_offset, i as i32);
+                    (prev[i], next[i]) = self.find_splice(&key, self.head_offset, i as i32);
                     // Someone adds the exact same key before we are able to do so. This can only happen on
                     // the base level. But we know we are not on the base level.
                     assert_ne!(prev[i], next[i]);
                 }
-                {
-                    let x_m = Rc::get_mut(&mut x).unwrap();
-                    x_m.tower[i] = AtomicU32::from(next[i]);
-                }
+                x.tower[i].store(next[i], Relaxed);
                 if let Some(pnode) = area_tmp.get_node(prev[i]) {
                     if pnode.tower[i]
-                        .compare_exchange(next[i], area_tmp.get_node_offset(&x), Acquire, Relaxed)
+                        .compare_exchange(next[i], x_offset, Acquire, Relaxed)
                         .is_ok()
                     {
                         // Managed to insert x between prev[i] and next[i]. Go to the next level.
@@ -143,65 +386,199 @@ impl SkipList {
                 // CAS failed. We need to recompute prev and next.
                 // It is unlikely to be helpful to try to use a different level as we redo the search,
                 // because it is unlikely that lots of nodes are inserted between prev[i] and next[i].
-                (prev[i], next[i]) = self.find_splice_for_level(&key, prev[i], i as i32);
+                (prev[i], next[i]) = self.find_splice(&key, prev[i], i as i32);
                 if prev[i] == next[i] {
                     assert_eq!(i, 0);
-                    let vo = area_tmp.put_value(&v);
-                    let enc_value = encode_value(vo, v.encoded_size() as u32);
-                    if let Some(prev_node) = area_tmp.get_node(prev[i]) {
-                        prev_node.set_value(enc_value);
+                    return self.update_node(prev[i], &key, &v);
+                }
+            }
+        }
+        if let Some(h) = hint {
+            for (i, slot) in h.iter_mut().enumerate().take(MAX_HEIGHT) {
+                *slot = if i < height { x_offset } else { prev[i] };
+            }
+        }
+        self.index_expiry(x_offset, v.expires_at);
+        Ok(())
+    }
+
+    // update_node writes v to the existing node of key at node_offset. The node is taken
+    // out of the prefix tombstones covering it, the write is newer than the delete.
+    fn update_node(&mut self, node_offset: u32, key: &[u8], v: &Value) -> Result<(), StepDbError> {
+        self.update_value(node_offset, v)?;
+        self.index_expiry(node_offset, v.expires_at);
+        let key = self.user_key(key);
+        for (prefix, watermark, revived) in self.prefix_tombstones.iter_mut() {
+            if node_offset < *watermark && key.starts_with(prefix) {
+                revived.insert(node_offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn index_expiry(&mut self, node_offset: u32, expires_at: u64) {
+        if expires_at == 0 {
+            return;
+        }
+        let bucket = expires_at - expires_at % EXPIRY_BUCKET;
+        self.expiry_index
+            .entry(bucket)
+            .or_default()
+            .push(node_offset);
+    }
+    // update_value overwrites the value of an existing node, the slot of the old value
+    // is handed back to the arena to be reused by later values.
+    fn update_value(&self, node_offset: u32, v: &Value) -> Result<(), StepDbError> {
+        let enc_value = put_value(&self.area, v)?;
+        if let Some(node) = self.area.get_node(node_offset) {
+            let old = node.value.swap(enc_value, Relaxed);
+            if old & INLINE_VALUE == 0 {
+                let (old_offset, old_size) = decode_value(old);
+                self.area.free_value(old_offset, old_size);
+            }
+        }
+        Ok(())
+    }
+
+    // evict_expired replaces every value expired at now with a tombstone and returns how
+    // many it replaced. The slots of the expired values go back to the arena, the nodes
+    // stay until the list is flushed and compacted. Only the nodes in the buckets of the
+    // expiry index starting at or before now are visited, not the whole list.
+    pub fn evict_expired(&mut self, now: u64) -> usize {
+        let pending = self.expiry_index.split_off(&now.saturating_add(1));
+        let due = std::mem::replace(&mut self.expiry_index, pending);
+        let mut count = 0;
+        for (bucket, offsets) in due {
+            for offset in offsets {
+                let Some(n) = self.area.get_node(offset) else {
+                    continue;
+                };
+                let v = self.stored_value(n);
+                if v.expires_at == 0 || v.meta & BIT_DELETE != 0 {
+                    continue;
+                }
+                if v.expires_at > now {
+                    // the bucket of now is only partly due, and a node updated to a
+                    // later expiry is in a later bucket already
+                    if v.expires_at - v.expires_at % EXPIRY_BUCKET == bucket {
+                        self.index_expiry(offset, v.expires_at);
                     }
-                    return;
+                    continue;
                 }
+                // a tombstone is inlined, it takes no room in the arena
+                self.update_value(offset, &Value::builder().meta(BIT_DELETE).build())
+                    .expect("a tombstone is inlined");
+                count += 1;
             }
         }
+        count
     }
+
     // findSpliceForLevel returns (outBefore, outAfter) with outBefore.key <= key <= outAfter.key.
     // The input "before" tells us where to start looking.
     // If we found a node with the same key, then we return outBefore = outAfter.
     // Otherwise, outBefore.key < key < outAfter.key.
-    fn find_splice_for_level(&self, key: &[u8], before: u32, level: i32) -> (u32, u32) {
+    // It returns None if before is not a node.
+    fn find_splice_for_level(&self, key: &[u8], before: u32, level: i32) -> Option<(u32, u32)> {
         let area_tmp = Rc::clone(&self.area);
         let mut before = before;
         loop {
             // Assume before.key < key.
-            let next = area_tmp.get_node(before).unwrap().get_next_offset(level);
+            let next = area_tmp.get_node(before)?.get_next_offset(level);
 
             let next_node = area_tmp.get_node(next);
             if next_node.is_none() {
-                return (before, next);
+                return Some((before, next));
             }
             let next_node = next_node.unwrap();
-            let key_offset = next_node.key_offset;
-            let key_size = next_node.key_size;
-            let next_key = area_tmp.get_key(key_offset, key_size);
-            let cmp = compare_keys(key, &next_key);
-            if cmp == 0 {
+            let cmp = self.compare_node(key, next_node);
+            if cmp.is_eq() {
                 // Equality case.
-                return (next, next);
+                return Some((next, next));
             }
-            if cmp < 0 {
+            if cmp.is_lt() {
                 // before.key < key < next.key. We are done for this level.
-                return (before, next);
+                return Some((before, next));
             }
             before = next; // Keep moving right on this level.
         }
     }
+
+    // find_splice is find_splice_for_level starting over from the head when before is not
+    // a node, e.g. an unset 0 offset from a level above the list height.
+    fn find_splice(&self, key: &[u8], before: u32, level: i32) -> (u32, u32) {
+        self.find_splice_for_level(key, before, level)
+            .or_else(|| self.find_splice_for_level(key, self.head_offset, level))
+            .expect("the head is always a node")
+    }
 }
 
 impl SkipList {
-    pub fn find_near(
+    pub fn find_near(&self, key: &[u8], less: bool, allow_equal: bool) -> (Option<&Node>, bool) {
+        self.find_near_below(key, less, allow_equal, u32::MAX)
+    }
+
+    // find_greater_or_equal returns the first entry with a key >= key, the ceiling of key.
+    pub fn find_greater_or_equal(&self, key: &[u8]) -> Option<Entry> {
+        self.find_entry(key, false, true)
+    }
+
+    // find_greater returns the first entry with a key > key.
+    pub fn find_greater(&self, key: &[u8]) -> Option<Entry> {
+        self.find_entry(key, false, false)
+    }
+
+    // find_less_or_equal returns the last entry with a key <= key, the floor of key.
+    pub fn find_less_or_equal(&self, key: &[u8]) -> Option<Entry> {
+        self.find_entry(key, true, true)
+    }
+
+    // find_less returns the last entry with a key < key.
+    pub fn find_less(&self, key: &[u8]) -> Option<Entry> {
+        self.find_entry(key, true, false)
+    }
+
+    // find_last returns the last node of the list, None if the list is empty.
+    pub fn find_last(&self) -> Option<&Node> {
+        let head = self.get_head()?;
+        let mut x = head;
+        for level in (0..self.get_height()).rev() {
+            while let Some(next) = self.get_next(x, level) {
+                x = next;
+            }
+        }
+        if std::ptr::eq(x, head) {
+            return None;
+        }
+        Some(x)
+    }
+
+    // key_range returns the smallest and the largest key of the list, None if it is empty.
+    pub fn key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let first = self.get_next(self.get_head()?, 0)?;
+        let last = self.find_last()?;
+        let key = |n: &Node| self.area.get_key(n.key_offset, n.key_size);
+        Some((key(first), key(last)))
+    }
+
+    fn find_entry(&self, key: &[u8], less: bool, allow_equal: bool) -> Option<Entry> {
+        let (n, _) = self.find_near(key, less, allow_equal);
+        n.map(|n| iterator::entry_of(self, n))
+    }
+
+    // find_near_below is find_near ignoring every node at or above the watermark offset.
+    fn find_near_below(
         &self,
         key: &[u8],
         less: bool,
         allow_equal: bool,
-    ) -> (Option<Rc<&Node>>, bool) {
+        watermark: u32,
+    ) -> (Option<&Node>, bool) {
         let mut x = self.get_head().unwrap();
         let mut level = (self.get_height() - 1) as i32;
-        let area_tmp = Rc::clone(&self.area);
         loop {
             // Assume x.key < key.
-            let next = self.get_next(x.deref(), level);
+            let next = self.get_next_below(x, level, watermark);
             if next.is_none() {
                 // x.key < key < END OF LIST
                 if level > 0 {
@@ -214,28 +591,26 @@ impl SkipList {
                     return (None, false);
                 }
                 // Try to return x. Make sure it is not a head node.
-                if x.key_offset.eq(&self.get_head().unwrap().key_offset) {
+                if std::ptr::eq(x, self.get_head().unwrap()) {
                     return (None, false);
                 }
                 return (Some(x), false);
             }
             let next = next.unwrap();
-            println!("next node:{:?}", next);
-            let next_key = area_tmp.get_key(next.key_offset, next.key_size);
-            let cmp = compare_keys(key, &next_key);
-            if cmp > 0 {
+            let cmp = self.compare_node(key, next);
+            if cmp.is_gt() {
                 // x.key < next.key < key. We can continue to move right.
                 x = next;
                 continue;
             }
-            if cmp == 0 {
+            if cmp.is_eq() {
                 // x.key < key == next.key.
                 if allow_equal {
                     return (Some(next), true);
                 }
                 if !less {
                     // We want >, so go to base level to grab the next bigger note.
-                    return (self.get_next(Rc::clone(&next).as_ref(), 0), false);
+                    return (self.get_next_below(next, 0, watermark), false);
                 }
                 // We want <. If not base level, we should go closer in the next level.
                 if level > 0 {
@@ -243,7 +618,7 @@ impl SkipList {
                     continue;
                 }
                 // On base level. Return x.
-                if x.key_offset.eq(&self.get_head().unwrap().key_offset) {
+                if std::ptr::eq(x, self.get_head().unwrap()) {
                     return (None, false);
                 }
                 return (Some(x), false);
@@ -258,7 +633,7 @@ impl SkipList {
                 return (Some(next), false);
             }
             // Try to return x. Make sure it is not a head node.
-            if x.key_offset.eq(&self.get_head().unwrap().key_offset) {
+            if std::ptr::eq(x, self.get_head().unwrap()) {
                 return (None, false);
             }
             return (Some(x), false);
@@ -266,30 +641,246 @@ impl SkipList {
     }
 
     pub fn search(&self, key: &[u8]) -> Value {
+        self.get(key).unwrap_or_default()
+    }
+
+    // get is search telling an absent key apart from an empty value.
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.get_below(key, u32::MAX)
+    }
+
+    fn get_below(&self, key: &[u8], watermark: u32) -> Option<Value> {
         let area_tmp = Rc::clone(&self.area);
+        let (n, _) = self.find_near_below(key, false, true, watermark); // findGreaterOrEqual.
+        let n = n?;
+        let next_key = area_tmp.get_key(n.key_offset, n.key_size);
+        if !self.same_key(key, &next_key) || self.deleted_by_prefix(n, &next_key) {
+            return None;
+        }
+
+        let v = self.get_value(n);
+        // a snapshot reads below a watermark and never repairs
+        if self.read_repair && watermark == u32::MAX && v.meta & BIT_DELETE != 0 {
+            self.unlink(n);
+        }
+        Some(v)
+    }
+
+    // unlink takes n out of every level of its tower and hands its value slot back to the
+    // arena. The node itself stays in the arena, so a reader standing on it still finds
+    // its way on through its tower. Each level is swapped with a CAS on the predecessor,
+    // like insert links a node in: if the predecessor changed meanwhile, the level is
+    // searched again. add takes &mut self, so no insert into this list runs during a get.
+    fn unlink(&self, n: &Node) {
+        let offset = self.area.offset_of(n);
+        let key = self.area.key_bytes(n.key_offset, n.key_size);
+        for level in (0..n.height as i32).rev() {
+            let mut prev = self.head_offset;
+            while let Some(p) = self.area.get_node(prev) {
+                let next = p.get_next_offset(level);
+                if next == offset {
+                    let after = n.get_next_offset(level);
+                    if p.tower[level as usize]
+                        .compare_exchange(offset, after, Acquire, Relaxed)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                    continue;
+                }
+                match self.area.get_node(next) {
+                    Some(next_node) if self.compare_node(key, next_node).is_gt() => prev = next,
+                    // n is not linked on this level, or was unlinked already
+                    _ => break,
+                }
+            }
+        }
+        // a bare tombstone is inlined, one stored in a slot hands the slot back
+        self.update_value(offset, &Value::builder().meta(BIT_DELETE).build())
+            .expect("a tombstone is inlined");
+    }
+
+    // with_value calls f with the value of key borrowed from the arena, it is get without
+    // copying the value out. The slice lives only as long as the call, &self keeps the list
+    // from being written meanwhile. A value pointer is still resolved through the value log
+    // into a buffer of its own, and an inlined value is read from the node word.
+    pub fn with_value<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Option<R> {
         let (n, _) = self.find_near(key, false, true); // findGreaterOrEqual.
+        let n = n?;
+        let next_key = self.area.key_bytes(n.key_offset, n.key_size);
+        if !self.same_key(key, next_key) || self.deleted_by_prefix(n, next_key) {
+            return None;
+        }
+
+        let word = n.value.load(Relaxed);
+        if word & INLINE_VALUE != 0 {
+            let len = ((word >> 56) & 0x7f) as usize;
+            let bytes = word.to_le_bytes();
+            return Some(f(&bytes[..len]));
+        }
+        let (val_offset, val_size) = decode_value(word);
+        let buf = self.area.value_bytes(val_offset, val_size);
+        match &self.vlog {
+            Some(vlog) if buf[0] & BIT_VALUE_POINTER != 0 => {
+                let vp = ValuePointer::decode(Value::value_bytes(buf).expect("value in the arena"));
+                Some(f(&(vlog.resolve_value)(&vp)))
+            }
+            _ => Some(f(Value::value_bytes(buf).expect("value in the arena"))),
+        }
+    }
+
+    // search_with_ttl is get along with how long the value lives on, the ttl is None for
+    // a value that never expires.
+    pub fn search_with_ttl(&self, key: &[u8]) -> Option<(Value, Option<Duration>)> {
+        let v = self.get(key)?;
+        let ttl = v.ttl_at(self.clock.now_unix());
+        Some((v, ttl))
+    }
+
+    // contains reports whether key is in the list without copying its value out.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let (n, _) = self.find_near(key, false, true); // findGreaterOrEqual.
+        n.is_some_and(|n| {
+            let next_key = self.area.get_key(n.key_offset, n.key_size);
+            self.same_key(key, &next_key) && !self.deleted_by_prefix(n, &next_key)
+        })
+    }
+
+    // delete_prefix deletes every key whose user key starts with prefix, with a single
+    // range tombstone instead of a tombstone per key. Reads and valid_entries skip the
+    // covered keys, the nodes stay until the list is flushed and compacted. Only the nodes
+    // present now are covered, keys written later under prefix are visible again, also
+    // when the write updates the old node in place.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) {
+        let tombstone = (prefix.to_vec(), self.area.size() as u32, BTreeSet::new());
+        let tombstones = &mut self.prefix_tombstones;
+        match tombstones.binary_search_by(|(p, _, _)| p.as_slice().cmp(prefix)) {
+            Ok(i) => tombstones[i] = tombstone,
+            Err(i) => tombstones.insert(i, tombstone),
+        }
+    }
+
+    // deleted_by_prefix reports whether n, holding key, is covered by a range tombstone.
+    pub(crate) fn deleted_by_prefix(&self, n: &Node, key: &[u8]) -> bool {
+        if self.prefix_tombstones.is_empty() {
+            return false;
+        }
+        let offset = self.area.offset_of(n);
+        let key = self.user_key(key);
+        self.prefix_tombstones
+            .iter()
+            .any(|(prefix, watermark, revived)| {
+                offset < *watermark && key.starts_with(prefix) && !revived.contains(&offset)
+            })
+    }
+
+    // into_area returns the arena of the list to be reused, None while a snapshot still
+    // shares it.
+    pub(crate) fn into_area(self) -> Option<Area> {
+        Rc::try_unwrap(self.area).ok()
+    }
+
+    // mem_size is the number of arena bytes in use.
+    pub fn mem_size(&self) -> i64 {
+        self.area.size()
+    }
+
+    // search_at returns the newest version of key with version <= read_ts, on a versioned
+    // list. Newer versions sort first, so the first node >= key@read_ts
+    // is the one we want if it still belongs to key.
+    pub fn search_at(&self, key: &[u8], read_ts: u64) -> Value {
+        let seek = key_with_ts(key, read_ts);
+        let (n, _) = self.find_near(&seek, false, true); // findGreaterOrEqual.
         if n.is_none() {
             return Value::default();
         }
         let n = n.unwrap();
-        let next_key = area_tmp.get_key(n.key_offset, n.key_size);
-        if !same_key(key, &next_key) {
+        let next_key = self.area.get_key(n.key_offset, n.key_size);
+        if !self.same_key(&seek, &next_key) || self.deleted_by_prefix(n, &next_key) {
             return Value::default();
         }
 
-        let (val_offset, val_size) = n.get_value_offset();
-        area_tmp.get_value(val_offset, val_size)
+        let mut v = self.get_value(n);
+        v.version = parse_ts(&next_key);
+        v
     }
 }
 
 impl SkipList {
-    pub fn get_next(&self, node: &Node, height: i32) -> Option<Rc<&Node>> {
-        let offset = node.get_next_offset(height);
-        println!("next offset:{},height:{}", offset, height);
+    pub fn get_next(&self, node: &Node, height: i32) -> Option<&Node> {
+        self.area.get_node(node.get_next_offset(height))
+    }
+
+    // get_next_below returns the next node on the level, skipping the nodes at or above the
+    // watermark offset. Those were linked in after the watermark was taken, so their own
+    // tower leads back to the nodes that were there before.
+    pub(crate) fn get_next_below(&self, node: &Node, height: i32, watermark: u32) -> Option<&Node> {
+        let mut offset = node.get_next_offset(height);
+        while offset >= watermark {
+            offset = self.area.get_node(offset)?.get_next_offset(height);
+        }
         self.area.get_node(offset)
     }
 
-    pub fn get_head(&self) -> Option<Rc<&Node>> {
+    // snapshot returns a read-only view of the list as it is now, nodes added later are
+    // not visible through it. Values updated in place after the snapshot are visible.
+    pub fn snapshot(&self) -> SkipListSnapshot {
+        SkipListSnapshot {
+            l: SkipList {
+                height: AtomicI32::new(self.get_height()),
+                head_offset: self.head_offset,
+                area: Rc::clone(&self.area),
+                p_threshold: self.p_threshold,
+                vlog: self.vlog.clone(),
+                clock: Arc::clone(&self.clock),
+                prefix_tombstones: self.prefix_tombstones.clone(),
+                // a snapshot is read-only, it never sweeps
+                expiry_index: BTreeMap::new(),
+                read_repair: false,
+                cmp: Rc::clone(&self.cmp),
+                versioned: self.versioned,
+            },
+            watermark: self.area.size() as u32,
+        }
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        (self.cmp)(a, b)
+    }
+
+    // set_versioned makes an empty list take versioned keys.
+    fn set_versioned(&mut self) {
+        self.versioned = true;
+        self.cmp = Rc::new(|a: &[u8], b: &[u8]| compare_keys(a, b).cmp(&0));
+    }
+
+    // same_key reports whether a and b are the same key: the same user key on a versioned
+    // list, equal keys otherwise.
+    fn same_key(&self, a: &[u8], b: &[u8]) -> bool {
+        if self.versioned {
+            same_key(a, b)
+        } else {
+            self.compare(a, b).is_eq()
+        }
+    }
+
+    // user_key is key without its version suffix on a versioned list, key itself otherwise.
+    fn user_key<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if self.versioned {
+            parse_key(key)
+        } else {
+            key
+        }
+    }
+
+    // compare_node is compare(key, key of n) without copying the key of n out of the arena.
+    fn compare_node(&self, key: &[u8], n: &Node) -> Ordering {
+        self.area
+            .compare_key_at(n.key_offset, n.key_size, key, &*self.cmp)
+            .reverse()
+    }
+
+    pub fn get_head(&self) -> Option<&Node> {
         self.area.get_node(self.head_offset)
     }
 
@@ -297,13 +888,184 @@ impl SkipList {
         self.height.load(Relaxed)
     }
 
+    // get_value returns the value of n, a value pointer is resolved through the value log.
     pub fn get_value(&self, n: &Node) -> Value {
-        let (val_offset, val_size) = n.get_value_offset();
+        let mut v = self.stored_value(n);
+        if let Some(vlog) = &self.vlog {
+            if v.meta & BIT_VALUE_POINTER != 0 {
+                v.v = (vlog.resolve_value)(&ValuePointer::decode(&v.v));
+                v.meta &= !BIT_VALUE_POINTER;
+            }
+        }
+        v
+    }
+
+    // stored_value returns the value of n as it is stored, a value pointer is not resolved.
+    fn stored_value(&self, n: &Node) -> Value {
+        let word = n.value.load(Relaxed);
+        if word & INLINE_VALUE != 0 {
+            return decode_inline(word);
+        }
+        let (val_offset, val_size) = decode_value(word);
         self.area.get_value(val_offset, val_size)
     }
-    pub fn iter(&self) -> SkipListIter {
+
+    // set_value_log makes add keep values longer than the entry's val_threshold in vlog,
+    // only a ValuePointer is stored in the arena.
+    pub fn set_value_log(&mut self, vlog: ValueLog) {
+        self.vlog = Some(Rc::new(vlog));
+    }
+
+    // set_read_repair makes get unlink a tombstone it finds for the key it looks up, so
+    // later searches don't walk past it. Only turn it on for a list that is the only
+    // store of its keys: in a memtable a tombstone hides the older versions of its key
+    // in the sstables, dropping it brings them back.
+    pub fn set_read_repair(&mut self, on: bool) {
+        self.read_repair = on;
+    }
+
+    // set_clock replaces the system clock the ttls of search_with_ttl are computed with.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+    pub fn iter(&self) -> SkipListIter<'_> {
         return iterator::new(self);
     }
+
+    // stable_iter yields the entries present now in key order, without the head node.
+    // It takes a snapshot and doesn't borrow the list, keys added while it runs are not
+    // yielded and don't make it skip or repeat a key. Values updated in place are seen
+    // as they are when their node is reached.
+    pub fn stable_iter(&self) -> StableIter {
+        self.snapshot().into_iter()
+    }
+
+    // range yields the entries between start and end in key order. Reversed bounds
+    // yield nothing.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = Entry> + 'a {
+        // Seek to the node before the first one to yield.
+        let n = match start {
+            Bound::Included(k) => self.find_near(k, true, false).0, // findLess.
+            Bound::Excluded(k) => self.find_near(k, true, true).0,  // findLessOrEqual.
+            Bound::Unbounded => None,
+        };
+        let n = n.unwrap_or_else(|| self.get_head().unwrap());
+        iterator::new_after(self, n).take_while(move |e| match end {
+            Bound::Included(k) => self.compare(&e.key, k).is_le(),
+            Bound::Excluded(k) => self.compare(&e.key, k).is_lt(),
+            Bound::Unbounded => true,
+        })
+    }
+
+    // scan_prefix yields the entries whose key starts with prefix, in key order. On a
+    // versioned list the version suffix is not part of the key that is matched.
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = Entry> + 'a {
+        // The smallest suffix sorts the seek key before every version of every key with prefix.
+        let seek = if self.versioned {
+            key_with_ts(prefix, u64::MAX)
+        } else {
+            prefix.to_vec()
+        };
+        let (n, _) = self.find_near(&seek, true, false); // findLess.
+        let n = n.unwrap_or_else(|| self.get_head().unwrap());
+        iterator::new_after(self, n).take_while(move |e| self.user_key(&e.key).starts_with(prefix))
+    }
+
+    // dump returns the keys reachable on every level, one line per level from the top:
+    //
+    // 2: b
+    // 1: a b d
+    // 0: a b c d
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        let head = self.get_head().unwrap();
+        for level in (0..self.get_height()).rev() {
+            out.push_str(&level.to_string());
+            out.push(':');
+            let mut n = self.get_next(head, level);
+            while let Some(x) = n {
+                let key = self.area.get_key(x.key_offset, x.key_size);
+                out.push(' ');
+                out.push_str(&String::from_utf8_lossy(&key));
+                n = self.get_next(x, level);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+// SkipListSnapshot sees only the nodes allocated before the watermark. The arena is
+// append-only for nodes, so those are exactly the nodes present when it was taken.
+// It shares the arena with the list it was taken from, so writes can go on meanwhile.
+pub struct SkipListSnapshot {
+    pub(crate) l: SkipList,
+    watermark: u32,
+}
+
+impl SkipListSnapshot {
+    pub fn search(&self, key: &[u8]) -> Value {
+        self.l.get_below(key, self.watermark).unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> SkipListIter<'_> {
+        iterator::new_below(&self.l, self.watermark)
+    }
+
+    // first_offset is the offset of the first node after the head, 0 if there is none.
+    pub(crate) fn first_offset(&self) -> u32 {
+        self.next_offset(self.l.get_head().unwrap())
+    }
+
+    // next_offset is the offset of the node following n on level 0, 0 at the end.
+    pub(crate) fn next_offset(&self, n: &Node) -> u32 {
+        self.l
+            .get_next_below(n, 0, self.watermark)
+            .map_or(0, |x| self.l.area.offset_of(x))
+    }
+}
+
+// INLINE_VALUE in the value word of a node marks a value kept in the word itself rather
+// than in the arena:
+//
+// | 1 | len 7 bits | meta u8 | up to INLINE_VALUE_SIZE value bytes, the first one lowest |
+//
+// A value is inlined if it has no expiry and at most INLINE_VALUE_SIZE bytes. Otherwise the
+// word holds | val_size u32 | val_offset u32 |, val_size stays below 1 << 31 so the top bit
+// tells the two apart.
+const INLINE_VALUE: u64 = 1 << 63;
+pub const INLINE_VALUE_SIZE: usize = 6;
+
+// put_value returns the value word of v, v is put in the arena unless it can be inlined.
+fn put_value(area: &Area, v: &Value) -> Result<u64, StepDbError> {
+    match encode_inline(v) {
+        Some(word) => Ok(word),
+        None => Ok(encode_value(area.put_value(v)?, v.encoded_size() as u32)),
+    }
+}
+
+fn encode_inline(v: &Value) -> Option<u64> {
+    if v.expires_at != 0 || v.v.len() > INLINE_VALUE_SIZE {
+        return None;
+    }
+    let mut word = INLINE_VALUE | (v.v.len() as u64) << 56 | u64::from(v.meta) << 48;
+    for (i, b) in v.v.iter().enumerate() {
+        word |= u64::from(*b) << (8 * i);
+    }
+    Some(word)
+}
+
+fn decode_inline(word: u64) -> Value {
+    let len = ((word >> 56) & 0x7f) as usize;
+    Value {
+        meta: (word >> 48) as u8,
+        v: (0..len).map(|i| (word >> (8 * i)) as u8).collect(),
+        ..Default::default()
+    }
 }
 
 fn encode_value(val_offset: u32, val_size: u32) -> u64 {
@@ -316,43 +1078,36 @@ fn decode_value(value: u64) -> (u32, u32) {
     (val_offset, val_size)
 }
 
-// ParseKey parses the actual key from the key bytes.
-fn parse_key(key: &[u8]) -> &[u8] {
-    if key.len() < 8 {
-        key
-    } else {
-        &key[..key.len() - 8]
+// A collected list gets an arena just big enough for its entries, so it is meant to be
+// read rather than to take many more writes.
+impl FromIterator<Entry> for SkipList {
+    fn from_iter<I: IntoIterator<Item = Entry>>(iter: I) -> SkipList {
+        let entries: Vec<Entry> = iter.into_iter().collect();
+        let size = MIN_ARENA_SIZE as i64 + entries.iter().map(estimate_size).sum::<i64>();
+        let size = u32::try_from(size).expect("entries don't fit in an arena");
+        let mut l = SkipList::new(size);
+        l.add_batch(entries).expect("entries don't fit in an arena");
+        l
     }
 }
 
-// ParseTs parses the timestamp from the key bytes.
-fn parse_ts(key: &[u8]) -> u64 {
-    if key.len() <= 8 {
-        0
-    } else {
-        u64::MAX - u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap())
-    }
-}
+// NODE_OVERHEAD is the most arena bytes an entry takes besides its key and value: a node
+// with the padding for alignment, the meta byte and the expires_at varint.
+pub const NODE_OVERHEAD: usize = std::mem::size_of::<Node>() + 8 + 1 + 10;
 
-// SameKey checks for key equality ignoring the version timestamp suffix.
-fn same_key(src: &[u8], dst: &[u8]) -> bool {
-    if src.len() != dst.len() {
-        return false;
-    }
-    parse_key(src) == parse_key(dst)
-}
+// MIN_ARENA_SIZE is the smallest arena a list can be created in, it holds the head node
+// alone: the byte at offset 0, which is never handed out, and the tallest node with the
+// padding for its alignment. An arena of MIN_ARENA_SIZE + estimate_size(e) holds e.
+pub const MIN_ARENA_SIZE: usize = 1 + std::mem::size_of::<Node>() + 7;
 
-// KeyWithTs generates a new key by appending ts to key.
-fn key_with_ts(key: &[u8], ts: u64) -> Vec<u8> {
-    let mut out = Vec::with_capacity(key.len() + 8);
-    out.extend_from_slice(key);
-    out.extend_from_slice(&(u64::MAX - ts).to_be_bytes());
-    out
+// estimate_size is an upper bound of the arena bytes add(e) takes.
+pub(crate) fn estimate_size(e: &Entry) -> i64 {
+    (NODE_OVERHEAD + e.key.len() + e.value.len()) as i64
 }
 
-fn random_height() -> usize {
+fn random_height(p_threshold: u32) -> usize {
     let mut h = 1;
-    while h < MAX_HEIGHT && random::<u32>() <= u32::MAX / 3 {
+    while h < MAX_HEIGHT && random::<u32>() <= p_threshold {
         h += 1;
     }
     h
@@ -360,9 +1115,27 @@ fn random_height() -> usize {
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::entry::new_entry;
-    use crate::memory::skiplist::new_skip_list;
+    use crate::error::StepDbError;
+    use crate::memory::clock::{Clock, MockClock};
+    use crate::memory::entry::{
+        new_entry, Entry, Value, ValueLog, ValuePointer, BIT_DELETE, BIT_VALUE_POINTER,
+    };
+    use crate::memory::keys::{key_with_ts, parse_key, parse_ts};
+    use crate::memory::skiplist::{
+        estimate_size, new_skip_list, new_skip_list_with_comparator, new_skip_list_with_p,
+        new_versioned_skip_list, Node, SkipList, EXPIRY_BUCKET, INLINE_VALUE_SIZE, MAX_HEIGHT,
+        MAX_KEY_SIZE, MIN_ARENA_SIZE,
+    };
+    use crate::memory::utils::compare_keys;
     use rand::Rng;
+    use std::cell::{Cell, RefCell};
+    use std::ops::Bound;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::rc::Rc;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::Arc;
+    use std::time::Duration;
 
     fn gen_key(len: usize) -> String {
         let mut rng = rand::thread_rng();
@@ -380,14 +1153,14 @@ mod tests {
         let k1 = gen_key(10);
         let v1 = "111111";
         let entry1 = new_entry(k1.as_bytes(), v1.as_bytes());
-        list.add(entry1);
+        list.add(entry1).unwrap();
         let value = list.search(k1.as_bytes());
         assert_eq!(*v1.as_bytes(), value.v);
 
         let k2 = gen_key(10);
         let v2 = "222222";
         let entry2 = new_entry(k2.as_bytes(), v2.as_bytes());
-        list.add(entry2);
+        list.add(entry2).unwrap();
         let value = list.search(k1.as_bytes());
 
         assert_eq!(*v1.as_bytes(), value.v);
@@ -396,23 +1169,996 @@ mod tests {
         println!("{:?}", list.area.get_buf());
     }
 
+    #[test]
+    fn test_add_batch() {
+        let n = 10000;
+        let entries =
+            |i: usize| new_entry(format!("key{:06}", i).as_bytes(), i.to_string().as_bytes());
+
+        let mut batch = new_skip_list(1 << 22);
+        batch.add_batch((0..n).map(entries)).unwrap();
+        let mut single = new_skip_list(1 << 22);
+        for i in 0..n {
+            single.add(entries(i)).unwrap();
+        }
+
+        for i in 0..n {
+            let value = batch.search(format!("key{:06}", i).as_bytes());
+            assert_eq!(i.to_string().as_bytes(), value.v);
+        }
+        let batch_items: Vec<_> = batch.iter().map(|e| (e.key, e.value)).collect();
+        let single_items: Vec<_> = single.iter().map(|e| (e.key, e.value)).collect();
+        assert_eq!(n + 1, batch_items.len());
+        assert_eq!(single_items, batch_items);
+    }
+
+    #[test]
+    fn test_update_reuses_value_slots() {
+        let mut list = new_skip_list(1 << 20);
+        let k = gen_key(10);
+        for i in 0..10 {
+            list.add(new_entry(k.as_bytes(), format!("{:06}", i).as_bytes()))
+                .unwrap();
+        }
+        let used = list.area.size();
+        for i in 10..10000 {
+            list.add(new_entry(k.as_bytes(), format!("{:06}", i).as_bytes()))
+                .unwrap();
+        }
+        assert_eq!(used, list.area.size());
+        assert_eq!(b"009999".to_vec(), list.search(k.as_bytes()).v);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut list = new_skip_list(10000);
+        let keys: Vec<_> = (0..10)
+            .map(|_| key_with_ts(gen_key(10).as_bytes(), 1))
+            .collect();
+        for k in keys[..5].iter() {
+            list.add(new_entry(k, k)).unwrap();
+        }
+        let old: Vec<_> = list.iter().map(|e| e.key).collect();
+
+        let snapshot = list.snapshot();
+        for k in keys[5..].iter() {
+            list.add(new_entry(k, k)).unwrap();
+        }
+
+        for k in keys[..5].iter() {
+            assert_eq!(*k, snapshot.search(k).v);
+        }
+        for k in keys[5..].iter() {
+            assert!(snapshot.search(k).v.is_empty());
+            assert_eq!(*k, list.search(k).v);
+        }
+        assert_eq!(old, snapshot.iter().map(|e| e.key).collect::<Vec<_>>());
+        assert_eq!(11, list.iter().count());
+    }
+
+    #[test]
+    fn test_stable_iter() {
+        let mut list = new_skip_list(1 << 16);
+        let keys: Vec<_> = (0..20)
+            .map(|i| key_with_ts(format!("key{:02}", i * 2).as_bytes(), 1))
+            .collect();
+        for k in keys.iter() {
+            list.add(new_entry(k, k)).unwrap();
+        }
+
+        let mut it = list.stable_iter();
+        let mut got: Vec<_> = it.by_ref().take(5).map(|e| e.key).collect();
+        // keys before, between and after the ones left to yield
+        for i in 0..41 {
+            if i % 2 == 1 || i == 40 {
+                let k = key_with_ts(format!("key{:02}", i).as_bytes(), 1);
+                list.add(new_entry(&k, &k)).unwrap();
+            }
+        }
+        got.extend(it.map(|e| e.key));
+        assert_eq!(keys, got);
+        assert_eq!(41, list.stable_iter().count());
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut list = new_skip_list(1 << 16);
+        let keys: Vec<_> = (0..20)
+            .map(|i| key_with_ts(format!("key{:02}", i).as_bytes(), 1))
+            .collect();
+        for k in keys.iter().step_by(2) {
+            list.add(new_entry(k, b"")).unwrap();
+        }
+        for k in keys.iter() {
+            assert_eq!(list.get(k).is_some(), list.contains(k));
+        }
+        assert!(list.contains(&keys[0]));
+        assert!(!list.contains(&keys[1]));
+        assert!(!list.contains(&key_with_ts(b"key99", 1)));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut list = new_skip_list(1 << 16);
+        let mut keys: Vec<_> = (0..20).map(|_| gen_key(10)).collect();
+        for k in keys.iter() {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        keys.sort();
+        keys.dedup();
+
+        let dump = list.dump();
+        let levels: Vec<Vec<&str>> = dump
+            .lines()
+            .map(|l| l.split(' ').skip(1).collect())
+            .collect();
+        assert_eq!(list.get_height() as usize, levels.len());
+        assert_eq!(keys, *levels.last().unwrap());
+        for w in levels.windows(2) {
+            assert!(w[0].iter().all(|k| w[1].contains(k)));
+        }
+    }
+
+    #[test]
+    fn test_find_neighbors() {
+        let mut list = new_skip_list(1 << 16);
+        let key = |k: &str| key_with_ts(k.as_bytes(), 1);
+        // keys of an empty list have no neighbors
+        assert!(list.find_greater_or_equal(&key("k10")).is_none());
+        assert!(list.find_less_or_equal(&key("k10")).is_none());
+        for k in ["k10", "k20", "k30"] {
+            list.add(new_entry(&key(k), k.as_bytes())).unwrap();
+        }
+        let found = |e: Option<Entry>| e.map(|e| e.value);
+
+        // at a key
+        let k = key("k20");
+        assert_eq!(Some(b"k20".to_vec()), found(list.find_greater_or_equal(&k)));
+        assert_eq!(Some(b"k30".to_vec()), found(list.find_greater(&k)));
+        assert_eq!(Some(b"k20".to_vec()), found(list.find_less_or_equal(&k)));
+        assert_eq!(Some(b"k10".to_vec()), found(list.find_less(&k)));
+        // between keys
+        let k = key("k15");
+        assert_eq!(Some(b"k20".to_vec()), found(list.find_greater_or_equal(&k)));
+        assert_eq!(Some(b"k20".to_vec()), found(list.find_greater(&k)));
+        assert_eq!(Some(b"k10".to_vec()), found(list.find_less_or_equal(&k)));
+        assert_eq!(Some(b"k10".to_vec()), found(list.find_less(&k)));
+        // at and beyond the ends
+        assert_eq!(None, found(list.find_less(&key("k10"))));
+        assert_eq!(None, found(list.find_less_or_equal(&key("k05"))));
+        assert_eq!(Some(b"k10".to_vec()), found(list.find_greater(&key("k05"))));
+        assert_eq!(None, found(list.find_greater(&key("k30"))));
+        assert_eq!(None, found(list.find_greater_or_equal(&key("k35"))));
+        assert_eq!(Some(b"k30".to_vec()), found(list.find_less(&key("k35"))));
+    }
+
+    #[test]
+    fn test_add_versioned() {
+        let mut list = new_versioned_skip_list(1 << 16);
+        for version in [2, 1, 3] {
+            let v = format!("v{}", version);
+            assert!(list
+                .add_versioned(new_entry(b"key", v.as_bytes()), version)
+                .unwrap());
+        }
+        assert!(!list.add_versioned(new_entry(b"key", b"again"), 2).unwrap());
+        list.add_versioned(new_entry(b"other", b"o"), 1).unwrap();
+
+        // every version is a node of its own, the newest first
+        let versions: Vec<_> = list
+            .iter()
+            .skip(1)
+            .filter(|e| parse_key(&e.key) == b"key")
+            .map(|e| (parse_ts(&e.key), e.value))
+            .collect();
+        assert_eq!(
+            vec![
+                (3, b"v3".to_vec()),
+                (2, b"v2".to_vec()),
+                (1, b"v1".to_vec())
+            ],
+            versions
+        );
+        assert_eq!(
+            b"v3".to_vec(),
+            list.search(&key_with_ts(b"key", u64::MAX)).v
+        );
+        assert_eq!(b"v2".to_vec(), list.search(&key_with_ts(b"key", 2)).v);
+    }
+
+    #[test]
+    fn test_find_splice_from_no_node() {
+        let list = new_skip_list(1 << 16);
+        let key = key_with_ts(b"k", 1);
+        assert!(list.find_splice_for_level(&key, 0, 3).is_none());
+        let head = list.head_offset;
+        assert_eq!(Some((head, 0)), list.find_splice_for_level(&key, head, 3));
+        assert_eq!((head, 0), list.find_splice(&key, 0, 3));
+
+        // with p close to 1 nearly every node is taller than the list, the levels above
+        // the list height have no splice yet and are searched from the head
+        let mut list = new_skip_list_with_p(1 << 20, 0.95);
+        for i in 0..100u32 {
+            list.add(new_entry(
+                &key_with_ts(&i.to_be_bytes(), 1),
+                &i.to_be_bytes(),
+            ))
+            .unwrap();
+        }
+        assert!(list.get_height() > 1);
+        for i in 0..100u32 {
+            let v = list.search(&key_with_ts(&i.to_be_bytes(), 1)).v;
+            assert_eq!(i.to_be_bytes().to_vec(), v);
+        }
+        list.add(new_entry(&key, b"v")).unwrap();
+        assert_eq!(b"v".to_vec(), list.search(&key).v);
+    }
+
+    #[test]
+    fn test_key_range() {
+        let mut list = new_skip_list(1 << 16);
+        assert!(list.key_range().is_none());
+        for k in ["k5", "k3", "k9", "k1"] {
+            list.add(new_entry(&key_with_ts(k.as_bytes(), 1), b""))
+                .unwrap();
+        }
+        assert_eq!(
+            Some((key_with_ts(b"k1", 1), key_with_ts(b"k9", 1))),
+            list.key_range()
+        );
+    }
+
+    #[test]
+    fn test_inline_value() {
+        let mut list = new_skip_list(1 << 16);
+        let key = key_with_ts(b"key", 1);
+        list.add(new_entry(&key, b"a value too long to be inlined"))
+            .unwrap();
+        let (n, _) = list.find_near(&key, false, true);
+        assert!(n.unwrap().get_value_offset().is_some());
+
+        // the arena doesn't grow for a value inlined in the node
+        let size = list.mem_size();
+        let mut e = new_entry(&key, b"abc");
+        e.meta = 0x4;
+        list.add(e).unwrap();
+        assert_eq!(size, list.mem_size());
+        let (n, _) = list.find_near(&key, false, true);
+        assert!(n.unwrap().get_value_offset().is_none());
+        let v = list.search(&key);
+        assert_eq!((0x4, b"abc".to_vec()), (v.meta, v.v));
+
+        for v in [&b""[..], &[0xff; INLINE_VALUE_SIZE]] {
+            list.add(new_entry(&key, v)).unwrap();
+            assert_eq!(v, list.search(&key).v);
+        }
+        // a value with an expiry is not inlined
+        let mut e = new_entry(&key, b"abc");
+        e.expires_at = 100;
+        list.add(e).unwrap();
+        let (n, _) = list.find_near(&key, false, true);
+        assert!(n.unwrap().get_value_offset().is_some());
+        assert_eq!(100, list.search(&key).expires_at);
+    }
+
+    #[test]
+    fn test_with_value() {
+        let mut list = new_skip_list(1 << 16);
+        let long = key_with_ts(b"long", 1);
+        let short = key_with_ts(b"short", 1);
+        list.add(new_entry(&long, b"a value too long to be inlined"))
+            .unwrap();
+        list.add(new_entry(&short, b"abc")).unwrap();
+
+        let arena = list.area.get_buf().as_ptr_range();
+        let in_arena = list
+            .with_value(&long, |v| {
+                assert_eq!(list.search(&long).v, v);
+                arena.contains(&v.as_ptr())
+            })
+            .unwrap();
+        // the value is read in place, not copied out of the arena
+        assert!(in_arena);
+        assert_eq!(
+            Some(list.search(&short).v),
+            list.with_value(&short, |v| v.to_vec())
+        );
+        assert!(list.with_value(b"absent", |_| ()).is_none());
+    }
+
+    #[test]
+    fn test_comparator() {
+        let mut list = new_skip_list_with_comparator(1 << 16, Box::new(|a, b| b.cmp(a)));
+        for k in ["b", "d", "a", "c"] {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        list.add_batch(["f", "e"].map(|k| new_entry(k.as_bytes(), k.as_bytes())))
+            .unwrap();
+        let keys: Vec<_> = list.iter().skip(1).map(|e| e.key).collect();
+        assert_eq!(
+            ["f", "e", "d", "c", "b", "a"]
+                .map(|k| k.as_bytes().to_vec())
+                .to_vec(),
+            keys
+        );
+        assert_eq!(b"c".to_vec(), list.search(b"c").v);
+        let keys: Vec<_> = list
+            .range(Included(&b"e"[..]), Excluded(&b"b"[..]))
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(vec![b"e".to_vec(), b"d".to_vec(), b"c".to_vec()], keys);
+    }
+
+    #[test]
+    fn test_short_and_binary_keys() {
+        let mut list = new_versioned_skip_list(1 << 16);
+        let keys: Vec<Vec<u8>> = vec![
+            b"".to_vec(),
+            b"a".to_vec(),
+            b"\0".to_vec(),
+            b"a\0b".to_vec(),
+            key_with_ts(b"", 1),
+            key_with_ts(b"\0\0", 1),
+            key_with_ts(b"a", 1),
+            b"abcdefgh".to_vec(),
+            vec![0; 9],
+        ];
+        for (i, k) in keys.iter().enumerate() {
+            list.add(new_entry(k, &[i as u8])).unwrap();
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(vec![i as u8], list.search(k).v, "{:?}", k);
+            assert!(list.contains(k), "{:?}", k);
+        }
+        for k in [&b"b"[..], b"\0\0", b"a\0", b"\0\0\0\0\0\0\0"] {
+            assert!(list.get(k).is_none(), "{:?}", k);
+        }
+        // the empty key is an entry of its own, not the head
+        let e = list.find_less_or_equal(b"").unwrap();
+        assert_eq!((b"".to_vec(), vec![0]), (e.key, e.value));
+
+        let mut sorted = keys.clone();
+        sorted.sort_by(|a, b| compare_keys(a, b).cmp(&0));
+        let listed: Vec<_> = list.iter().valid_entries().map(|e| e.key).collect();
+        assert_eq!(sorted, listed);
+    }
+
+    #[test]
+    fn test_plain_long_keys() {
+        let mut list = new_skip_list(1 << 16);
+        for (k, v) in [("key00004", "k4"), ("user:0001", "u1"), ("abcdefgh", "a")] {
+            list.add(new_entry(k.as_bytes(), v.as_bytes())).unwrap();
+        }
+        for (k, v) in [("key00004", "k4"), ("user:0001", "u1"), ("abcdefgh", "a")] {
+            assert_eq!(v.as_bytes(), list.get(k.as_bytes()).unwrap().v);
+            assert!(list.contains(k.as_bytes()));
+        }
+        // absent keys of the same length next to present ones
+        for k in ["key00003", "key00005", "user:0000", "user:0002", "abcdefgi"] {
+            assert!(list.get(k.as_bytes()).is_none(), "{}", k);
+            assert!(!list.contains(k.as_bytes()), "{}", k);
+            assert!(list.with_value(k.as_bytes(), |_| ()).is_none(), "{}", k);
+        }
+        // plain keys are in byte order
+        let keys: Vec<_> = list.iter().valid_entries().map(|e| e.key).collect();
+        assert_eq!(
+            vec![
+                b"abcdefgh".to_vec(),
+                b"key00004".to_vec(),
+                b"user:0001".to_vec()
+            ],
+            keys
+        );
+    }
+
+    #[test]
+    fn test_key_too_long() {
+        let mut list = new_skip_list(1 << 20);
+        let long = vec![b'k'; MAX_KEY_SIZE + 1];
+        assert_eq!(
+            Err(StepDbError::KeyTooLarge(MAX_KEY_SIZE + 1)),
+            list.add(new_entry(&long, b"v"))
+        );
+        // nothing was added, not even under a truncated key
+        assert!(list.iter().nth(1).is_none());
+
+        let longest = vec![b'k'; MAX_KEY_SIZE];
+        list.add(new_entry(&longest, b"v")).unwrap();
+        assert_eq!(b"v".to_vec(), list.search(&longest).v);
+        assert!(list.get(&long).is_none());
+    }
+
+    #[test]
+    fn test_add_batch_error() {
+        let mut list = new_skip_list(1 << 20);
+        let batch = [b"a".to_vec(), vec![b'k'; MAX_KEY_SIZE + 1], b"z".to_vec()];
+        assert_eq!(
+            Err(StepDbError::KeyTooLarge(MAX_KEY_SIZE + 1)),
+            list.add_batch(batch.iter().map(|k| new_entry(k, b"v")))
+        );
+        // the batch stops at the refused entry
+        assert!(list.contains(b"a"));
+        assert!(!list.contains(b"z"));
+    }
+
+    #[test]
+    fn test_arena_full() {
+        let mut list = new_skip_list(1 << 10);
+        let mut added = 0;
+        let err = loop {
+            let key = format!("key{:04}", added);
+            match list.add(new_entry(key.as_bytes(), &[b'v'; 64])) {
+                Ok(()) => added += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(StepDbError::ArenaFull, err);
+        assert!(added > 0);
+        // the entries added before the arena filled up are all there
+        for i in 0..added {
+            let key = format!("key{:04}", i);
+            assert_eq!(vec![b'v'; 64], list.search(key.as_bytes()).v);
+        }
+    }
+
+    #[test]
+    fn test_min_arena_size() {
+        let mut list = new_skip_list(MIN_ARENA_SIZE as u32);
+        assert!(list.get(b"k").is_none());
+        assert_eq!(Err(StepDbError::ArenaFull), list.add(new_entry(b"k", b"v")));
+
+        let e = new_entry(b"key", b"a value too long to be inlined");
+        let mut list = new_skip_list((MIN_ARENA_SIZE as i64 + estimate_size(&e)) as u32);
+        list.add(e).unwrap();
+        assert_eq!(
+            b"a value too long to be inlined".to_vec(),
+            list.search(b"key").v
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "smaller than MIN_ARENA_SIZE")]
+    fn test_arena_too_small() {
+        new_skip_list(MIN_ARENA_SIZE as u32 - 1);
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(
+            Some(StepDbError::ArenaFull),
+            SkipList::try_new(MIN_ARENA_SIZE as u32 - 1).err()
+        );
+        let mut list = SkipList::try_new(MIN_ARENA_SIZE as u32 + 64).unwrap();
+        list.add(new_entry(b"k", b"v")).unwrap();
+        assert_eq!(b"v".to_vec(), list.search(b"k").v);
+    }
+
+    #[test]
+    fn test_read_repair() {
+        let tombstone = |k: &[u8]| {
+            let mut e = new_entry(k, b"a value with a slot of its own");
+            e.meta = BIT_DELETE;
+            e
+        };
+        // tall towers, so unlinking has several levels to go through
+        let mut list = new_skip_list_with_p(1 << 20, 0.9);
+        for i in 0..100u32 {
+            list.add(new_entry(format!("key{:03}", i).as_bytes(), b"v"))
+                .unwrap();
+        }
+        list.add(tombstone(b"key042")).unwrap();
+        // off by default, the tombstone stays
+        assert_eq!(BIT_DELETE, list.search(b"key042").meta);
+        assert!(list.contains(b"key042"));
+
+        list.set_read_repair(true);
+        let (n, _) = list.find_near(b"key042", false, true);
+        let (val_offset, _) = n.unwrap().get_value_offset().unwrap();
+        for _ in 0..3 {
+            list.search(b"key042");
+        }
+        assert!(!list.contains(b"key042"));
+        assert!(list.get(b"key042").is_none());
+        // the slot of the tombstone value went back to the arena
+        assert_eq!(
+            val_offset,
+            list.area
+                .put_value(&Value::builder().value(&[0; 30]).build())
+                .unwrap()
+        );
+
+        // every other key is still linked on every level
+        let keys: Vec<_> = list.iter().skip(1).map(|e| e.key).collect();
+        assert_eq!(99, keys.len());
+        assert!(keys.iter().all(|k| k != b"key042"));
+        for level in 0..list.get_height() {
+            let mut n = list.get_next(list.get_head().unwrap(), level);
+            while let Some(x) = n {
+                assert_ne!(
+                    b"key042".to_vec(),
+                    list.area.get_key(x.key_offset, x.key_size)
+                );
+                n = list.get_next(x, level);
+            }
+        }
+        for i in (0..100u32).filter(|i| *i != 42) {
+            assert_eq!(
+                b"v".to_vec(),
+                list.search(format!("key{:03}", i).as_bytes()).v
+            );
+        }
+        // a key added back after the repair is found again
+        list.add(new_entry(b"key042", b"back")).unwrap();
+        assert_eq!(b"back".to_vec(), list.search(b"key042").v);
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let clock = MockClock::new(1_000_000);
+        let mut list = new_skip_list(1 << 16);
+        let ttls = [("a", 0), ("b", 10), ("c", 100), ("d", 10), ("e", 0)];
+        for (k, ttl) in ttls {
+            let mut e = new_entry(k.as_bytes(), k.as_bytes());
+            if ttl > 0 {
+                e.expires_at = clock.now_unix() + ttl;
+            }
+            list.add(e).unwrap();
+        }
+        assert_eq!(0, list.evict_expired(clock.now_unix()));
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(2, list.evict_expired(clock.now_unix()));
+        for (k, ttl) in ttls {
+            let v = list.search(k.as_bytes());
+            if ttl == 10 {
+                assert_eq!(BIT_DELETE, v.meta, "{}", k);
+                assert!(v.v.is_empty());
+            } else {
+                assert_eq!(0, v.meta, "{}", k);
+                assert_eq!(k.as_bytes(), v.v);
+            }
+        }
+        // tombstones are not counted again
+        assert_eq!(0, list.evict_expired(clock.now_unix()));
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(1, list.evict_expired(clock.now_unix()));
+    }
+
+    #[test]
+    fn test_expiry_buckets() {
+        let base = 1_000_020;
+        assert_eq!(0, base % EXPIRY_BUCKET);
+        let mut list = new_skip_list(1 << 16);
+        let expiries = [("a", 10), ("b", 70), ("c", 75), ("d", 130), ("e", 0)];
+        for (k, after) in expiries {
+            let mut e = new_entry(k.as_bytes(), k.as_bytes());
+            if after > 0 {
+                e.expires_at = base + after;
+            }
+            list.add(e).unwrap();
+        }
+        let buckets = |list: &SkipList| -> Vec<(u64, usize)> {
+            list.expiry_index
+                .iter()
+                .map(|(&b, offsets)| (b - base, offsets.len()))
+                .collect()
+        };
+        assert_eq!(vec![(0, 1), (60, 2), (120, 1)], buckets(&list));
+
+        // the bucket of now is only partly due, what hasn't expired in it stays
+        assert_eq!(2, list.evict_expired(base + 72));
+        assert_eq!(vec![(60, 1), (120, 1)], buckets(&list));
+        assert_eq!(BIT_DELETE, list.search(b"a").meta);
+        assert_eq!(BIT_DELETE, list.search(b"b").meta);
+        assert_eq!(b"c".to_vec(), list.search(b"c").v);
+
+        // moving d to a later bucket leaves it in the old one until a sweep passes there
+        let mut e = new_entry(b"d", b"d");
+        e.expires_at = base + 200;
+        list.add(e).unwrap();
+        assert_eq!(vec![(60, 1), (120, 1), (180, 1)], buckets(&list));
+        assert_eq!(1, list.evict_expired(base + 150));
+        assert_eq!(vec![(180, 1)], buckets(&list));
+        assert_eq!(b"d".to_vec(), list.search(b"d").v);
+        assert_eq!(1, list.evict_expired(base + 200));
+        assert!(list.expiry_index.is_empty());
+        assert_eq!(b"e".to_vec(), list.search(b"e").v);
+
+        // the last bucket of the clock
+        let mut e = new_entry(b"f", b"f");
+        e.expires_at = u64::MAX;
+        list.add(e).unwrap();
+        assert_eq!(0, list.evict_expired(u64::MAX - 1));
+        assert_eq!(1, list.expiry_index.len());
+        assert_eq!(1, list.evict_expired(u64::MAX));
+        assert!(list.expiry_index.is_empty());
+        assert_eq!(BIT_DELETE, list.search(b"f").meta);
+    }
+
+    #[test]
+    fn test_search_with_ttl() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let mut list = new_skip_list(1 << 16);
+        list.set_clock(clock.clone());
+        let k1 = key_with_ts(b"k1", 1);
+        let k2 = key_with_ts(b"k2", 1);
+        let mut e = new_entry(&k1, b"v1");
+        e.expires_at = Value::with_ttl_at(b"", Duration::from_secs(100), 1_000_000).expires_at;
+        list.add(e).unwrap();
+        list.add(new_entry(&k2, b"v2")).unwrap();
+
+        let (v, ttl) = list.search_with_ttl(&k1).unwrap();
+        assert_eq!(b"v1".to_vec(), v.v);
+        assert_eq!(Some(Duration::from_secs(100)), ttl);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            Some(Duration::from_secs(70)),
+            list.search_with_ttl(&k1).unwrap().1
+        );
+        let (v, ttl) = list.search_with_ttl(&k2).unwrap();
+        assert_eq!(b"v2".to_vec(), v.v);
+        assert_eq!(None, ttl);
+        assert!(list.search_with_ttl(&key_with_ts(b"k3", 1)).is_none());
+    }
+
+    #[test]
+    fn test_value_log() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let resolved = Rc::new(Cell::new(0));
+        let mut list = new_skip_list(1 << 16);
+        let (w, r, n) = (Rc::clone(&log), Rc::clone(&log), Rc::clone(&resolved));
+        list.set_value_log(ValueLog {
+            write: Box::new(move |v| {
+                let mut log = w.borrow_mut();
+                let vp = ValuePointer {
+                    file_id: 1,
+                    offset: log.len() as u32,
+                    len: v.len() as u32,
+                };
+                log.extend_from_slice(v);
+                vp
+            }),
+            resolve_value: Box::new(move |vp| {
+                n.set(n.get() + 1);
+                let start = vp.offset as usize;
+                r.borrow()[start..start + vp.len as usize].to_vec()
+            }),
+        });
+
+        let big = key_with_ts(b"big", 1);
+        let small = key_with_ts(b"small", 1);
+        let mut e = new_entry(&big, &[7; 100]);
+        e.val_threshold = 32;
+        list.add(e).unwrap();
+        let mut e = new_entry(&small, &[8; 10]);
+        e.val_threshold = 32;
+        list.add(e).unwrap();
+        assert_eq!(100, log.borrow().len());
+
+        // the arena only holds the pointer
+        let (n, _) = list.find_near(&big, false, true);
+        let (offset, size) = n.unwrap().get_value_offset().unwrap();
+        let raw = list.area.get_value(offset, size);
+        assert_ne!(0, raw.meta & BIT_VALUE_POINTER);
+        assert_eq!(0, ValuePointer::decode(&raw.v).offset);
+
+        assert_eq!(vec![7; 100], list.search(&big).v);
+        assert_eq!(1, resolved.get());
+        assert_eq!(vec![8; 10], list.search(&small).v);
+        assert_eq!(1, resolved.get());
+    }
+
+    #[test]
+    fn test_branching_factor() {
+        let avg_height = |p: f64| {
+            let mut list = new_skip_list_with_p(1 << 20, p);
+            for i in 0..2000 {
+                let k = key_with_ts(format!("key{:05}", i).as_bytes(), 1);
+                list.add(new_entry(&k, b"")).unwrap();
+            }
+            let mut total = 0;
+            let mut n = list.get_next(list.get_head().unwrap(), 0);
+            while let Some(x) = n {
+                total += x.height as usize;
+                n = list.get_next(x, 0);
+            }
+            total as f64 / 2000.0
+        };
+        // the expected height is 1 / (1 - p)
+        let low = avg_height(0.1);
+        let high = avg_height(0.5);
+        assert!(low < 1.3, "{}", low);
+        assert!(high > 1.7, "{}", high);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let keys: Vec<_> = (0..500).map(|i| format!("key{:04}", i)).collect();
+        let list: SkipList = keys
+            .iter()
+            .rev()
+            .map(|k| new_entry(k.as_bytes(), k.as_bytes()))
+            .collect();
+        for k in keys.iter() {
+            assert_eq!(k.as_bytes(), list.search(k.as_bytes()).v);
+        }
+        assert!(list.search(b"key0500").v.is_empty());
+
+        let empty: SkipList = Vec::new().into_iter().collect();
+        assert_eq!(0, empty.into_iter().count());
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut list = new_skip_list(1 << 16);
+        let mut keys: Vec<_> = (0..100).map(|_| gen_key(10)).collect();
+        for k in keys.iter() {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        keys.sort();
+        keys.dedup();
+
+        let entries: Vec<_> = list.into_iter().collect();
+        assert_eq!(keys.len(), entries.len());
+        for (k, e) in keys.iter().zip(entries.iter()) {
+            assert_eq!(k.as_bytes(), e.key);
+            assert_eq!(k.as_bytes(), e.value);
+        }
+        assert_eq!(0, new_skip_list(1 << 10).into_iter().count());
+    }
+
+    #[test]
+    fn test_iterate_during_insert() {
+        let mut list = new_skip_list(1 << 16);
+        for i in (0..40).step_by(2) {
+            let k = key_with_ts(format!("key{:02}", i).as_bytes(), 1);
+            list.add(new_entry(&k, &k)).unwrap();
+        }
+        // the snapshot shares the arena, its iterator holds node references while the
+        // list links new nodes next to them
+        let snapshot = list.snapshot();
+        let mut n = 0;
+        for (i, e) in snapshot.iter().skip(1).enumerate() {
+            let k = key_with_ts(format!("key{:02}", i * 2 + 1).as_bytes(), 1);
+            list.add(new_entry(&k, &k)).unwrap();
+            assert_eq!(e.key, e.value);
+            n += 1;
+        }
+        assert_eq!(20, n);
+        assert_eq!(41, list.iter().count());
+    }
+
+    #[test]
+    fn test_delete_prefix() {
+        let mut list = new_skip_list(1 << 16);
+        for ns in ["user:", "item:"] {
+            for i in 0..5 {
+                let k = key_with_ts(format!("{}{}", ns, i).as_bytes(), 1);
+                list.add(new_entry(&k, b"v")).unwrap();
+            }
+        }
+        list.delete_prefix(b"user:");
+        let key = |ns: &str, i: i32| key_with_ts(format!("{}{}", ns, i).as_bytes(), 1);
+        for i in 0..5 {
+            assert!(list.get(&key("user:", i)).is_none());
+            assert!(!list.contains(&key("user:", i)));
+            assert!(list
+                .search_at(format!("user:{}", i).as_bytes(), 1)
+                .v
+                .is_empty());
+            assert_eq!(b"v".to_vec(), list.search(&key("item:", i)).v);
+        }
+        let live: Vec<_> = list.iter().valid_entries().map(|e| e.key).collect();
+        assert_eq!((0..5).map(|i| key("item:", i)).collect::<Vec<_>>(), live);
+
+        // a key added after the delete is visible
+        list.add(new_entry(&key_with_ts(b"user:0", 2), b"new"))
+            .unwrap();
+        assert_eq!(b"new".to_vec(), list.search_at(b"user:0", 2).v);
+        assert!(list.search_at(b"user:0", 1).v.is_empty());
+    }
+
+    #[test]
+    fn test_put_after_delete_prefix() {
+        let mut list = new_skip_list(1 << 16);
+        let key = |i: i32| key_with_ts(format!("user:{}", i).as_bytes(), 1);
+        for i in 0..5 {
+            list.add(new_entry(&key(i), b"before")).unwrap();
+        }
+        list.delete_prefix(b"user:");
+        // the same keys again, updating the covered nodes in place
+        list.add(new_entry(&key(0), b"after")).unwrap();
+        list.add_batch([new_entry(&key(1), b"after")]).unwrap();
+        for i in 0..2 {
+            assert_eq!(b"after".to_vec(), list.get(&key(i)).unwrap().v);
+            assert!(list.contains(&key(i)));
+        }
+        assert!(list.get(&key(2)).is_none());
+        let live: Vec<_> = list.iter().valid_entries().map(|e| e.key).collect();
+        assert_eq!(vec![key(0), key(1)], live);
+
+        // a later delete covers them again, a broader one too
+        list.delete_prefix(b"user:");
+        assert!(list.get(&key(0)).is_none());
+        list.add(new_entry(&key(0), b"again")).unwrap();
+        assert_eq!(b"again".to_vec(), list.get(&key(0)).unwrap().v);
+        list.delete_prefix(b"us");
+        assert!(list.get(&key(0)).is_none());
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut list = new_skip_list(10000);
+        for k in ["user:2", "other:1", "user:1", "zzz", "use"] {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        let keys: Vec<_> = list.scan_prefix(b"user:").map(|e| e.key).collect();
+        assert_eq!(vec![b"user:1".to_vec(), b"user:2".to_vec()], keys);
+        assert_eq!(1, list.scan_prefix(b"other").count());
+        assert_eq!(0, list.scan_prefix(b"none").count());
+        assert_eq!(5, list.scan_prefix(b"").count());
+
+        // plain keys of 8 bytes or more are matched whole
+        let mut list = new_skip_list(1 << 16);
+        for i in 1..=100 {
+            let k = format!("user:{}", i);
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        list.add(new_entry(b"user:0001", b"u1")).unwrap();
+        list.add(new_entry(b"users:00001", b"x")).unwrap();
+        assert_eq!(101, list.scan_prefix(b"user:").count());
+        assert_eq!(1, list.scan_prefix(b"user:0001").count());
+        assert_eq!(1, list.scan_prefix(b"user:100").count());
+        assert_eq!(12, list.scan_prefix(b"user:1").count());
+        assert_eq!(1, list.scan_prefix(b"users:0000").count());
+        assert_eq!(0, list.scan_prefix(b"user:0002").count());
+
+        let mut list = new_versioned_skip_list(10000);
+        for (k, ts) in [("user:1", 1), ("user:1", 2), ("user:22", 1), ("users", 1)] {
+            list.add(new_entry(&key_with_ts(k.as_bytes(), ts), k.as_bytes()))
+                .unwrap();
+        }
+        let values: Vec<_> = list.scan_prefix(b"user:").map(|e| e.value).collect();
+        assert_eq!(3, values.len());
+        assert!(values.iter().all(|v| v.starts_with(b"user:")));
+    }
+
+    #[test]
+    fn test_range() {
+        let mut list = new_skip_list(10000);
+        for k in ["k1", "k2", "k3", "k4", "k5"] {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        let range = |start: Bound<&[u8]>, end: Bound<&[u8]>| {
+            list.range(start, end)
+                .map(|e| String::from_utf8(e.key).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let (k2, k4): (&[u8], &[u8]) = (b"k2", b"k4");
+
+        assert_eq!(vec!["k2", "k3", "k4"], range(Included(k2), Included(k4)));
+        assert_eq!(vec!["k2", "k3"], range(Included(k2), Excluded(k4)));
+        assert_eq!(vec!["k3", "k4"], range(Excluded(k2), Included(k4)));
+        assert_eq!(vec!["k3"], range(Excluded(k2), Excluded(k4)));
+        assert_eq!(vec!["k1", "k2", "k3", "k4"], range(Unbounded, Included(k4)));
+        assert_eq!(vec!["k3", "k4", "k5"], range(Excluded(k2), Unbounded));
+        assert_eq!(5, range(Unbounded, Unbounded).len());
+        // bounds between keys
+        assert_eq!(vec!["k3"], range(Included(b"k25"), Excluded(b"k35")));
+        assert!(range(Excluded(b"k5"), Unbounded).is_empty());
+        assert!(range(Unbounded, Excluded(b"k1")).is_empty());
+        // reversed bounds
+        assert!(range(Included(k4), Included(k2)).is_empty());
+        assert!(range(Excluded(k2), Excluded(k2)).is_empty());
+    }
+
+    #[test]
+    fn test_search_at() {
+        let mut list = new_versioned_skip_list(10000);
+        let k = b"key1";
+        for (ts, v) in [(10, "v10"), (20, "v20"), (30, "v30")] {
+            list.add(new_entry(&key_with_ts(k, ts), v.as_bytes()))
+                .unwrap();
+        }
+
+        assert!(list.search_at(k, 5).v.is_empty());
+        for (read_ts, v, version) in [
+            (10, "v10", 10),
+            (15, "v10", 10),
+            (25, "v20", 20),
+            (99, "v30", 30),
+        ] {
+            let value = list.search_at(k, read_ts);
+            assert_eq!(v.as_bytes(), value.v);
+            assert_eq!(version, value.version);
+        }
+        assert!(list.search_at(b"key2", 99).v.is_empty());
+    }
+
+    #[test]
+    fn test_node_encode_le() {
+        let node = Node {
+            value: AtomicU64::new(0x0102030405060708),
+            key_offset: 0x0a0b0c0d,
+            key_size: 0x1122,
+            height: 3,
+            ..Default::default()
+        };
+        for (i, next) in [0x01020304u32, 0, u32::MAX].iter().enumerate() {
+            node.tower[i].store(*next, Relaxed);
+        }
+        let mut buf = vec![0; node.encoded_size_le()];
+        assert_eq!(16 + 3 * 4, node.encode_le(&mut buf));
+
+        // The bytes are little-endian whatever the host is.
+        assert_eq!([8, 7, 6, 5, 4, 3, 2, 1], buf[0..8]);
+        assert_eq!([0x0d, 0x0c, 0x0b, 0x0a], buf[8..12]);
+        assert_eq!([0x22, 0x11], buf[12..14]);
+        assert_eq!([3, 0], buf[14..16]);
+        assert_eq!([4, 3, 2, 1], buf[16..20]);
+
+        // A reader on a host of the other endianness would see every field byte-swapped
+        // if it read the bytes natively, decode_le always gets the original values back.
+        let native = u32::from_ne_bytes(buf[8..12].try_into().unwrap());
+        assert!(native == node.key_offset || native.swap_bytes() == node.key_offset);
+        let decoded = Node::decode_le(&buf);
+        assert_eq!(node.value.load(Relaxed), decoded.value.load(Relaxed));
+        assert_eq!(node.key_offset, decoded.key_offset);
+        assert_eq!(node.key_size, decoded.key_size);
+        assert_eq!(node.height, decoded.height);
+        for i in 0..MAX_HEIGHT {
+            assert_eq!(
+                node.get_next_offset(i as i32),
+                decoded.get_next_offset(i as i32)
+            );
+        }
+    }
+
+    #[test]
+    fn test_valid_entries() {
+        let mut list = new_skip_list(1 << 16);
+        for k in ["a", "b", "c", "d"] {
+            list.add(new_entry(k.as_bytes(), k.as_bytes())).unwrap();
+        }
+        let mut e = new_entry(b"b", b"");
+        e.meta = BIT_DELETE;
+        list.add(e).unwrap();
+
+        // iter starts with the head and keeps the tombstone
+        let all: Vec<_> = list.iter().map(|e| e.key).collect();
+        assert_eq!(
+            vec![
+                vec![],
+                b"a".to_vec(),
+                b"b".to_vec(),
+                b"c".to_vec(),
+                b"d".to_vec()
+            ],
+            all
+        );
+        let valid: Vec<_> = list.iter().valid_entries().map(|e| e.key).collect();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()], valid);
+
+        assert_eq!(0, new_skip_list(1 << 10).iter().valid_entries().count());
+    }
+
     #[test]
     fn test_iterator() {
         let mut list = new_skip_list(10000);
         let k1 = gen_key(10);
         let v1 = "111111";
         let entry1 = new_entry(k1.as_bytes(), v1.as_bytes());
-        list.add(entry1);
+        list.add(entry1).unwrap();
 
         let k2 = gen_key(10);
         let v2 = "222222";
         let entry1 = new_entry(k1.as_bytes(), v1.as_bytes());
-        list.add(entry1);
+        list.add(entry1).unwrap();
 
         let k3 = gen_key(10);
         let v3 = "333333";
         let entry1 = new_entry(k1.as_bytes(), v1.as_bytes());
-        list.add(entry1);
+        list.add(entry1).unwrap();
 
         for (i, e) in list.iter().enumerate() {
             match i {
@@ -0,0 +1,155 @@
+use crate::error::StepDbError;
+use crate::memory::entry::{new_entry, Entry, Value};
+use crate::memory::iterator;
+use crate::memory::keys::{key_with_ts, parse_key};
+use crate::memory::skiplist::{new_versioned_skip_list, Node, SkipList};
+
+// SortedMap is an ordered map over a SkipList. Its keys are plain keys, they are stored
+// with a fixed timestamp so the versioning of the list never mixes two of them up, and
+// the entries it returns carry the plain key again.
+pub struct SortedMap {
+    l: SkipList,
+    len: usize,
+}
+
+impl SortedMap {
+    // new creates an empty map whose arena holds area_size bytes.
+    pub fn new(area_size: u32) -> SortedMap {
+        SortedMap {
+            l: new_versioned_skip_list(area_size),
+            len: 0,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.l.get(&stored_key(key))
+    }
+
+    // insert sets key to value, replacing the value of a present key. It returns the
+    // error of SkipList::add for an entry the list refuses.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), StepDbError> {
+        let key = stored_key(key);
+        let present = self.l.contains(&key);
+        self.l.add(new_entry(&key, value))?;
+        if !present {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    // ceil returns the entry with the smallest key >= key.
+    pub fn ceil(&self, key: &[u8]) -> Option<Entry> {
+        self.l.find_greater_or_equal(&stored_key(key)).map(plain)
+    }
+
+    // floor returns the entry with the largest key <= key.
+    pub fn floor(&self, key: &[u8]) -> Option<Entry> {
+        self.l.find_less_or_equal(&stored_key(key)).map(plain)
+    }
+
+    pub fn first(&self) -> Option<Entry> {
+        let head = self.l.get_head()?;
+        self.l.get_next(head, 0).map(|n| self.entry(n))
+    }
+
+    pub fn last(&self) -> Option<Entry> {
+        self.l.find_last().map(|n| self.entry(n))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn entry(&self, n: &Node) -> Entry {
+        plain(iterator::entry_of(&self.l, n))
+    }
+}
+
+fn stored_key(key: &[u8]) -> Vec<u8> {
+    key_with_ts(key, 0)
+}
+
+// plain strips the timestamp stored_key added.
+fn plain(mut e: Entry) -> Entry {
+    e.key = parse_key(&e.key).to_vec();
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::entry::Entry;
+    use crate::memory::sorted_map::SortedMap;
+
+    #[test]
+    fn test_first_last() {
+        let mut m = SortedMap::new(1 << 16);
+        assert!(m.is_empty());
+        assert!(m.first().is_none());
+        assert!(m.last().is_none());
+
+        m.insert(b"b", b"2").unwrap();
+        assert_eq!(b"b".to_vec(), m.first().unwrap().key);
+        assert_eq!(b"b".to_vec(), m.last().unwrap().key);
+        for (k, v) in [(b"d", b"4"), (b"a", b"1"), (b"c", b"3")] {
+            m.insert(k, v).unwrap();
+        }
+        let first = m.first().unwrap();
+        assert_eq!((b"a".to_vec(), b"1".to_vec()), (first.key, first.value));
+        let last = m.last().unwrap();
+        assert_eq!((b"d".to_vec(), b"4".to_vec()), (last.key, last.value));
+        assert_eq!(4, m.len());
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        // plain keys of any length, 8 bytes and longer ones are not taken for versions
+        let keys: [&[u8]; 6] = [b"", b"\0", b"a", b"abcdefgh", b"abcdefgh\0", &[0; 9]];
+        let mut m = SortedMap::new(1 << 16);
+        for (i, k) in keys.iter().enumerate() {
+            m.insert(k, &[i as u8]).unwrap();
+        }
+        assert_eq!(keys.len(), m.len());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(vec![i as u8], m.get(k).unwrap().v, "{:?}", k);
+        }
+        assert!(m.get(b"abcdefgx").is_none());
+        assert!(m.get(&[0; 8]).is_none());
+        assert_eq!(b"".to_vec(), m.first().unwrap().key);
+    }
+
+    #[test]
+    fn test_ceil_floor() {
+        let mut m = SortedMap::new(1 << 16);
+        assert!(m.ceil(b"a").is_none());
+        assert!(m.floor(b"a").is_none());
+        for k in ["k10", "k20", "k30"] {
+            m.insert(k.as_bytes(), k.as_bytes()).unwrap();
+        }
+        // a replaced value doesn't count twice
+        m.insert(b"k20", b"new").unwrap();
+        assert_eq!(3, m.len());
+        assert_eq!(b"new".to_vec(), m.get(b"k20").unwrap().v);
+        assert!(m.get(b"k25").is_none());
+
+        let key = |e: Option<Entry>| e.map(|e| e.key);
+        // at keys
+        assert_eq!(Some(b"k20".to_vec()), key(m.ceil(b"k20")));
+        assert_eq!(Some(b"k20".to_vec()), key(m.floor(b"k20")));
+        // between keys
+        assert_eq!(Some(b"k20".to_vec()), key(m.ceil(b"k15")));
+        assert_eq!(Some(b"k10".to_vec()), key(m.floor(b"k15")));
+        // beyond the ends
+        assert_eq!(Some(b"k10".to_vec()), key(m.ceil(b"k")));
+        assert_eq!(None, key(m.floor(b"k")));
+        assert_eq!(None, key(m.ceil(b"k4")));
+        assert_eq!(Some(b"k30".to_vec()), key(m.floor(b"k4")));
+        // keys of 8 bytes and more are plain keys too
+        m.insert(b"k30-long-key", b"x").unwrap();
+        assert_eq!(Some(b"k30-long-key".to_vec()), key(m.ceil(b"k30-")));
+        assert_eq!(Some(b"k30".to_vec()), key(m.floor(b"k30-")));
+    }
+}
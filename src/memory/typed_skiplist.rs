@@ -0,0 +1,88 @@
+use crate::error::StepDbError;
+use crate::memory::entry::{new_entry, Value};
+use crate::memory::keys::{key_with_ts, parse_key};
+use crate::memory::skiplist::{new_versioned_skip_list, SkipList};
+use std::marker::PhantomData;
+
+// TypedSkipList is a SkipList keyed by K instead of bytes, the keys are kept in the arena
+// as the bytes of K.as_ref(). K is rebuilt from those bytes with TryFrom, which String
+// implements for Vec<u8> where From is not.
+//
+// Like SortedMap, keys are stored with a fixed timestamp, so the order is the byte order
+// of the keys, whatever their length.
+pub struct TypedSkipList<K> {
+    l: SkipList,
+    _pd: PhantomData<K>,
+}
+
+impl<K> TypedSkipList<K>
+where
+    K: AsRef<[u8]> + TryFrom<Vec<u8>>,
+{
+    pub fn new(area_size: u32) -> TypedSkipList<K> {
+        TypedSkipList {
+            l: new_versioned_skip_list(area_size),
+            _pd: PhantomData,
+        }
+    }
+
+    // add sets key to value, see SkipList::add for the errors.
+    pub fn add(&mut self, key: K, value: &[u8]) -> Result<(), StepDbError> {
+        self.l.add(new_entry(&stored_key(&key), value))
+    }
+
+    pub fn search(&self, key: &K) -> Value {
+        self.l.search(&stored_key(key))
+    }
+
+    // iter yields the keys in byte order along with their values.
+    pub fn iter(&self) -> impl Iterator<Item = (K, Value)> + '_ {
+        // The first item is the head.
+        self.l.iter().skip(1).map(|e| {
+            let key = K::try_from(parse_key(&e.key).to_vec())
+                .unwrap_or_else(|_| unreachable!("a key added as K converts back to K"));
+            (key, Value::from(&e))
+        })
+    }
+}
+
+fn stored_key<K: AsRef<[u8]>>(key: &K) -> Vec<u8> {
+    key_with_ts(key.as_ref(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::typed_skiplist::TypedSkipList;
+
+    #[test]
+    fn test_string_keys() {
+        let mut list = TypedSkipList::<String>::new(1 << 16);
+        let keys = ["b", "a", "ab", "B", "a long key", "a longer key", "é", "z"];
+        for k in keys {
+            list.add(k.to_string(), k.to_uppercase().as_bytes())
+                .unwrap();
+        }
+        assert_eq!(b"AB".to_vec(), list.search(&"ab".to_string()).v);
+        assert!(list.search(&"c".to_string()).v.is_empty());
+
+        let got: Vec<String> = list.iter().map(|(k, _)| k).collect();
+        let mut want: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        want.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(want, got);
+        for (k, v) in list.iter() {
+            assert_eq!(k.to_uppercase().into_bytes(), v.v);
+        }
+    }
+
+    #[test]
+    fn test_byte_keys() {
+        let mut list = TypedSkipList::<Vec<u8>>::new(1 << 16);
+        list.add(vec![2, 0], b"2").unwrap();
+        list.add(vec![1, 255], b"1").unwrap();
+        let got: Vec<_> = list.iter().map(|(k, v)| (k, v.v)).collect();
+        assert_eq!(
+            vec![(vec![1, 255], b"1".to_vec()), (vec![2, 0], b"2".to_vec())],
+            got
+        );
+    }
+}
@@ -1,17 +1,82 @@
 // CompareKeys checks the key without timestamp and checks the timestamp if keyNoTs
 // is same.
 // a<timestamp> would be sorted higher than aa<timestamp> if we use bytes.compare
-// All keys should have timestamp.
+// Keys should have timestamp, see memory::keys for the layout. A key too short to carry
+// a timestamp is a user key of its own, it sorts before the versions of the same user key,
+// so short and versioned keys can share a list in one consistent order.
 pub fn compare_keys(key1: &[u8], key2: &[u8]) -> i32 {
-    assert!(
-        key1.len() > 8 && key2.len() > 8,
-        "key1: {}, key2: {} < 8",
-        String::from_utf8_lossy(key1),
-        String::from_utf8_lossy(key2)
-    );
-    let cmp = key1[..key1.len() - 8].cmp(&key2[..key2.len() - 8]);
-    if !cmp.is_eq() {
-        return cmp as i32;
+    let (user1, ts1) = split_key(key1);
+    let (user2, ts2) = split_key(key2);
+    user1.cmp(user2).then(ts1.cmp(&ts2)) as i32
+}
+
+// split_key returns the user key and the timestamp suffix of key, None for a key too short
+// to carry one.
+fn split_key(key: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match key.len().checked_sub(8) {
+        Some(n) => (&key[..n], Some(&key[n..])),
+        None => (key, None),
+    }
+}
+
+// compare_keys_no_ts compares un-versioned keys byte by byte.
+pub fn compare_keys_no_ts(key1: &[u8], key2: &[u8]) -> i32 {
+    key1.cmp(key2) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::keys::key_with_ts;
+    use crate::memory::utils::{compare_keys, compare_keys_no_ts};
+
+    #[test]
+    fn test_compare_short_keys() {
+        assert_eq!(0, compare_keys(b"", b""));
+        assert_eq!(-1, compare_keys(b"", b"a"));
+        assert_eq!(-1, compare_keys(b"a", b"b"));
+        assert_eq!(1, compare_keys(b"b", b"a"));
+        assert_eq!(-1, compare_keys(b"a", b"aaaaaaaaaa"));
+        assert_eq!(-1, compare_keys_no_ts(b"a", b"aa"));
+        assert_eq!(-1, compare_keys(b"\0", b"\0\0"));
+    }
+
+    #[test]
+    fn test_compare_mixed_keys() {
+        // a short key sorts before every version of the same user key
+        assert_eq!(-1, compare_keys(b"a", &key_with_ts(b"a", u64::MAX)));
+        assert_eq!(-1, compare_keys(b"", &key_with_ts(b"", u64::MAX)));
+        assert_eq!(1, compare_keys(b"b", &key_with_ts(b"a", 1)));
+        // the order is transitive across short and versioned keys
+        let mut keys = vec![
+            b"".to_vec(),
+            b"a".to_vec(),
+            b"\0".to_vec(),
+            b"a\0b".to_vec(),
+            key_with_ts(b"", 1),
+            key_with_ts(b"\0", 1),
+            key_with_ts(b"a", 1),
+            key_with_ts(b"a", 2),
+            b"abcdefgh".to_vec(),
+            vec![0; 9],
+        ];
+        keys.sort_by(|a, b| compare_keys(a, b).cmp(&0));
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert!(compare_keys(a, b) <= 0, "{:?} {:?}", a, b);
+                assert!(compare_keys(b, a) >= 0, "{:?} {:?}", b, a);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_versioned_keys() {
+        let a1 = key_with_ts(b"a", 1);
+        let a2 = key_with_ts(b"a", 2);
+        let aa1 = key_with_ts(b"aa", 1);
+        assert_eq!(0, compare_keys(&a1, &a1));
+        assert_eq!(-1, compare_keys(&a2, &a1));
+        assert_eq!(1, compare_keys(&a1, &a2));
+        assert_eq!(-1, compare_keys(&a1, &aa1));
+        assert_eq!(-1, compare_keys(&a2, &aa1));
     }
-    key1[key1.len() - 8..].cmp(&key2[key2.len() - 8..]) as i32
 }